@@ -0,0 +1,32 @@
+use criterion::{BenchmarkId, black_box, Criterion, criterion_group, criterion_main};
+
+use telesteller::bench::Repository;
+
+/// builds filters like `building/3/floor/7/+/temperature`, `building/3/#` and `+/3/floor/7/room/2/humidity`.
+fn filters(size: usize) -> Vec<String> {
+    (0..size).map(|i| match i % 4 {
+        0 => format!("building/{}/floor/{}/room/{}/temperature", i % 17, i % 13, i),
+        1 => format!("building/{}/floor/{}/+/humidity", i % 17, i),
+        2 => format!("building/{}/floor/{}/#", i % 17, i),
+        _ => format!("+/{}/floor/{}/room/{}/light", i % 17, i % 13, i),
+    }).collect()
+}
+
+fn bench_matches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("PublisherRepository::matches");
+    for size in [100, 1_000, 10_000].iter() {
+        let filters = filters(*size);
+        for (name, mut repository) in [("dumb", Repository::dumb()), ("topic_tree", Repository::topic_tree())] {
+            filters.iter().for_each(|filter| repository.add(filter));
+
+            let topic = format!("building/{}/floor/{}/room/{}/humidity", 5, 1, 9);
+            group.bench_with_input(BenchmarkId::new(name, size), &topic, |b, topic| {
+                b.iter(|| repository.matches(black_box(topic)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_matches);
+criterion_main!(benches);
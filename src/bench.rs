@@ -0,0 +1,22 @@
+//! Thin wrappers exposing internal structures to benchmarks in `benches/`, which is only compiled
+//! with feature `bench`.
+
+use tokio::sync::broadcast;
+
+use crate::context::pub_sub::{DumbPublisherRepository, PublisherRepository, TopicTreePublisherRepository};
+
+pub struct Repository(Box<dyn PublisherRepository + Send + Sync>);
+
+impl Repository {
+    pub fn dumb() -> Repository { Repository(Box::new(DumbPublisherRepository::new())) }
+
+    pub fn topic_tree() -> Repository { Repository(Box::new(TopicTreePublisherRepository::new())) }
+
+    pub fn add(&mut self, filter: &str) {
+        let (tx, _) = broadcast::channel(1);
+        self.0.add(filter, tx);
+    }
+
+    /// returns the number of Topic Filters matching the Topic Name.
+    pub fn matches(&self, topic: &str) -> usize { self.0.matches(topic).len() }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_stream::StreamMap;
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+use crate::config::{BridgeConfig, BridgeTopic, Direction};
+use crate::context::{Stats, topic};
+use crate::handler::pub_sub::{expires_at, message_stream};
+use crate::message::{Qos, ReasonCode, request, response};
+use crate::message::client::{ClientCodec, Incoming, Outgoing};
+use crate::message::codec::{DecodeError, EncodeError};
+use crate::message::request::{Origin, PUBLISH};
+use crate::server::SyncWorkerManager;
+use crate::util::ext::BoolExt;
+use crate::util::Shutdown;
+
+/// seconds to wait for CONNACK once connected.
+const CONNECT_TIMEOUT: u64 = 10;
+/// the most Qos 1 messages exported but not acknowledged yet, beyond which exporting pauses.
+const MAX_INFLIGHT: usize = 1024;
+
+type Transport = Framed<TcpStream, ClientCodec>;
+
+/// keeps the bridge connected to the remote broker until the Server shuts down, reconnecting with
+/// a delay doubled on each failure. Messages published while disconnected are not forwarded, while
+/// Qos 1 ones not acknowledged yet are sent again once reconnected.
+pub(crate) async fn run(config: BridgeConfig, worker_manager: Arc<SyncWorkerManager>, stats: Arc<Stats>, mut shutdown: Shutdown) {
+    let min_delay = Duration::from_secs(config.reconnect_delay);
+    let max_delay = Duration::from_secs(config.max_reconnect_delay).max(min_delay);
+    let mut delay = min_delay;
+    let mut bridge = Bridge::new(&config, &worker_manager, &stats);
+    loop {
+        let connected = tokio::select! {
+            connected = connect(&config) => connected,
+            _ = shutdown.poll() => return,
+        };
+        match connected {
+            Ok(transport) => {
+                info!(addr = &config.addr[..], "bridge connected.");
+                delay = min_delay;
+                match bridge.forward(transport, &mut shutdown).await {
+                    Ok(()) => {
+                        info!("bridge disconnected as the server shuts down.");
+                        return;
+                    }
+                    Err(err) => warn!(err = %err, "bridge connection lost."),
+                }
+            }
+            Err(err) => warn!(addr = &config.addr[..], err = %err, "failed to connect the bridge."),
+        }
+
+        debug!(delay = ?delay, "bridge will reconnect after the delay.");
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.poll() => return,
+        }
+        delay = (delay * 2).min(max_delay);
+    }
+}
+
+async fn connect(config: &BridgeConfig) -> Result<Transport, Error> {
+    let mut transport = Framed::new(TcpStream::connect(&config.addr).await?, ClientCodec);
+    transport.send(Outgoing::CONNECT {
+        client_id: config.client_id.clone().unwrap_or_else(|| format!("telesteller-bridge-{}", config.name)),
+        keep_alive: config.keep_alive,
+        username: config.username.clone(),
+        password: config.password.clone(),
+    }).await?;
+
+    match tokio::time::timeout(Duration::from_secs(CONNECT_TIMEOUT), transport.next()).await {
+        Ok(Some(Ok(Incoming::CONNACK { reason_code: ReasonCode::SUCCESS, .. }))) => Ok(transport),
+        Ok(Some(Ok(Incoming::CONNACK { reason_code, .. }))) => Err(Error::Refused(reason_code.0)),
+        Ok(Some(Ok(incoming))) => Err(Error::Unexpected(incoming)),
+        Ok(Some(Err(err))) => Err(err.into()),
+        Ok(None) => Err(Error::Closed),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+fn max_qos(topic: &BridgeTopic) -> Qos {
+    if topic.qos == 0 { Qos::FireAndForget } else { Qos::AcknowledgedDeliver }
+}
+
+fn is_out(topic: &BridgeTopic) -> bool { topic.direction != Direction::In }
+
+fn is_in(topic: &BridgeTopic) -> bool { topic.direction != Direction::Out }
+
+fn local_filter(topic: &BridgeTopic) -> String { format!("{}{}", topic.local_prefix, topic.filter) }
+
+fn remote_filter(topic: &BridgeTopic) -> String { format!("{}{}", topic.remote_prefix, topic.filter) }
+
+/// The state of the bridge, which lasts across connections to the remote broker.
+///
+/// Loops are prevented by the origin of messages, namely a message imported by the bridge is not
+/// exported again, while a message exported is never sent back by the remote broker, as the bridge
+/// subscribes with No Local.
+struct Bridge<'a> {
+    config: &'a BridgeConfig,
+    worker_manager: &'a Arc<SyncWorkerManager>,
+    stats: &'a Arc<Stats>,
+    /// Qos 1 messages exported but not acknowledged yet, keyed by the Packet Identifier, with the
+    /// Topic Name of the remote broker.
+    inflight: BTreeMap<u16, (String, Arc<PUBLISH>)>,
+    next_id: u16,
+}
+
+impl<'a> Bridge<'a> {
+    fn new(config: &'a BridgeConfig, worker_manager: &'a Arc<SyncWorkerManager>, stats: &'a Arc<Stats>) -> Self {
+        Bridge {
+            config,
+            worker_manager,
+            stats,
+            inflight: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// forwards messages until the connection is lost, or returns Ok once the Server shuts down.
+    async fn forward(&mut self, mut transport: Transport, shutdown: &mut Shutdown) -> Result<(), Error> {
+        // a Topic Filter is subscribed once with the highest Qos of the topics sharing it.
+        let mut filters = BTreeMap::new();
+        for topic in self.config.topics.iter().filter(|topic| is_in(topic)) {
+            let granted = filters.entry(remote_filter(topic)).or_insert(Qos::FireAndForget);
+            *granted = max_qos(topic).max(*granted);
+        }
+        if !filters.is_empty() {
+            let id = self.next_id();
+            transport.send(Outgoing::SUBSCRIBE { id, filters: filters.into_iter().collect() }).await?;
+        }
+        // the remote broker might not have received them before the last connection is lost.
+        for (id, (remote_topic, message)) in self.inflight.iter() {
+            transport.send(Outgoing::PUBLISH(exported(remote_topic, message, Qos::AcknowledgedDeliver, Some(*id), true))).await?;
+        }
+
+        let mut exports = StreamMap::new();
+        {
+            let mut worker_manager = self.worker_manager.write().await;
+            for (i, topic) in self.config.topics.iter().enumerate().filter(|(_, topic)| is_out(topic)) {
+                let filter = local_filter(topic);
+                let subscriber = worker_manager.subscribe(&filter).await;
+                exports.insert(i, message_stream(subscriber, filter, self.stats.clone(), None));
+            }
+        }
+
+        let keep_alive = Duration::from_secs(u64::from(self.config.keep_alive.max(1)));
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + keep_alive, keep_alive);
+        let mut awaiting_pong = false;
+        loop {
+            tokio::select! {
+                Some((i, message)) = exports.next(), if self.inflight.len() < MAX_INFLIGHT =>
+                    self.export(i, message, &mut transport).await?,
+                incoming = transport.next() => match incoming {
+                    Some(Ok(incoming)) => {
+                        awaiting_pong = false;
+                        self.receive(incoming, &mut transport).await?;
+                    }
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Err(Error::Closed),
+                },
+                _ = ping.tick(), if self.config.keep_alive > 0 => {
+                    if awaiting_pong {
+                        return Err(Error::Timeout);
+                    }
+                    transport.send(Outgoing::PINGREQ).await?;
+                    awaiting_pong = true;
+                }
+                _ = shutdown.poll() => {
+                    let _ = transport.send(Outgoing::DISCONNECT).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn export(&mut self, i: usize, message: Arc<PUBLISH>, transport: &mut Transport) -> Result<(), Error> {
+        if matches!(&message.origin, Some(Origin::Bridge(name)) if *name == self.config.name) {
+            debug!(topic = &message.topic[..], "message imported by the bridge is not exported again.");
+            return Ok(());
+        }
+        if message.expired() {
+            return Ok(());
+        }
+
+        let topic = &self.config.topics[i];
+        // "a/#" also matches "a", which is out of the prefix "a/".
+        let remote_topic = match message.topic.strip_prefix(&topic.local_prefix[..]) {
+            Some(rest) => format!("{}{}", topic.remote_prefix, rest),
+            None => return Ok(()),
+        };
+
+        let qos = message.qos.min(max_qos(topic));
+        let id = (qos > Qos::FireAndForget).if_so_then(|| self.next_id());
+        debug!(topic = &message.topic[..], remote_topic = &remote_topic[..], "message exported by the bridge.");
+        let frame = exported(&remote_topic, &message, qos, id, false);
+        if let Some(id) = id {
+            self.inflight.insert(id, (remote_topic, message));
+        }
+        transport.send(Outgoing::PUBLISH(frame)).await?;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self, incoming: Incoming, transport: &mut Transport) -> Result<(), Error> {
+        match incoming {
+            Incoming::PUBLISH(message) => {
+                // Qos 2 is never granted, as the bridge subscribes with Qos 1 at most.
+                if let (Qos::AcknowledgedDeliver, Some(id)) = (message.qos, message.id) {
+                    transport.send(Outgoing::PUBACK(id)).await?;
+                }
+                self.import(message).await;
+            }
+            Incoming::PUBACK(id) => {
+                self.inflight.remove(&id);
+            }
+            Incoming::SUBACK { reason_codes, .. } if reason_codes.iter().any(|code| code.0 >= 0x80) =>
+                warn!("some Topic Filters are rejected by the remote broker, whose messages will not be imported."),
+            Incoming::DISCONNECT(reason_code) => return Err(Error::Disconnected(reason_code.0)),
+            Incoming::CONNACK { .. } => return Err(Error::Unexpected(incoming)),
+            Incoming::SUBACK { .. } | Incoming::PINGRESP => {}
+        }
+
+        Ok(())
+    }
+
+    /// dispatches the message to local subscribers like a PUBLISH of clients.
+    async fn import(&mut self, message: request::PUBLISH) {
+        let topic = self.config.topics.iter()
+            .filter(|topic| is_in(topic))
+            .find(|topic| topic::matches(&remote_filter(topic), &message.topic));
+        let local_topic = match topic.and_then(|topic| Some(format!("{}{}", topic.local_prefix, message.topic.strip_prefix(&topic.remote_prefix[..])?))) {
+            Some(local_topic) => local_topic,
+            None => {
+                debug!(remote_topic = &message.topic[..], "message not matching any topic of the bridge is dropped.");
+                return;
+            }
+        };
+
+        debug!(remote_topic = &message.topic[..], topic = &local_topic[..], "message imported by the bridge.");
+        let message = PUBLISH {
+            dup: false,
+            qos: message.qos,
+            retain: message.retain,
+            topic: local_topic.clone(),
+            id: None,
+            payload: message.payload,
+            expires_at: expires_at(&message.properties),
+            properties: message.properties,
+            origin: Some(Origin::Bridge(self.config.name.clone())),
+        };
+        if let Err(err) = self.worker_manager.read().await.dispatch(&local_topic, message).await {
+            debug!(send_error = ?err, topic = &local_topic[..], "failed to dispatch.");
+        }
+    }
+
+    /// allocates a Packet Identifier which is non-zero and not used by any in-flight message.
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if self.next_id != 0 && !self.inflight.contains_key(&self.next_id) {
+                return self.next_id;
+            }
+        }
+    }
+}
+
+fn exported(remote_topic: &str, message: &PUBLISH, qos: Qos, id: Option<u16>, dup: bool) -> response::PUBLISH {
+    response::PUBLISH {
+        dup,
+        qos,
+        retain: message.retain,
+        topic: remote_topic.to_owned(),
+        id,
+        payload: message.payload.clone(),
+        properties: message.forwarded_properties(),
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("IOError {0} occurred while connecting.")]
+    IO(#[from] std::io::Error),
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    #[error("{0}")]
+    EncodeError(#[from] EncodeError),
+    #[error("connection refused by the remote broker with reason code {0:#04x}.")]
+    Refused(u8),
+    #[error("disconnected by the remote broker with reason code {0:#04x}.")]
+    Disconnected(u8),
+    #[error("unexpected {0:?} received.")]
+    Unexpected(Incoming),
+    #[error("connection closed by the remote broker.")]
+    Closed,
+    #[error("the remote broker doesn't respond in time.")]
+    Timeout,
+}
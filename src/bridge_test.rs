@@ -0,0 +1,202 @@
+#![allow(non_snake_case)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::config::{BridgeConfig, BridgeTopic, Direction};
+use crate::listener::Listener;
+use crate::server::Error;
+use crate::server_test::{connect, packet, publish, publish_qos1, read, subscribe, text};
+use crate::{Opt, Server};
+
+/// starts a Server bridged to the remote brokers, which could be shut down by the returned sender.
+async fn start(listener: TcpListener, bridges: Vec<BridgeConfig>) -> (JoinHandle<Result<(), Error>>, broadcast::Sender<()>) {
+    let mut opt = Opt::from_iter(&["telesteller"]);
+    opt.bridges = bridges;
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener)], None, shutdown_tx.clone(), Arc::new(Semaphore::new(8))).unwrap();
+    (tokio::spawn(async move { server.serve().await }), shutdown_tx)
+}
+
+async fn serve(bridges: Vec<BridgeConfig>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    start(listener, bridges).await;
+    addr
+}
+
+fn bridge(addr: SocketAddr, topics: Vec<BridgeTopic>) -> BridgeConfig {
+    BridgeConfig {
+        name: "test".to_owned(),
+        addr: addr.to_string(),
+        client_id: None,
+        username: None,
+        password: None,
+        keep_alive: 60,
+        reconnect_delay: 1,
+        max_reconnect_delay: 1,
+        topics,
+    }
+}
+
+fn topic(filter: &str, direction: Direction, local_prefix: &str, remote_prefix: &str) -> BridgeTopic {
+    BridgeTopic {
+        filter: filter.to_owned(),
+        direction,
+        qos: 0,
+        local_prefix: local_prefix.to_owned(),
+        remote_prefix: remote_prefix.to_owned(),
+    }
+}
+
+async fn try_read(socket: &mut TcpStream, timeout: Duration) -> Option<Vec<u8>> {
+    tokio::time::timeout(timeout, read(socket)).await.ok().flatten()
+}
+
+/// publishes to the topic until the subscriber receives it, as the bridge connects in the
+/// background, then drains what the subscriber receives.
+async fn wait_bridged(publisher: &mut TcpStream, topic: &str, subscribers: &mut [&mut TcpStream]) {
+    loop {
+        publish(publisher, topic, "probe").await;
+        if try_read(subscribers[0], Duration::from_millis(200)).await.is_some() {
+            break;
+        }
+    }
+    for subscriber in subscribers.iter_mut() {
+        while try_read(subscriber, Duration::from_millis(300)).await.is_some() {}
+    }
+}
+
+/// asserts that the subscriber receives exactly the message.
+async fn assert_received(subscriber: &mut TcpStream, topic: &str, message: &str) {
+    assert_eq!(read(subscriber).await.unwrap(), packet(0x30, [text(topic), message.as_bytes().to_vec()].concat()));
+    assert_eq!(try_read(subscriber, Duration::from_millis(300)).await, None);
+}
+
+#[tokio::test]
+async fn test_bridge_prefix() {
+    let remote = serve(vec![]).await;
+    let local = serve(vec![bridge(remote, vec![
+        topic("a/#", Direction::Out, "", "edge/"),
+        topic("cmd/#", Direction::In, "remote/", "edge/"),
+    ])]).await;
+
+    let (mut remote_subscriber, _) = connect(remote, "rs", true, None).await;
+    subscribe(&mut remote_subscriber, "edge/a/#", 0).await;
+    let (mut local_publisher, _) = connect(local, "lp", true, None).await;
+    wait_bridged(&mut local_publisher, "a/probe", &mut [&mut remote_subscriber]).await;
+    publish(&mut local_publisher, "a/b", "out").await;
+    assert_received(&mut remote_subscriber, "edge/a/b", "out").await;
+
+    let (mut local_subscriber, _) = connect(local, "ls", true, None).await;
+    subscribe(&mut local_subscriber, "remote/#", 0).await;
+    let (mut remote_publisher, _) = connect(remote, "rp", true, None).await;
+    publish(&mut remote_publisher, "edge/cmd/x", "in").await;
+    assert_received(&mut local_subscriber, "remote/cmd/x", "in").await;
+
+    // topics out of the filters are not forwarded.
+    publish(&mut remote_publisher, "edge/other", "in").await;
+    publish(&mut local_publisher, "cmd/x", "out").await;
+    assert_eq!(try_read(&mut local_subscriber, Duration::from_millis(300)).await, None);
+    assert_eq!(try_read(&mut remote_subscriber, Duration::from_millis(300)).await, None);
+}
+
+#[tokio::test]
+async fn test_bridge_loop() {
+    let remote = serve(vec![]).await;
+    let local = serve(vec![bridge(remote, vec![topic("a/#", Direction::Both, "", "")])]).await;
+
+    let (mut remote_client, _) = connect(remote, "r", true, None).await;
+    subscribe(&mut remote_client, "a/#", 0).await;
+    let (mut local_client, _) = connect(local, "l", true, None).await;
+    subscribe(&mut local_client, "a/#", 0).await;
+    let (mut local_publisher, _) = connect(local, "lp", true, None).await;
+    wait_bridged(&mut local_publisher, "a/probe", &mut [&mut remote_client, &mut local_client]).await;
+
+    // the remote broker sends the message back to the bridge, which is not imported again.
+    publish(&mut local_client, "a/1", "local").await;
+    assert_received(&mut remote_client, "a/1", "local").await;
+    assert_received(&mut local_client, "a/1", "local").await;
+
+    // the message imported is not exported again.
+    publish(&mut remote_client, "a/2", "remote").await;
+    assert_received(&mut local_client, "a/2", "remote").await;
+    assert_received(&mut remote_client, "a/2", "remote").await;
+}
+
+#[tokio::test]
+async fn test_bridge_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    // the remote broker is not started until the bridge fails to connect.
+    drop(listener);
+    let local = serve(vec![bridge(remote, vec![topic("a/#", Direction::Out, "", "")])]).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let (handle, shutdown_tx) = start(TcpListener::bind(remote).await.unwrap(), vec![]).await;
+    let (mut remote_subscriber, _) = connect(remote, "rs", true, None).await;
+    subscribe(&mut remote_subscriber, "a/#", 0).await;
+    let (mut local_publisher, _) = connect(local, "lp", true, None).await;
+    wait_bridged(&mut local_publisher, "a/probe", &mut [&mut remote_subscriber]).await;
+
+    // the bridge connects again once the remote broker restarts.
+    shutdown_tx.send(()).unwrap();
+    handle.await.unwrap().unwrap();
+    start(TcpListener::bind(remote).await.unwrap(), vec![]).await;
+    let (mut remote_subscriber, _) = connect(remote, "rs", true, None).await;
+    subscribe(&mut remote_subscriber, "a/#", 0).await;
+    wait_bridged(&mut local_publisher, "a/probe", &mut [&mut remote_subscriber]).await;
+    publish(&mut local_publisher, "a/b", "message").await;
+    assert_received(&mut remote_subscriber, "a/b", "message").await;
+}
+
+/// accepts a connection of the bridge as the remote broker, which is accepted by CONNACK.
+async fn accept(listener: &TcpListener) -> TcpStream {
+    let (mut socket, _) = listener.accept().await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap()[0], 0x10);
+    socket.write_all(&[0x20, 0x03, 0x00, 0x00, 0x00]).await.unwrap();
+    socket
+}
+
+#[tokio::test]
+async fn test_bridge_inflight() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut qos1 = topic("a/#", Direction::Out, "", "");
+    qos1.qos = 1;
+    let local = serve(vec![bridge(listener.local_addr().unwrap(), vec![qos1])]).await;
+    let (mut local_publisher, _) = connect(local, "lp", true, None).await;
+
+    // Qos 1 messages are not acknowledged before the connection is lost.
+    let mut remote = accept(&listener).await;
+    let mut exported = Vec::new();
+    while exported.is_empty() {
+        publish_qos1(&mut local_publisher, "a/b", "m", 1).await;
+        while let Some(publish) = try_read(&mut remote, Duration::from_millis(200)).await {
+            assert_eq!(publish[0], 0x32);
+            exported.push(publish);
+        }
+    }
+    drop(remote);
+
+    // which are sent again with DUP once reconnected, until acknowledged.
+    let mut remote = accept(&listener).await;
+    for publish in exported.iter() {
+        let mut dup = publish.clone();
+        dup[0] |= 0x08;
+        assert_eq!(read(&mut remote).await.unwrap(), dup);
+        // the Packet Identifier follows the Topic Name "a/b".
+        remote.write_all(&packet(0x40, publish[7..9].to_vec())).await.unwrap();
+    }
+    assert_eq!(try_read(&mut remote, Duration::from_millis(300)).await, None);
+    drop(remote);
+
+    let mut remote = accept(&listener).await;
+    assert_eq!(try_read(&mut remote, Duration::from_millis(300)).await, None);
+}
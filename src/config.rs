@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::context::{self, AuthManager, topic};
+use crate::Opt;
+use crate::tls;
+
+/// the prefix of environment variables overriding options, e.g. `TELESTELLER_MAX_CONNECTION`.
+const ENV_PREFIX: &str = "TELESTELLER_";
+
+/// A listener declared in the configuration file, whose transport is chosen by `kind`:
+///
+/// ```toml
+/// [[listeners]]
+/// kind = "tls"
+/// addr = "0.0.0.0:8883"
+/// cert = "server.pem"
+/// key = "server.key"
+/// max_connections = 1024
+/// ```
+///
+/// connections of a listener are limited by `max_connections` besides `max_connection` of the
+/// whole Server.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ListenerConfig {
+    Tcp {
+        addr: String,
+        max_connections: Option<usize>,
+    },
+    Tls {
+        addr: String,
+        cert: PathBuf,
+        key: PathBuf,
+        ca: Option<PathBuf>,
+        max_connections: Option<usize>,
+    },
+    Ws {
+        addr: String,
+        #[serde(default = "default_ws_path")]
+        path: String,
+        max_connections: Option<usize>,
+    },
+}
+
+fn default_ws_path() -> String { "/mqtt".to_owned() }
+
+impl ListenerConfig {
+    pub fn addr(&self) -> &str {
+        match self {
+            ListenerConfig::Tcp { addr, .. } | ListenerConfig::Tls { addr, .. } | ListenerConfig::Ws { addr, .. } => addr,
+        }
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        match self {
+            ListenerConfig::Tcp { max_connections, .. }
+            | ListenerConfig::Tls { max_connections, .. }
+            | ListenerConfig::Ws { max_connections, .. } => *max_connections,
+        }
+    }
+
+    fn max_connections_mut(&mut self) -> &mut Option<usize> {
+        match self {
+            ListenerConfig::Tcp { max_connections, .. }
+            | ListenerConfig::Tls { max_connections, .. }
+            | ListenerConfig::Ws { max_connections, .. } => max_connections,
+        }
+    }
+}
+
+/// A bridge to another broker declared in the configuration file, which forwards messages of the
+/// topics in the given direction. The other broker should speak MQTT 5, so that messages forwarded
+/// out are not sent back.
+///
+/// ```toml
+/// [[bridges]]
+/// name = "cloud"
+/// addr = "broker.example.com:1883"
+///
+/// [[bridges.topics]]
+/// filter = "sensors/#"
+/// direction = "out"
+/// remote_prefix = "site1/"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    pub name: String,
+    pub addr: String,
+    /// `telesteller-bridge-{name}` if not given.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// seconds, 0 turns it off.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u16,
+    /// seconds to wait before the first reconnection, which doubles on each failure.
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay: u64,
+    #[serde(default = "default_max_reconnect_delay")]
+    pub max_reconnect_delay: u64,
+    pub topics: Vec<BridgeTopic>,
+}
+
+fn default_keep_alive() -> u16 { 60 }
+fn default_reconnect_delay() -> u64 { 1 }
+fn default_max_reconnect_delay() -> u64 { 60 }
+
+/// Messages matching `local_prefix` + `filter` are forwarded out with the prefix replaced by
+/// `remote_prefix`, and the other way round for messages forwarded in.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeTopic {
+    pub filter: String,
+    #[serde(default)]
+    pub direction: Direction,
+    /// the maximum Qos of messages forwarded, which is either 0 or 1.
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub local_prefix: String,
+    #[serde(default)]
+    pub remote_prefix: String,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Out,
+    In,
+    Both,
+}
+
+impl BridgeConfig {
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |field: &str| Err(Error::InvalidValue(format!("{} of bridge `{}`", field, self.name)));
+        if self.topics.is_empty() {
+            return invalid("topics");
+        }
+        for topic in self.topics.iter() {
+            let filters = [format!("{}{}", topic.local_prefix, topic.filter), format!("{}{}", topic.remote_prefix, topic.filter)];
+            // prefixes are prepended to Topic Names, so they can't contain wildcards.
+            if filters.iter().any(|filter| !topic::is_valid_filter(filter) || topic::parse_shared(filter).is_some())
+                || topic.local_prefix.contains(['+', '#']) || topic.remote_prefix.contains(['+', '#']) {
+                return invalid(&format!("topic `{}`", topic.filter));
+            }
+            if topic.qos > 1 {
+                return invalid(&format!("qos of topic `{}`", topic.filter));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The configuration file in TOML, whose top-level keys are the long names of command line
+/// options, and listeners are declared in `[[listeners]]` sections:
+///
+/// ```toml
+/// log_filter = "warn"
+/// max_connection = 10000
+/// password_file = "passwords"
+///
+/// [[listeners]]
+/// kind = "tcp"
+/// addr = "0.0.0.0:1883"
+///
+/// [[listeners]]
+/// kind = "ws"
+/// addr = "0.0.0.0:8080"
+/// path = "/mqtt"
+/// ```
+///
+/// and so are bridges in `[[bridges]]` sections, see [`BridgeConfig`].
+#[derive(Debug, Default)]
+pub(crate) struct ConfigFile {
+    pub(crate) listeners: Vec<ListenerConfig>,
+    pub(crate) bridges: Vec<BridgeConfig>,
+    /// keyed by the name of the option, in which `_` is replaced by `-`.
+    pub(crate) options: BTreeMap<String, toml::Value>,
+}
+
+impl ConfigFile {
+    pub(crate) fn load(path: &Path) -> Result<ConfigFile, Error> {
+        ConfigFile::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<ConfigFile, Error> {
+        let mut table = toml::from_str::<toml::value::Table>(content)?;
+        let listeners = match table.remove("listeners") {
+            Some(listeners) => listeners.try_into()?,
+            None => Vec::new(),
+        };
+        let bridges: Vec<BridgeConfig> = match table.remove("bridges") {
+            Some(bridges) => bridges.try_into()?,
+            None => Vec::new(),
+        };
+        for bridge in bridges.iter() {
+            bridge.validate()?;
+        }
+        let options = table.into_iter()
+            .map(|(key, value)| (key.replace('_', "-"), value))
+            .collect();
+
+        Ok(ConfigFile { listeners, bridges, options })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum OptionKind {
+    /// `--deny-anonymous`, which takes no value.
+    Flag,
+    /// `--addr <addr>`.
+    Value,
+}
+
+/// every option of [`Opt`] which could be given by the configuration file or the environment,
+/// which should be kept in line with [`Opt`].
+pub(crate) const OPTIONS: &[(&str, OptionKind)] = &[
+    ("config", OptionKind::Value),
+    ("check-config", OptionKind::Flag),
+    ("addr", OptionKind::Value),
+    ("max-connection", OptionKind::Value),
+    ("max-session", OptionKind::Value),
+    ("log-filter", OptionKind::Value),
+    ("tls-addr", OptionKind::Value),
+    ("tls-cert", OptionKind::Value),
+    ("tls-key", OptionKind::Value),
+    ("tls-ca", OptionKind::Value),
+    ("ws-addr", OptionKind::Value),
+    ("ws-path", OptionKind::Value),
+    ("password-file", OptionKind::Value),
+    ("deny-anonymous", OptionKind::Flag),
+    ("acl-file", OptionKind::Value),
+    ("session-file", OptionKind::Value),
+    ("max-queued-messages", OptionKind::Value),
+    ("sys-interval", OptionKind::Value),
+    ("metrics-addr", OptionKind::Value),
+    ("connect-timeout", OptionKind::Value),
+    ("drain-timeout", OptionKind::Value),
+    ("share-strategy", OptionKind::Value),
+];
+
+/// whether the option takes a value, or None if there's no such option.
+fn kind_of(name: &str) -> Option<OptionKind> {
+    OPTIONS.iter().find(|(option, _)| *option == name).map(|(_, kind)| *kind)
+}
+
+/// loads the options from command line arguments, environment variables and the configuration
+/// file given by `--config`, where the former override the latter. Options from the environment
+/// are named by [`ENV_PREFIX`] followed by the name in upper case, e.g. `TELESTELLER_LOG_FILTER`,
+/// and flags are turned on by `true`.
+pub(crate) fn load_from<I>(args: I, env: impl IntoIterator<Item=(String, String)>) -> Result<Opt, Error>
+    where I: IntoIterator, I::Item: Into<OsString> {
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    let matches = Opt::clap().get_matches_from_safe(&args)?;
+    let is_given = |name: &str| matches.occurrences_of(name) > 0;
+
+    let mut env_options = HashMap::new();
+    for (key, value) in env {
+        let name = match key.strip_prefix(ENV_PREFIX) {
+            Some(name) => name.to_lowercase().replace('_', "-"),
+            None => continue,
+        };
+        // environment variables of other purposes may share the prefix.
+        if let Some(kind) = kind_of(&name) {
+            env_options.insert(name, (kind, value));
+        }
+    }
+
+    let config = match (Opt::from_clap(&matches).config, env_options.get("config")) {
+        (Some(path), _) => Some(ConfigFile::load(&path)?),
+        (None, Some((_, path))) => Some(ConfigFile::load(Path::new(path))?),
+        (None, None) => None,
+    }.unwrap_or_default();
+
+    let mut merged = vec![args.first().cloned().unwrap_or_else(|| "telesteller".into())];
+    for (name, value) in config.options.iter() {
+        let kind = match kind_of(name) {
+            Some(_) if name == "config" || name == "check-config" => return Err(Error::UnknownOption(name.clone())),
+            Some(kind) => kind,
+            None => return Err(Error::UnknownOption(name.clone())),
+        };
+        if is_given(name) || env_options.contains_key(name) {
+            continue;
+        }
+
+        match (kind, value) {
+            (OptionKind::Flag, toml::Value::Boolean(true)) => merged.push(format!("--{}", name).into()),
+            (OptionKind::Flag, toml::Value::Boolean(false)) => {}
+            (OptionKind::Value, toml::Value::String(value)) => merged.extend([format!("--{}", name).into(), value.into()]),
+            (OptionKind::Value, toml::Value::Integer(value)) => merged.extend([format!("--{}", name).into(), value.to_string().into()]),
+            _ => return Err(Error::InvalidValue(name.clone())),
+        }
+    }
+    for (name, (kind, value)) in env_options.iter() {
+        if is_given(name) {
+            continue;
+        }
+
+        match (kind, &value[..]) {
+            (OptionKind::Flag, "true") => merged.push(format!("--{}", name).into()),
+            (OptionKind::Flag, "false") => {}
+            (OptionKind::Flag, _) => return Err(Error::InvalidValue(format!("{}{}", ENV_PREFIX, name.to_uppercase().replace('-', "_")))),
+            (OptionKind::Value, _) => merged.extend([format!("--{}", name).into(), value.into()]),
+        }
+    }
+    merged.extend(args.into_iter().skip(1));
+
+    let matches = Opt::clap().get_matches_from_safe(&merged)?;
+    let mut opt = Opt::from_clap(&matches);
+    opt.listeners = config.listeners;
+    opt.bridges = config.bridges;
+    if !opt.listeners.is_empty() {
+        override_listeners(&mut opt, |name| matches.occurrences_of(name) > 0);
+    }
+
+    Ok(opt)
+}
+
+/// replaces the first listener of the same kind in the configuration file by the one given by
+/// `--addr`, `--tls-*` or `--ws-*`, or adds it if there's none.
+fn override_listeners(opt: &mut Opt, is_given: impl Fn(&str) -> bool) {
+    let mut overrides = Vec::new();
+    if is_given("addr") {
+        overrides.push(ListenerConfig::Tcp { addr: opt.addr.clone(), max_connections: None });
+    }
+    if ["tls-addr", "tls-cert", "tls-key", "tls-ca"].iter().any(|name| is_given(name)) {
+        if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+            overrides.push(ListenerConfig::Tls {
+                addr: opt.tls_addr.clone(),
+                cert: cert.clone(),
+                key: key.clone(),
+                ca: opt.tls_ca.clone(),
+                max_connections: None,
+            });
+        }
+    }
+    if is_given("ws-addr") || is_given("ws-path") {
+        if let Some(addr) = &opt.ws_addr {
+            overrides.push(ListenerConfig::Ws { addr: addr.clone(), path: opt.ws_path.clone(), max_connections: None });
+        }
+    }
+
+    for mut config in overrides {
+        let same_kind = opt.listeners.iter_mut()
+            .find(|listener| std::mem::discriminant(*listener) == std::mem::discriminant(&config));
+        match same_kind {
+            // the limit can't be given by command line arguments, which is kept.
+            Some(listener) => {
+                *config.max_connections_mut() = listener.max_connections();
+                *listener = config;
+            }
+            None => opt.listeners.push(config),
+        }
+    }
+}
+
+/// validates what the Server is about to load, without binding any listener.
+pub fn check(opt: &Opt) -> Result<(), Error> {
+    tracing_subscriber::EnvFilter::try_new(&opt.log_filter).map_err(|err| Error::InvalidValue(format!("log-filter: {}", err)))?;
+    for listener in opt.listeners() {
+        if let ListenerConfig::Tls { cert, key, ca, .. } = listener {
+            tls::acceptor(&cert, &key, ca.as_deref())?;
+        }
+    }
+    AuthManager::new(opt.password_file.as_deref(), !opt.deny_anonymous, opt.acl_file.as_deref())?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot read the configuration file: {0:?}")]
+    ReadError(#[from] std::io::Error),
+    #[error("invalid configuration file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("{0}")]
+    ArgumentError(#[from] structopt::clap::Error),
+    #[error("unknown option `{0}` in the configuration file")]
+    UnknownOption(String),
+    #[error("invalid value of `{0}`")]
+    InvalidValue(String),
+    #[error("{0}")]
+    TlsError(#[from] tls::Error),
+    #[error("{0}")]
+    AuthError(#[from] context::auth::Error),
+}
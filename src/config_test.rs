@@ -0,0 +1,180 @@
+#![allow(non_snake_case)]
+
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::config::{BridgeConfig, BridgeTopic, ConfigFile, Direction, Error, ListenerConfig, load_from, OptionKind, OPTIONS};
+use crate::Opt;
+
+const CONFIG: &str = r#"
+log_filter = "warn"
+max_connection = 100
+deny_anonymous = true
+
+[[listeners]]
+kind = "tcp"
+addr = "0.0.0.0:1883"
+max_connections = 10
+
+[[listeners]]
+kind = "tls"
+addr = "0.0.0.0:8883"
+cert = "server.pem"
+key = "server.key"
+
+[[listeners]]
+kind = "ws"
+addr = "0.0.0.0:8080"
+"#;
+
+/// writes the configuration to a file named after the test.
+fn write(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("telesteller-config-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Opt, Error> {
+    load_from(["telesteller"].iter().chain(args), env.iter().map(|(k, v)| (k.to_string(), v.to_string())))
+}
+
+#[test]
+fn test_parse() {
+    let config = ConfigFile::parse(CONFIG).unwrap();
+    assert_eq!(config.listeners, vec![
+        ListenerConfig::Tcp { addr: "0.0.0.0:1883".to_owned(), max_connections: Some(10) },
+        ListenerConfig::Tls {
+            addr: "0.0.0.0:8883".to_owned(),
+            cert: PathBuf::from("server.pem"),
+            key: PathBuf::from("server.key"),
+            ca: None,
+            max_connections: None,
+        },
+        ListenerConfig::Ws { addr: "0.0.0.0:8080".to_owned(), path: "/mqtt".to_owned(), max_connections: None },
+    ]);
+    assert_eq!(config.options.keys().collect::<Vec<_>>(), ["deny-anonymous", "log-filter", "max-connection"]);
+
+    assert!(matches!(ConfigFile::parse("[[listeners]]\nkind = \"udp\"\naddr = \"\""), Err(Error::ParseError(_))));
+    assert!(matches!(ConfigFile::parse("[[listeners]]\nkind = \"tcp\"\naddr = \"\"\npath = \"/\""), Err(Error::ParseError(_))));
+    assert!(matches!(ConfigFile::parse("max_connection ="), Err(Error::ParseError(_))));
+}
+
+#[test]
+fn test_load() {
+    let path = write("load", CONFIG);
+    let opt = load(&["--config", path.to_str().unwrap()], &[]).unwrap();
+    assert_eq!(opt.log_filter, "warn");
+    assert_eq!(opt.max_connection, 100);
+    assert!(opt.deny_anonymous);
+    assert_eq!(opt.listeners().len(), 3);
+
+    // the configuration file could be given by the environment as well.
+    let opt = load(&[], &[("TELESTELLER_CONFIG", path.to_str().unwrap())]).unwrap();
+    assert_eq!(opt.max_connection, 100);
+}
+
+#[test]
+fn test_load_override() {
+    let path = write("override", CONFIG);
+    let config = path.to_str().unwrap();
+
+    let opt = load(&["--config", config, "--max-connection", "5"],
+                   &[("TELESTELLER_MAX_CONNECTION", "7"), ("TELESTELLER_LOG_FILTER", "debug"), ("TELESTELLER_UNKNOWN", "1")]).unwrap();
+    assert_eq!(opt.max_connection, 5);
+    assert_eq!(opt.log_filter, "debug");
+    assert!(opt.deny_anonymous);
+
+    let opt = load(&["--config", config], &[("TELESTELLER_MAX_CONNECTION", "7"), ("TELESTELLER_DENY_ANONYMOUS", "false")]).unwrap();
+    assert_eq!(opt.max_connection, 7);
+    assert!(!opt.deny_anonymous);
+
+    // the first listener of the same kind is replaced, whose limit is kept.
+    let opt = load(&["--config", config, "--addr", "127.0.0.1:1884"], &[("TELESTELLER_WS_PATH", "/ws")]).unwrap();
+    assert_eq!(opt.listeners()[0], ListenerConfig::Tcp { addr: "127.0.0.1:1884".to_owned(), max_connections: Some(10) });
+    assert_eq!(opt.listeners()[2], ListenerConfig::Ws { addr: "0.0.0.0:8080".to_owned(), path: "/mqtt".to_owned(), max_connections: None });
+}
+
+#[test]
+fn test_load_default() {
+    let opt = load(&["--addr", "127.0.0.1:1884", "--ws-addr", "127.0.0.1:8080"], &[]).unwrap();
+    assert_eq!(opt.listeners(), vec![
+        ListenerConfig::Tcp { addr: "127.0.0.1:1884".to_owned(), max_connections: None },
+        ListenerConfig::Ws { addr: "127.0.0.1:8080".to_owned(), path: "/mqtt".to_owned(), max_connections: None },
+    ]);
+}
+
+#[test]
+fn test_load_invalid() {
+    let path = write("unknown", "max_connections = 1");
+    assert!(matches!(load(&["--config", path.to_str().unwrap()], &[]), Err(Error::UnknownOption(name)) if name == "max-connections"));
+
+    let path = write("nested", "config = \"other.toml\"");
+    assert!(matches!(load(&["--config", path.to_str().unwrap()], &[]), Err(Error::UnknownOption(_))));
+
+    let path = write("type", "deny_anonymous = \"yes\"");
+    assert!(matches!(load(&["--config", path.to_str().unwrap()], &[]), Err(Error::InvalidValue(_))));
+
+    let path = write("value", "max_connection = \"many\"");
+    assert!(matches!(load(&["--config", path.to_str().unwrap()], &[]), Err(Error::ArgumentError(_))));
+
+    assert!(matches!(load(&[], &[("TELESTELLER_DENY_ANONYMOUS", "yes")]), Err(Error::InvalidValue(_))));
+    assert!(matches!(load(&["--config", "/nonexistent/telesteller.toml"], &[]), Err(Error::ReadError(_))));
+}
+
+#[test]
+fn test_parse_bridges() {
+    let config = ConfigFile::parse(r#"
+[[bridges]]
+name = "cloud"
+addr = "127.0.0.1:1883"
+
+[[bridges.topics]]
+filter = "a/#"
+remote_prefix = "edge/"
+
+[[bridges.topics]]
+filter = "cmd/+"
+direction = "in"
+qos = 1
+"#).unwrap();
+    assert_eq!(config.bridges, vec![BridgeConfig {
+        name: "cloud".to_owned(),
+        addr: "127.0.0.1:1883".to_owned(),
+        client_id: None,
+        username: None,
+        password: None,
+        keep_alive: 60,
+        reconnect_delay: 1,
+        max_reconnect_delay: 60,
+        topics: vec![
+            BridgeTopic { filter: "a/#".to_owned(), direction: Direction::Out, qos: 0, local_prefix: "".to_owned(), remote_prefix: "edge/".to_owned() },
+            BridgeTopic { filter: "cmd/+".to_owned(), direction: Direction::In, qos: 1, local_prefix: "".to_owned(), remote_prefix: "".to_owned() },
+        ],
+    }]);
+
+    let bridge = |topic: &str| format!("[[bridges]]\nname = \"b\"\naddr = \"\"\n[[bridges.topics]]\n{}", topic);
+    for topic in ["filter = \"a/#/b\"", "filter = \"a\"\nqos = 2", "filter = \"a\"\nlocal_prefix = \"+/\"", "filter = \"$share/g/a\""] {
+        assert!(matches!(ConfigFile::parse(&bridge(topic)), Err(Error::InvalidValue(_))), "{} should be invalid", topic);
+    }
+    assert!(matches!(ConfigFile::parse(&bridge("filter = \"a\"\ndirection = \"up\"")), Err(Error::ParseError(_))));
+}
+
+/// the options known by the configuration file are the ones of `Opt`.
+#[test]
+fn test_options() {
+    let app = Opt::clap();
+    let flags = app.p.flags.iter()
+        .filter_map(|flag| flag.s.long)
+        .filter(|name| *name != "help" && *name != "version")
+        .map(|name| (name, OptionKind::Flag));
+    let values = app.p.opts.iter()
+        .filter_map(|opt| opt.s.long)
+        .map(|name| (name, OptionKind::Value));
+    let mut expected = flags.chain(values).collect::<Vec<_>>();
+    expected.sort_by_key(|(name, _)| *name);
+
+    let mut options = OPTIONS.to_vec();
+    options.sort_by_key(|(name, _)| *name);
+    assert_eq!(options, expected);
+}
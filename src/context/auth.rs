@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+use crate::context::topic;
+
+/// Decides whether a client is allowed to connect by its CONNECT, and which topics it can access
+/// afterwards. Clients without a username are anonymous, which are accepted or rejected by
+/// `allow_anonymous` before any Authenticator.
+pub(crate) struct AuthManager {
+    authenticator: Option<Arc<dyn Authenticator + Sync + Send>>,
+    allow_anonymous: bool,
+    /// every topic is accessible to everyone if there's no ACL.
+    acl: Option<Acl>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Rejection {
+    BadUsernameOrPassword,
+    NotAuthorized,
+}
+
+impl AuthManager {
+    /// authenticates the client by its CONNECT. The password is verified on the blocking threads,
+    /// as hashing takes long on purpose, without holding the AuthManager meanwhile.
+    pub(crate) async fn authenticate(auth_manager: &RwLock<AuthManager>, client_id: &str, username: Option<&str>, password: Option<&[u8]>) -> Result<(), Rejection> {
+        let authenticator = {
+            let auth_manager = auth_manager.read().await;
+            match (username, &auth_manager.authenticator) {
+                (None, _) if auth_manager.allow_anonymous => return Ok(()),
+                (None, _) => return Err(Rejection::NotAuthorized),
+                (Some(_), Some(authenticator)) => authenticator.clone(),
+                // usernames are not verified at all if there's no Authenticator.
+                (Some(_), None) => return Ok(()),
+            }
+        };
+
+        let (client_id, username, password) = (client_id.to_owned(), username.unwrap_or_default().to_owned(), password.map(<[u8]>::to_vec));
+        tokio::task::spawn_blocking(move || authenticator.authenticate(&client_id, &username, password.as_deref())).await
+            .unwrap_or(false)
+            .then_some(())
+            .ok_or(Rejection::BadUsernameOrPassword)
+    }
+
+    /// checks whether the client can publish to the Topic Name, or subscribe to the Topic Filter.
+    pub(crate) fn authorize(&self, client_id: &str, username: Option<&str>, topic: &str, access: Access) -> bool {
+        match &self.acl {
+            Some(acl) => acl.authorize(client_id, username, topic, access),
+            None => true,
+        }
+    }
+
+    pub(crate) fn new(password_file: Option<&Path>, allow_anonymous: bool, acl_file: Option<&Path>) -> Result<AuthManager, Error> {
+        let authenticator = match password_file {
+            Some(path) => Some(Arc::new(PasswordFileAuthenticator::load(path)?) as Arc<dyn Authenticator + Sync + Send>),
+            None => None,
+        };
+
+        Ok(AuthManager {
+            authenticator,
+            allow_anonymous,
+            acl: acl_file.map(Acl::load).transpose()?,
+        })
+    }
+}
+
+pub(crate) trait Authenticator {
+    /// checks the username and password of a client, returning whether it is accepted.
+    fn authenticate(&self, client_id: &str, username: &str, password: Option<&[u8]>) -> bool;
+}
+
+/// Authenticates clients by a file of `username:hash` lines, where the hash is salted and in the
+/// format of mosquitto_passwd, so existing password files can be reused:
+///
+/// - `$6$<salt>$<hash>`: SHA-512 of the password followed by the salt.
+/// - `$7$<iterations>$<salt>$<hash>`: PBKDF2 with HMAC-SHA-512.
+///
+/// salts and hashes are encoded in base64.
+pub(crate) struct PasswordFileAuthenticator {
+    passwords: HashMap<String, PasswordHash>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum PasswordHash {
+    Sha512 { salt: Vec<u8>, hash: Vec<u8> },
+    Pbkdf2Sha512 { iterations: u32, salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl PasswordHash {
+    pub(crate) fn parse(s: &str) -> Option<PasswordHash> {
+        let parts = s.split('$').collect::<Vec<_>>();
+        match parts[..] {
+            ["", "6", salt, hash] => Some(PasswordHash::Sha512 {
+                salt: BASE64.decode(salt).ok()?,
+                hash: BASE64.decode(hash).ok()?,
+            }),
+            ["", "7", iterations, salt, hash] => Some(PasswordHash::Pbkdf2Sha512 {
+                iterations: iterations.parse().ok()?,
+                salt: BASE64.decode(salt).ok()?,
+                hash: BASE64.decode(hash).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// compares the hashes in constant time, which tells nothing of how much they differ.
+    pub(crate) fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            PasswordHash::Sha512 { salt, hash } => {
+                let mut hasher = Sha512::new();
+                hasher.update(password);
+                hasher.update(salt);
+                hasher.finalize().as_slice().ct_eq(hash.as_slice()).into()
+            }
+            PasswordHash::Pbkdf2Sha512 { iterations, salt, hash } => {
+                let mut out = vec![0; hash.len()];
+                pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, *iterations, &mut out);
+                out.ct_eq(hash).into()
+            }
+        }
+    }
+}
+
+impl PasswordFileAuthenticator {
+    pub(crate) fn load(path: &Path) -> Result<PasswordFileAuthenticator, Error> {
+        PasswordFileAuthenticator::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<PasswordFileAuthenticator, Error> {
+        let mut passwords = HashMap::new();
+        for (no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let hash = line.split_once(':')
+                .and_then(|(username, hash)| Some((username, PasswordHash::parse(hash)?)));
+            match hash {
+                Some((username, hash)) => passwords.insert(username.to_owned(), hash),
+                None => return Err(Error::InvalidLine(no + 1)),
+            };
+        }
+
+        Ok(PasswordFileAuthenticator { passwords })
+    }
+}
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate(&self, _client_id: &str, username: &str, password: Option<&[u8]>) -> bool {
+        match (self.passwords.get(username), password) {
+            (Some(hash), Some(password)) => hash.verify(password),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Access {
+    /// subscribing to a Topic Filter.
+    Read,
+    /// publishing to a Topic Name.
+    Write,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Permission {
+    Read,
+    Write,
+    ReadWrite,
+    /// denies both, which overrides any other rule.
+    Deny,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Owner {
+    /// `topic` rules before any `user` line, which apply to anonymous clients.
+    Anonymous,
+    User(String),
+    /// `pattern` rules, which apply to every client.
+    Everyone,
+}
+
+#[derive(Debug)]
+struct Rule {
+    owner: Owner,
+    permission: Permission,
+    /// a Topic Filter, in which `%u` and `%c` of `pattern` rules are replaced by the username and
+    /// the Client Identifier.
+    topic: String,
+}
+
+/// Access Control List loaded from a file in the format of mosquitto's acl_file, for example:
+///
+/// ```text
+/// # anonymous clients can only read
+/// topic read public/#
+///
+/// user alice
+/// topic readwrite alice/#
+/// topic deny alice/secret
+///
+/// # every client can write to its own topic
+/// pattern write clients/%c/#
+/// ```
+///
+/// a topic is accessible only if some rule grants the access and no `deny` rule matches it, so
+/// alice can't subscribe to `alice/#` either, which would receive messages of `alice/secret`.
+pub(crate) struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub(crate) fn load(path: &Path) -> Result<Acl, Error> {
+        Acl::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Acl, Error> {
+        let mut rules = Vec::new();
+        let mut owner = Owner::Anonymous;
+        for (no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || Error::InvalidAclLine(no + 1);
+            let (keyword, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let rest = rest.trim_start();
+            match keyword {
+                "user" => owner = Owner::User(rest.to_owned()),
+                "topic" | "pattern" => {
+                    // the permission can be omitted, which means readwrite.
+                    let (permission, topic) = match rest.split_once(char::is_whitespace) {
+                        Some(("read", topic)) => (Permission::Read, topic),
+                        Some(("write", topic)) => (Permission::Write, topic),
+                        Some(("readwrite", topic)) => (Permission::ReadWrite, topic),
+                        Some(("deny", topic)) => (Permission::Deny, topic),
+                        _ => (Permission::ReadWrite, rest),
+                    };
+                    let topic = topic.trim_start();
+                    if !topic::is_valid_filter(topic) {
+                        return Err(invalid());
+                    }
+
+                    rules.push(Rule {
+                        owner: match keyword {
+                            "pattern" => Owner::Everyone,
+                            _ => match &owner {
+                                Owner::User(username) => Owner::User(username.clone()),
+                                _ => Owner::Anonymous,
+                            },
+                        },
+                        permission,
+                        topic: topic.to_owned(),
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Acl { rules })
+    }
+
+    pub(crate) fn authorize(&self, client_id: &str, username: Option<&str>, topic: &str, access: Access) -> bool {
+        let mut granted = false;
+        for rule in self.rules.iter() {
+            let filter = match (&rule.owner, username) {
+                (Owner::Anonymous, None) => rule.topic.clone(),
+                (Owner::User(owner), Some(username)) if owner == username => rule.topic.clone(),
+                (Owner::Everyone, _) => match substitute(&rule.topic, client_id, username) {
+                    Some(filter) => filter,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            // a subscription is denied if it could receive any message of a denied topic, while
+            // it is granted only if all the messages it could receive are granted.
+            let matched = match (access, rule.permission) {
+                (Access::Read, Permission::Deny) => topic::overlaps(&filter, topic),
+                (Access::Read, _) => topic::covers(&filter, topic),
+                (Access::Write, _) => topic::matches(&filter, topic),
+            };
+            match (matched, rule.permission, access) {
+                (false, ..) => {}
+                (true, Permission::Deny, _) => return false,
+                (true, Permission::ReadWrite, _)
+                | (true, Permission::Read, Access::Read)
+                | (true, Permission::Write, Access::Write) => granted = true,
+                _ => {}
+            }
+        }
+
+        granted
+    }
+}
+
+/// replaces `%u` and `%c` in the Topic Filter. None is returned if the rule can't apply, which is
+/// when `%u` is used by an anonymous client, or the replacement could change the filter structure.
+fn substitute(filter: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let is_plain = |s: &str| !s.contains(['+', '#', '/']);
+
+    let mut filter = filter.to_owned();
+    if filter.contains("%c") {
+        if !is_plain(client_id) {
+            return None;
+        }
+        filter = filter.replace("%c", client_id);
+    }
+    if filter.contains("%u") {
+        match username {
+            Some(username) if is_plain(username) => filter = filter.replace("%u", username),
+            _ => return None,
+        }
+    }
+
+    Some(filter)
+}
+
+/// named after the errors of other modules, see `tls::Error`.
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot read the password or ACL file: {0:?}")]
+    ReadError(#[from] std::io::Error),
+    #[error("invalid entry at line {0} of the password file")]
+    InvalidLine(usize),
+    #[error("invalid entry at line {0} of the ACL file")]
+    InvalidAclLine(usize),
+}
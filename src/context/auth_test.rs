@@ -0,0 +1,129 @@
+#![allow(non_snake_case)]
+
+use tokio::sync::RwLock;
+
+use super::auth::*;
+
+const PASSWORDS: &str = "
+# generated by mosquitto_passwd
+alice:$6$MDEyMzQ1Njc4OWFiY2RlZg==$veVOCoSwGP72Xht1aILusDwsSUHyefI11yC6xJHBHZsnVJg6s2+oRchFr/YJLsRuAM0iTVMjwQpm2iqyxIn43A==
+bob:$7$101$MDEyMzQ1Njc4OWFiY2RlZg==$HQezd7h+ZDbVzIIYmADE1qpGnSHblF1j7mBpM/ntu/hKee9EMECDZjFz/ho3KLOIOfbpg+tAAjH0nDI2wNtS3g==
+";
+
+#[test]
+fn test_password_file() {
+    let authenticator = PasswordFileAuthenticator::parse(PASSWORDS).unwrap();
+
+    assert!(authenticator.authenticate("c", "alice", Some(b"secret")));
+    assert!(!authenticator.authenticate("c", "alice", Some(b"hunter2")));
+    assert!(!authenticator.authenticate("c", "alice", None));
+    assert!(authenticator.authenticate("c", "bob", Some(b"hunter2")));
+    assert!(!authenticator.authenticate("c", "bob", Some(b"secret")));
+    assert!(!authenticator.authenticate("c", "eve", Some(b"secret")));
+}
+
+#[test]
+fn test_password_file_invalid() {
+    match PasswordFileAuthenticator::parse("alice:$6$MDEy\nbob") {
+        Err(Error::InvalidLine(1)) => {}
+        result => panic!("expected Error::InvalidLine(1), got {:?}.", result.map(|_| ())),
+    }
+    match PasswordFileAuthenticator::parse("alice:$1$abc$def") {
+        Err(Error::InvalidLine(1)) => {}
+        result => panic!("expected Error::InvalidLine(1), got {:?}.", result.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_anonymous() {
+    let allowed = RwLock::new(AuthManager::new(None, true, None).unwrap());
+    assert_eq!(AuthManager::authenticate(&allowed, "c", None, None).await, Ok(()));
+    // usernames are not verified without a password file.
+    assert_eq!(AuthManager::authenticate(&allowed, "c", Some("anyone"), None).await, Ok(()));
+
+    let denied = RwLock::new(AuthManager::new(None, false, None).unwrap());
+    assert_eq!(AuthManager::authenticate(&denied, "c", None, None).await, Err(Rejection::NotAuthorized));
+}
+
+#[tokio::test]
+async fn test_AuthManager() {
+    let path = std::env::temp_dir().join(format!("telesteller-passwd-{}", std::process::id()));
+    std::fs::write(&path, PASSWORDS).unwrap();
+    let manager = RwLock::new(AuthManager::new(Some(&path), false, None).unwrap());
+
+    assert_eq!(AuthManager::authenticate(&manager, "c", Some("alice"), Some(b"secret")).await, Ok(()));
+    assert_eq!(AuthManager::authenticate(&manager, "c", Some("alice"), Some(b"wrong")).await, Err(Rejection::BadUsernameOrPassword));
+    assert_eq!(AuthManager::authenticate(&manager, "c", None, None).await, Err(Rejection::NotAuthorized));
+}
+
+const ACL: &str = "
+# anonymous clients
+topic read public/#
+
+user alice
+topic readwrite alice/#
+topic deny alice/secret
+topic write inbox
+
+pattern write clients/%c/#
+pattern read users/%u/#
+";
+
+#[test]
+fn test_acl() {
+    let acl = Acl::parse(ACL).unwrap();
+
+    assert!(acl.authorize("c", None, "public/news", Access::Read));
+    assert!(acl.authorize("c", None, "public/#", Access::Read));
+    assert!(!acl.authorize("c", None, "public/news", Access::Write));
+    assert!(!acl.authorize("c", None, "#", Access::Read));
+    // rules of anonymous clients don't apply to users.
+    assert!(!acl.authorize("c", Some("alice"), "public/news", Access::Read));
+
+    assert!(acl.authorize("c", Some("alice"), "alice/a", Access::Write));
+    assert!(acl.authorize("c", Some("alice"), "alice/public/+", Access::Read));
+    assert!(acl.authorize("c", Some("alice"), "inbox", Access::Write));
+    assert!(!acl.authorize("c", Some("alice"), "inbox", Access::Read));
+    assert!(!acl.authorize("c", Some("bob"), "alice/a", Access::Write));
+}
+
+#[test]
+fn test_acl_deny() {
+    let acl = Acl::parse(ACL).unwrap();
+
+    assert!(!acl.authorize("c", Some("alice"), "alice/secret", Access::Write));
+    assert!(!acl.authorize("c", Some("alice"), "alice/secret", Access::Read));
+    // neither is a filter which could receive messages of the denied topic.
+    assert!(!acl.authorize("c", Some("alice"), "alice/#", Access::Read));
+    assert!(!acl.authorize("c", Some("alice"), "alice/+", Access::Read));
+    assert!(acl.authorize("c", Some("alice"), "alice/public/#", Access::Read));
+}
+
+#[test]
+fn test_acl_pattern() {
+    let acl = Acl::parse(ACL).unwrap();
+
+    assert!(acl.authorize("c1", None, "clients/c1/status", Access::Write));
+    assert!(!acl.authorize("c1", None, "clients/c2/status", Access::Write));
+    assert!(acl.authorize("c1", Some("bob"), "users/bob/#", Access::Read));
+    assert!(!acl.authorize("c1", Some("bob"), "users/alice/#", Access::Read));
+    // %u never matches anonymous clients, and wildcards can't be injected by %c.
+    assert!(!acl.authorize("c1", None, "users/+/x", Access::Read));
+    assert!(!acl.authorize("#", None, "clients/a/status", Access::Write));
+}
+
+#[test]
+fn test_acl_invalid() {
+    match Acl::parse("topic read a/#\nuser\n") {
+        Err(Error::InvalidAclLine(2)) => {}
+        result => panic!("expected Error::InvalidAclLine(2), got {:?}.", result.map(|_| ())),
+    }
+    match Acl::parse("topic read a/#/b") {
+        Err(Error::InvalidAclLine(1)) => {}
+        result => panic!("expected Error::InvalidAclLine(1), got {:?}.", result.map(|_| ())),
+    }
+    match Acl::parse("subscribe a") {
+        Err(Error::InvalidAclLine(1)) => {}
+        result => panic!("expected Error::InvalidAclLine(1), got {:?}.", result.map(|_| ())),
+    }
+}
@@ -1,5 +1,19 @@
+pub(crate) use auth::AuthManager;
 pub(crate) use pub_sub::PublisherManager;
 pub(crate) use session::SessionManager;
+pub(crate) use stats::Stats;
 
+pub(crate) mod auth;
 pub(crate) mod pub_sub;
-pub(crate) mod session;
\ No newline at end of file
+pub(crate) mod session;
+pub(crate) mod stats;
+pub(crate) mod topic;
+
+#[cfg(test)]
+mod auth_test;
+#[cfg(test)]
+mod pub_sub_test;
+#[cfg(test)]
+mod session_test;
+#[cfg(test)]
+mod topic_test;
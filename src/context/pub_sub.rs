@@ -1,38 +1,197 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use rand::Rng;
 use tokio::sync::broadcast::{self, error::SendError};
+use tokio::sync::RwLock;
 
+use crate::context::topic::{self, TopicTree};
 use crate::message::request::PUBLISH;
+use crate::opt::ShareStrategy;
 
 pub(crate) struct PublisherManager {
-    publishers: Box<dyn PublisherRepository + Sync + Send>
+    publishers: Box<dyn PublisherRepository + Sync + Send>,
+    /// groups of Shared Subscriptions keyed by the Topic Filter and then the Share Name.
+    shared: TopicTree<HashMap<String, SharedGroup>>,
+    strategy: ShareStrategy,
+    retained: RetainedMessageStore,
 }
 
 impl PublisherManager {
     pub(crate) async fn dispatch(&self, topic: &str, message: PUBLISH) -> Result<(), SendError<Arc<PUBLISH>>> {
         let handle = Arc::new(message);
-        if let Some(publisher) = self.publishers.find(topic) {
-            publisher.send(handle)?;
+        if handle.retain {
+            self.retained.retain(handle.clone()).await;
         }
 
-        Ok(())
+        // every matching publisher should be sent to, even if some of them have no subscriber left.
+        let mut result = Ok(());
+        for publisher in self.publishers.matches(topic) {
+            if let Err(err) = publisher.send(handle.clone()) {
+                result = Err(err);
+            }
+        }
+        for groups in self.shared.matches(topic) {
+            for group in groups.values() {
+                if let Err(err) = group.send(handle.clone(), self.strategy) {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
     }
 
+    /// subscribes to the Topic Filter, or joins the group if it's a Shared Subscription.
     pub(crate) async fn subscribe(&mut self, topic: &str) -> Subscriber {
-        return match self.publishers.find(topic) {
+        if let Some((group, filter)) = topic::parse_shared(topic) {
+            return self.join(group, filter);
+        }
+
+        match self.publishers.find(topic) {
             Some(publisher) => publisher.subscribe(),
             None => {
                 let (tx, rx) = broadcast::channel(1024);
                 self.publishers.add(topic, tx);
                 rx
             }
-        };
+        }
+    }
+
+    /// every member gets its own Publisher, so that a message is only sent to the chosen one.
+    fn join(&mut self, group: &str, filter: &str) -> Subscriber {
+        let (tx, rx) = broadcast::channel(1024);
+        let group = self.shared.get_or_insert_with(filter, HashMap::new)
+            .entry(group.to_owned())
+            .or_insert_with(SharedGroup::new);
+        group.leave();
+        group.members.push(tx);
+
+        rx
+    }
+
+    /// removes the Publisher of the Topic Filter if no subscriber is left, or members that have
+    /// left the group if it's a Shared Subscription.
+    pub(crate) fn unsubscribe(&mut self, filter: &str) {
+        if let Some((group, filter)) = topic::parse_shared(filter) {
+            if let Some(groups) = self.shared.get_mut(filter) {
+                if let Some(shared) = groups.get_mut(group) {
+                    shared.leave();
+                    if shared.members.is_empty() {
+                        groups.remove(group);
+                    }
+                }
+                if groups.is_empty() {
+                    self.shared.remove(filter);
+                }
+            }
+            return;
+        }
+
+        if let Some(0) = self.publishers.find(filter).map(|publisher| publisher.receiver_count()) {
+            self.publishers.remove(filter);
+        }
     }
 
-    pub(crate) fn new() -> PublisherManager {
+    /// number of subscriptions of every Topic Filter, including those of offline clients.
+    pub(crate) fn subscriptions(&self) -> usize {
+        let shared = self.shared.values().iter()
+            .flat_map(|groups| groups.values())
+            .flat_map(|group| group.members.iter())
+            .map(|member| member.receiver_count())
+            .sum::<usize>();
+        self.publishers.all().iter().map(|publisher| publisher.receiver_count()).sum::<usize>() + shared
+    }
+
+    /// returns retained messages that should be sent to a newly created subscription of `filter`,
+    /// which are never sent to a Shared Subscription according to MQTT 5 spec 4.8.2.
+    pub(crate) async fn retained(&self, filter: &str) -> Vec<Arc<PUBLISH>> {
+        match topic::parse_shared(filter) {
+            Some(_) => Vec::new(),
+            None => self.retained.find(filter).await,
+        }
+    }
+
+    pub(crate) fn new(strategy: ShareStrategy) -> PublisherManager {
         PublisherManager {
-            publishers: Box::new(DumbPublisherRepository::new())
+            publishers: Box::new(TopicTreePublisherRepository::new()),
+            shared: TopicTree::new(),
+            strategy,
+            retained: RetainedMessageStore::new(),
+        }
+    }
+}
+
+/// Members of a Shared Subscription, of which only one receives each message.
+struct SharedGroup {
+    members: Vec<Publisher>,
+    /// the member to be chosen next by [`ShareStrategy::RoundRobin`].
+    next: AtomicUsize,
+}
+
+impl SharedGroup {
+    /// sends the message to a member chosen by the strategy. Members that have left but are not
+    /// removed yet are skipped, so the message goes to the next one instead.
+    fn send(&self, message: Arc<PUBLISH>, strategy: ShareStrategy) -> Result<(), SendError<Arc<PUBLISH>>> {
+        let len = self.members.len();
+        if len == 0 {
+            return Err(SendError(message));
+        }
+
+        let start = match strategy {
+            ShareStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            ShareStrategy::Random => rand::thread_rng().gen_range(0..len),
+        };
+        for i in 0..len {
+            let member = &self.members[(start + i) % len];
+            if member.receiver_count() > 0 {
+                return member.send(message).map(|_| ());
+            }
+        }
+
+        Err(SendError(message))
+    }
+
+    /// removes members whose subscriber is gone.
+    fn leave(&mut self) {
+        self.members.retain(|member| member.receiver_count() > 0);
+    }
+
+    fn new() -> Self {
+        SharedGroup {
+            members: Vec::new(),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Keeps the last message published with RETAIN flag of every topic, according to MQTT 3.1.1 spec 3.3.1.3.
+struct RetainedMessageStore {
+    messages: RwLock<HashMap<String, Arc<PUBLISH>>>
+}
+
+impl RetainedMessageStore {
+    /// replaces the retained message of the topic, or removes it if the payload is empty.
+    async fn retain(&self, message: Arc<PUBLISH>) {
+        let mut messages = self.messages.write().await;
+        if message.payload.is_empty() {
+            messages.remove(&message.topic);
+        } else {
+            messages.insert(message.topic.clone(), message);
+        }
+    }
+
+    async fn find(&self, filter: &str) -> Vec<Arc<PUBLISH>> {
+        self.messages.read().await.iter()
+            .filter(|(topic, _)| topic::matches(filter, topic))
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    fn new() -> Self {
+        RetainedMessageStore {
+            messages: RwLock::new(HashMap::new())
         }
     }
 }
@@ -40,29 +199,48 @@ impl PublisherManager {
 pub(crate) type Subscriber = broadcast::Receiver<Arc<PUBLISH>>;
 pub(crate) type Publisher = broadcast::Sender<Arc<PUBLISH>>;
 
-trait PublisherRepository {
-    fn contains(&self, topic: &str) -> bool;
-    fn find(&self, topic: &str) -> Option<&Publisher>;
-    fn add(&mut self, topic: &str, publisher: Publisher);
-    fn remove(&mut self, topic: &str);
+/// Stores a Publisher for every Topic Filter subscribed.
+#[allow(dead_code)]
+pub(crate) trait PublisherRepository {
+    fn contains(&self, filter: &str) -> bool;
+    /// finds the Publisher of exactly the Topic Filter.
+    fn find(&self, filter: &str) -> Option<&Publisher>;
+    /// finds Publishers of every Topic Filter matching the Topic Name.
+    fn matches(&self, topic: &str) -> Vec<&Publisher>;
+    fn all(&self) -> Vec<&Publisher>;
+    fn add(&mut self, filter: &str, publisher: Publisher);
+    fn remove(&mut self, filter: &str);
     fn new() -> Self where Self: Sized;
 }
 
-struct DumbPublisherRepository {
+/// A PublisherRepository that scans every Topic Filter while matching, which is kept as the baseline
+/// of benchmarks.
+#[cfg(any(test, feature = "bench"))]
+pub(crate) struct DumbPublisherRepository {
     repository: HashMap<String, Publisher>
 }
 
+#[cfg(any(test, feature = "bench"))]
 impl PublisherRepository for DumbPublisherRepository {
-    fn contains(&self, topic: &str) -> bool { self.repository.contains_key(topic) }
+    fn contains(&self, filter: &str) -> bool { self.repository.contains_key(filter) }
+
+    fn find(&self, filter: &str) -> Option<&Publisher> { self.repository.get(filter) }
+
+    fn matches(&self, topic: &str) -> Vec<&Publisher> {
+        self.repository.iter()
+            .filter(|(filter, _)| topic::matches(filter, topic))
+            .map(|(_, publisher)| publisher)
+            .collect()
+    }
 
-    fn find(&self, topic: &str) -> Option<&Publisher> { self.repository.get(topic) }
+    fn all(&self) -> Vec<&Publisher> { self.repository.values().collect() }
 
-    fn add(&mut self, topic: &str, publisher: Publisher) {
-        self.repository.insert(topic.to_owned(), publisher);
+    fn add(&mut self, filter: &str, publisher: Publisher) {
+        self.repository.insert(filter.to_owned(), publisher);
     }
 
-    fn remove(&mut self, topic: &str) {
-        self.repository.retain(|key, _| key != topic)
+    fn remove(&mut self, filter: &str) {
+        self.repository.remove(filter);
     }
 
     fn new() -> Self where Self: Sized {
@@ -70,4 +248,32 @@ impl PublisherRepository for DumbPublisherRepository {
             repository: HashMap::new()
         }
     }
-}
\ No newline at end of file
+}
+
+pub(crate) struct TopicTreePublisherRepository {
+    repository: TopicTree<Publisher>
+}
+
+impl PublisherRepository for TopicTreePublisherRepository {
+    fn contains(&self, filter: &str) -> bool { self.repository.get(filter).is_some() }
+
+    fn find(&self, filter: &str) -> Option<&Publisher> { self.repository.get(filter) }
+
+    fn matches(&self, topic: &str) -> Vec<&Publisher> { self.repository.matches(topic) }
+
+    fn all(&self) -> Vec<&Publisher> { self.repository.values() }
+
+    fn add(&mut self, filter: &str, publisher: Publisher) {
+        self.repository.insert(filter, publisher);
+    }
+
+    fn remove(&mut self, filter: &str) {
+        self.repository.remove(filter);
+    }
+
+    fn new() -> Self where Self: Sized {
+        TopicTreePublisherRepository {
+            repository: TopicTree::new()
+        }
+    }
+}
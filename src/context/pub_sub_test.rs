@@ -0,0 +1,140 @@
+use bytes::Bytes;
+
+use crate::message::Qos;
+use crate::message::property::Properties;
+use crate::message::request::PUBLISH;
+use crate::opt::ShareStrategy;
+
+use super::pub_sub::*;
+
+fn publish(topic: &str, payload: &'static str, retain: bool) -> PUBLISH {
+    PUBLISH {
+        dup: false,
+        qos: Qos::AcknowledgedDeliver,
+        retain,
+        topic: topic.to_owned(),
+        id: Some(1),
+        payload: Bytes::from(payload),
+        properties: Properties::default(),
+        origin: None,
+        expires_at: None,
+    }
+}
+
+#[tokio::test]
+async fn test_dispatch_retained() {
+    let mut manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    manager.dispatch("a/b", publish("a/b", "first", true)).await.unwrap();
+    manager.dispatch("a/b", publish("a/b", "second", true)).await.unwrap();
+    manager.dispatch("a/b", publish("a/b", "not retained", false)).await.unwrap();
+
+    let retained = manager.retained("a/b").await;
+    assert_eq!(retained.len(), 1);
+    assert_eq!(retained[0].payload, Bytes::from("second"));
+    assert!(manager.retained("a/c").await.is_empty());
+
+    // retained messages are delivered to current subscribers as well.
+    let mut subscriber = manager.subscribe("a/b").await;
+    manager.dispatch("a/b", publish("a/b", "third", true)).await.unwrap();
+    assert_eq!(subscriber.recv().await.unwrap().payload, Bytes::from("third"));
+}
+
+#[tokio::test]
+async fn test_dispatch_retained_empty_payload() {
+    let manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    manager.dispatch("a/b", publish("a/b", "state", true)).await.unwrap();
+    manager.dispatch("a/b", publish("a/b", "", true)).await.unwrap();
+
+    assert!(manager.retained("a/b").await.is_empty());
+}
+
+#[tokio::test]
+async fn test_unsubscribe() {
+    let mut manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    let subscriber1 = manager.subscribe("a/+").await;
+    let mut subscriber2 = manager.subscribe("a/+").await;
+
+    // the Publisher is kept as long as there're subscribers left.
+    drop(subscriber1);
+    manager.unsubscribe("a/+");
+    manager.dispatch("a/b", publish("a/b", "message", false)).await.unwrap();
+    assert_eq!(subscriber2.recv().await.unwrap().payload, Bytes::from("message"));
+
+    drop(subscriber2);
+    manager.unsubscribe("a/+");
+    assert!(manager.dispatch("a/b", publish("a/b", "message", false)).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_shared_round_robin() {
+    let mut manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    let mut member1 = manager.subscribe("$share/group/a/+").await;
+    let mut member2 = manager.subscribe("$share/group/a/+").await;
+    let mut other = manager.subscribe("$share/other/a/b").await;
+    assert_eq!(manager.subscriptions(), 3);
+
+    for payload in ["1", "2", "3", "4"] {
+        manager.dispatch("a/b", publish("a/b", payload, false)).await.unwrap();
+    }
+    for payload in ["1", "3"] {
+        assert_eq!(member1.recv().await.unwrap().payload, Bytes::from(payload));
+    }
+    for payload in ["2", "4"] {
+        assert_eq!(member2.recv().await.unwrap().payload, Bytes::from(payload));
+    }
+    // every group receives a copy.
+    for payload in ["1", "2", "3", "4"] {
+        assert_eq!(other.recv().await.unwrap().payload, Bytes::from(payload));
+    }
+    assert!(member1.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_shared_random() {
+    let mut manager = PublisherManager::new(ShareStrategy::Random);
+    let mut member1 = manager.subscribe("$share/group/a/b").await;
+    let mut member2 = manager.subscribe("$share/group/a/b").await;
+
+    for _ in 0..100 {
+        manager.dispatch("a/b", publish("a/b", "message", false)).await.unwrap();
+    }
+    let (mut received1, mut received2) = (0, 0);
+    while member1.try_recv().is_ok() {
+        received1 += 1;
+    }
+    while member2.try_recv().is_ok() {
+        received2 += 1;
+    }
+    assert_eq!(received1 + received2, 100);
+}
+
+#[tokio::test]
+async fn test_shared_member_left() {
+    let mut manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    let member1 = manager.subscribe("$share/group/a/b").await;
+    let mut member2 = manager.subscribe("$share/group/a/b").await;
+
+    // messages go to the members left, even before the one gone is unsubscribed.
+    drop(member1);
+    for payload in ["1", "2"] {
+        manager.dispatch("a/b", publish("a/b", payload, false)).await.unwrap();
+        assert_eq!(member2.recv().await.unwrap().payload, Bytes::from(payload));
+    }
+
+    manager.unsubscribe("$share/group/a/b");
+    assert_eq!(manager.subscriptions(), 1);
+    drop(member2);
+    manager.unsubscribe("$share/group/a/b");
+    assert_eq!(manager.subscriptions(), 0);
+    assert!(manager.dispatch("a/b", publish("a/b", "message", false)).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_shared_retained() {
+    let mut manager = PublisherManager::new(ShareStrategy::RoundRobin);
+    manager.dispatch("a/b", publish("a/b", "retained", true)).await.unwrap();
+    let _member = manager.subscribe("$share/group/a/b").await;
+
+    assert!(manager.retained("$share/group/a/b").await.is_empty());
+    assert_eq!(manager.retained("a/b").await.len(), 1);
+}
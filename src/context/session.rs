@@ -1,60 +1,557 @@
 use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use crate::handler::Session;
+use bytes::{BufMut, Bytes};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
+
+use crate::handler::{DesignatedSubscription, InflightMessage, MessageStream, QueuedMessage, Session};
+use crate::handler::pub_sub::expires_at;
+use crate::message::{Qos, SubscriptionOptions};
+use crate::message::property::{self, Properties};
+use crate::message::request::PUBLISH;
+
+/// sent to a live connection to ask it to close for a new one with the same Client Identifier.
+/// The connection holds the Takeover until it's fully closed, so dropping it tells the new
+/// connection that the Session can be taken over.
+pub(crate) type Takeover = oneshot::Sender<()>;
+
+/// A task queueing messages for an offline client, which exits once `stop` is sent, returning the
+/// streams of the subscriptions, or once `stop` is dropped, queueing the messages received so far.
+pub(crate) struct Parked {
+    pub(crate) stop: oneshot::Sender<()>,
+    pub(crate) task: JoinHandle<MessageStream>,
+}
+
+/// A live connection in the registry of SessionManager, which is received from when it's taken over.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    pub(crate) client_id: String,
+    id: u64,
+    pub(crate) takeover: oneshot::Receiver<Takeover>,
+}
 
 pub(crate) struct SessionManager {
-    sessions: Box<dyn SessionRepository + Sync + Send>
+    sessions: Box<dyn SessionRepository + Sync + Send>,
+    /// live connections keyed by the Client Identifier, see MQTT 3.1.1 spec 3.1.4.
+    connections: HashMap<String, (u64, oneshot::Sender<Takeover>)>,
+    last_connection: u64,
+    /// tasks queueing messages for clients that are offline.
+    parked: HashMap<String, Parked>,
+    /// number of messages dropped for each offline client since its queue is full.
+    dropped: HashMap<String, usize>,
+    max_queued: usize,
 }
 
 impl SessionManager {
     pub(crate) fn get(&self, client_id: &str) -> Option<&Session> { self.sessions.get(client_id) }
-    pub(crate) fn get_mut(&mut self, client_id: &str) -> Option<&mut Session> { self.sessions.get_mut(client_id) }
     pub(crate) fn put(&mut self, client_id: &str, session: Session) { self.sessions.put(client_id, session) }
     pub(crate) fn evict(&mut self, client_id: &str) { self.sessions.evict(client_id) }
+    pub(crate) fn client_ids(&self) -> Vec<String> { self.sessions.client_ids() }
 
-    pub(crate) fn new(size: usize) -> Self {
-        SessionManager {
-            sessions: Box::new(HashMapSessionRepository::new())
+    /// waits until every change so far is written to the session file, if any, which should be
+    /// awaited after the SessionManager is released.
+    pub(crate) fn synced(&self) -> impl Future<Output=()> {
+        let synced = self.sessions.synced();
+        async move {
+            if let Some(synced) = synced {
+                let _ = synced.await;
+            }
         }
     }
+
+    /// number of clients connected.
+    pub(crate) fn connected(&self) -> usize { self.connections.len() }
+
+    /// number of clients either connected, or offline with a persistent Session.
+    pub(crate) fn total(&self) -> usize {
+        let offline = self.sessions.client_ids().into_iter()
+            .filter(|client_id| !self.connections.contains_key(client_id))
+            .count();
+        self.connections.len() + offline
+    }
+
+    /// queues a message for the offline client. The message is dropped if the queue is full,
+    /// returning the number of messages dropped so far.
+    pub(crate) fn enqueue(&mut self, client_id: &str, message: QueuedMessage) -> Result<(), usize> {
+        match self.sessions.get(client_id) {
+            Some(session) if session.queue.len() >= self.max_queued => {
+                let dropped = self.dropped.entry(client_id.to_owned()).or_default();
+                *dropped += 1;
+                Err(*dropped)
+            }
+            Some(_) => {
+                self.sessions.enqueue(client_id, message);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// keeps the task queueing messages for the offline client, until it connects again.
+    pub(crate) fn park(&mut self, client_id: &str, parked: Parked) {
+        // the previous task is stopped by dropping `stop`.
+        self.parked.insert(client_id.to_owned(), parked);
+    }
+
+    /// returns the task queueing messages for the client, which should be stopped as it connects.
+    pub(crate) fn unpark(&mut self, client_id: &str) -> Option<Parked> { self.parked.remove(client_id) }
+
+    /// removes the Session of the offline client once it expires, along with the task queueing
+    /// messages for it.
+    pub(crate) fn expire(&mut self, client_id: &str) {
+        self.sessions.evict(client_id);
+        self.parked.remove(client_id);
+        self.dropped.remove(client_id);
+    }
+
+    /// returns the number of messages dropped while the client was offline.
+    pub(crate) fn take_dropped(&mut self, client_id: &str) -> usize { self.dropped.remove(client_id).unwrap_or(0) }
+
+    /// registers a live connection of the client, replacing the one that is already connected,
+    /// which is returned to be taken over.
+    pub(crate) fn register(&mut self, client_id: &str) -> (Registration, Option<oneshot::Sender<Takeover>>) {
+        self.last_connection += 1;
+        let (tx, rx) = oneshot::channel();
+        let replaced = self.connections.insert(client_id.to_owned(), (self.last_connection, tx));
+
+        (Registration { client_id: client_id.to_owned(), id: self.last_connection, takeover: rx }, replaced.map(|(_, tx)| tx))
+    }
+
+    /// removes the connection from the registry, unless it has been replaced by a new one.
+    pub(crate) fn deregister(&mut self, registration: &Registration) {
+        if let Some((id, _)) = self.connections.get(&registration.client_id) {
+            if *id == registration.id {
+                self.connections.remove(&registration.client_id);
+            }
+        }
+    }
+
+    /// Sessions are kept in memory only, unless `session_file` is given to persist them across
+    /// restarts. Up to `max_queued` messages are queued for each offline client.
+    pub(crate) fn new(_size: usize, session_file: Option<&Path>, max_queued: usize) -> Result<Self, Error> {
+        let sessions: Box<dyn SessionRepository + Sync + Send> = match session_file {
+            Some(path) => Box::new(LogSessionRepository::open(path)?),
+            None => Box::new(HashMapSessionRepository::new()),
+        };
+
+        Ok(SessionManager {
+            sessions,
+            connections: HashMap::new(),
+            last_connection: 0,
+            parked: HashMap::new(),
+            dropped: HashMap::new(),
+            max_queued,
+        })
+    }
 }
 
 trait SessionRepository {
     fn get(&self, client_id: &str) -> Option<&Session>;
-    fn get_mut(&mut self, client_id: &str) -> Option<&mut Session>;
     fn put(&mut self, client_id: &str, session: Session);
     fn evict(&mut self, client_id: &str);
-    fn new() -> Self where Self: Sized;
+    /// appends the message to the queue of the Session, if it exists.
+    fn enqueue(&mut self, client_id: &str, message: QueuedMessage);
+    fn client_ids(&self) -> Vec<String>;
+    /// completes once every change so far is persisted, or None if nothing is persisted.
+    fn synced(&self) -> Option<oneshot::Receiver<()>>;
 }
 
 struct HashMapSessionRepository {
     repository: HashMap<String, Session>
 }
 
+impl HashMapSessionRepository {
+    fn new() -> Self {
+        HashMapSessionRepository {
+            repository: HashMap::new()
+        }
+    }
+}
+
 impl SessionRepository for HashMapSessionRepository {
     fn get(&self, client_id: &str) -> Option<&Session> {
-        self.repository.get(&client_id.to_owned())
+        self.repository.get(client_id)
     }
 
-    fn get_mut(&mut self, client_id: &str) -> Option<&mut Session> {
-        self.repository.get_mut(&client_id.to_owned())
+    fn put(&mut self, client_id: &str, session: Session) {
+        trace!(client_id, "Session saved.");
+        self.repository.insert(client_id.to_owned(), session);
     }
 
-    fn put(&mut self, client_id: &str, session: Session) {
-        for (client_id, session) in self.repository.iter() {
-            println!("({}, {:?}) ", client_id, session);
+    fn evict(&mut self, client_id: &str) {
+        self.repository.remove(client_id);
+    }
+
+    fn enqueue(&mut self, client_id: &str, message: QueuedMessage) {
+        if let Some(session) = self.repository.get_mut(client_id) {
+            session.queue.push_back(message);
+        }
+    }
+
+    fn client_ids(&self) -> Vec<String> { self.repository.keys().cloned().collect() }
+
+    fn synced(&self) -> Option<oneshot::Receiver<()>> { None }
+}
+
+const PUT: u8 = 0;
+const EVICT: u8 = 1;
+const ENQUEUE: u8 = 2;
+/// the log is not compacted until it has this many records more than the Sessions.
+const COMPACTION_THRESHOLD: usize = 1024;
+
+/// Keeps Sessions in memory, while every change is appended to a log file as well, so that the
+/// Sessions could be recovered by replaying the log after the Server restarts, even if it crashed.
+/// The log is compacted into a snapshot of the Sessions when it's opened, and once it grows too
+/// long.
+///
+/// each record is a Four Byte Integer of its length, followed by a byte of its type and the
+/// Client Identifier. A PUT record is then followed by the Session, and an ENQUEUE record by the
+/// message queued, so that queueing a message doesn't rewrite the whole Session.
+///
+/// the file is written by a dedicated thread, so that changes never block the executor or the
+/// SessionManager, and changes made meanwhile are synced to the disk together.
+struct LogSessionRepository {
+    repository: HashMap<String, Session>,
+    records: usize,
+    writer: Option<mpsc::UnboundedSender<Write>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// a change of the session file, which is made by the writer thread in order.
+enum Write {
+    Append(Vec<u8>),
+    /// replaces the log with the snapshot of Sessions.
+    Compact(Vec<u8>),
+    /// sent once every change before is synced to the disk.
+    Sync(oneshot::Sender<()>),
+}
+
+impl LogSessionRepository {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let mut repository = HashMap::new();
+        match fs::read(path) {
+            Ok(bytes) => LogSessionRepository::replay(&bytes, &mut repository)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        info!(path = ?path, sessions = repository.len(), "Sessions recovered from the session file.");
+
+        let file = LogSessionRepository::compact(path, &LogSessionRepository::snapshot(&repository))?;
+        let (writer, writes) = mpsc::unbounded_channel();
+        let path = path.to_owned();
+        let thread = thread::Builder::new()
+            .name("session-writer".to_owned())
+            .spawn(move || LogSessionRepository::write(path, file, writes))?;
+
+        Ok(LogSessionRepository {
+            records: repository.len(),
+            repository,
+            writer: Some(writer),
+            thread: Some(thread),
+        })
+    }
+
+    fn replay(bytes: &[u8], repository: &mut HashMap<String, Session>) -> Result<(), Error> {
+        let mut cursor = 0;
+        while cursor < bytes.len() {
+            let offset = cursor;
+            let record = property::read_u32(&mut cursor, bytes)
+                .and_then(|len| property::read_slice(&mut cursor, len as usize, bytes));
+            // the last record is incomplete if the Server crashed while writing it.
+            let record = match record {
+                Ok(record) => record,
+                Err(_) => {
+                    warn!(offset, "incomplete record at the end of the session file is discarded.");
+                    return Ok(());
+                }
+            };
+
+            let (kind, record) = record.split_first().ok_or(Error::Corrupted(offset))?;
+            let mut cursor = 0;
+            let client_id = property::read_text(&mut cursor, record).map_err(|_| Error::Corrupted(offset))?;
+            match *kind {
+                PUT => {
+                    let session = read_session(&mut cursor, record).ok_or(Error::Corrupted(offset))?;
+                    repository.insert(client_id, session);
+                }
+                EVICT => { repository.remove(&client_id); }
+                ENQUEUE => {
+                    let message = read_queued(&mut cursor, record).ok_or(Error::Corrupted(offset))?;
+                    if let Some(session) = repository.get_mut(&client_id) {
+                        session.queue.push_back(message);
+                    }
+                }
+                _ => return Err(Error::Corrupted(offset)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// a PUT record of each Session.
+    fn snapshot(repository: &HashMap<String, Session>) -> Vec<u8> {
+        let mut snapshot = Vec::new();
+        for (client_id, session) in repository.iter() {
+            put_record(PUT, client_id, |dst| put_session(session, dst), &mut snapshot);
+        }
+        snapshot
+    }
+
+    /// rewrites the log with the snapshot, which replaces the log atomically.
+    fn compact(path: &Path, snapshot: &[u8]) -> Result<File, Error> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(snapshot)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+
+        Ok(OpenOptions::new().append(true).open(path)?)
+    }
+
+    /// runs on the writer thread until the repository is dropped, which syncs the file once for
+    /// all changes received so far.
+    fn write(path: PathBuf, mut file: File, mut writes: mpsc::UnboundedReceiver<Write>) {
+        while let Some(write) = writes.blocking_recv() {
+            let mut synced = Vec::new();
+            let mut next = Some(write);
+            while let Some(write) = next {
+                match write {
+                    Write::Append(record) => if let Err(err) = file.write_all(&record) {
+                        error!(err = ?err, path = ?&path, "failed to write the session file, the Session will be lost on restart.");
+                    },
+                    Write::Compact(snapshot) => match LogSessionRepository::compact(&path, &snapshot) {
+                        Ok(compacted) => file = compacted,
+                        Err(err) => error!(err = ?err, path = ?&path, "failed to compact the session file."),
+                    },
+                    Write::Sync(tx) => synced.push(tx),
+                }
+                next = writes.try_recv().ok();
+            }
+
+            if let Err(err) = file.sync_data() {
+                error!(err = ?err, path = ?&path, "failed to sync the session file, the Session may be lost on crash.");
+            }
+            for tx in synced {
+                let _ = tx.send(());
+            }
         }
+    }
 
+    /// appends the record of a change which has been applied to the Sessions.
+    fn append(&mut self, record: Vec<u8>) {
+        self.send(Write::Append(record));
+
+        self.records += 1;
+        if self.records > self.repository.len() * 2 + COMPACTION_THRESHOLD {
+            self.send(Write::Compact(LogSessionRepository::snapshot(&self.repository)));
+            self.records = self.repository.len();
+        }
+    }
+
+    fn send(&self, write: Write) {
+        // the writer thread lives as long as the repository, unless it panicked.
+        if let Some(writer) = &self.writer {
+            if writer.send(write).is_err() {
+                error!("the writer of the session file exited, the Session will be lost on restart.");
+            }
+        }
+    }
+}
+
+impl Drop for LogSessionRepository {
+    /// waits for the writer thread to write everything left.
+    fn drop(&mut self) {
+        self.writer.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl SessionRepository for LogSessionRepository {
+    fn get(&self, client_id: &str) -> Option<&Session> {
+        self.repository.get(client_id)
+    }
+
+    fn put(&mut self, client_id: &str, session: Session) {
+        let mut record = Vec::new();
+        put_record(PUT, client_id, |dst| put_session(&session, dst), &mut record);
         self.repository.insert(client_id.to_owned(), session);
+        self.append(record);
     }
 
     fn evict(&mut self, client_id: &str) {
-        self.repository.remove(client_id);
+        if self.repository.remove(client_id).is_some() {
+            let mut record = Vec::new();
+            put_record(EVICT, client_id, |_| {}, &mut record);
+            self.append(record);
+        }
     }
 
-    fn new() -> Self where Self: Sized {
-        HashMapSessionRepository {
-            repository: HashMap::new()
+    fn enqueue(&mut self, client_id: &str, message: QueuedMessage) {
+        if let Some(session) = self.repository.get_mut(client_id) {
+            let mut record = Vec::new();
+            put_record(ENQUEUE, client_id, |dst| put_queued(&message, dst), &mut record);
+            session.queue.push_back(message);
+            self.append(record);
         }
     }
+
+    fn client_ids(&self) -> Vec<String> { self.repository.keys().cloned().collect() }
+
+    fn synced(&self) -> Option<oneshot::Receiver<()>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Write::Sync(tx));
+        Some(rx)
+    }
+}
+
+fn put_text(value: &str, dst: &mut Vec<u8>) {
+    dst.put_u16(value.len() as u16);
+    dst.extend_from_slice(value.as_bytes());
+}
+
+fn put_record(kind: u8, client_id: &str, put_body: impl FnOnce(&mut Vec<u8>), dst: &mut Vec<u8>) {
+    let mut record = vec![kind];
+    put_text(client_id, &mut record);
+    put_body(&mut record);
+
+    dst.put_u32(record.len() as u32);
+    dst.extend_from_slice(&record);
+}
+
+fn put_session(session: &Session, dst: &mut Vec<u8>) {
+    dst.put_u16(session.last_id);
+
+    dst.put_u32(session.subscriptions.len() as u32);
+    for subscription in session.subscriptions.iter() {
+        put_text(&subscription.topic, dst);
+        dst.put_u8(subscription.options.to_byte(subscription.qos));
+    }
+
+    dst.put_u32(session.received.len() as u32);
+    for id in session.received.iter() {
+        dst.put_u16(*id);
+    }
+
+    dst.put_u32(session.inflight.len() as u32);
+    for (id, inflight) in session.inflight.iter() {
+        dst.put_u16(*id);
+        dst.put_u8(inflight.qos as u8);
+        dst.put_u8(inflight.retain as u8 | (inflight.released as u8) << 1);
+        put_message(&inflight.message, dst);
+    }
+
+    dst.put_u32(session.queue.len() as u32);
+    for queued in session.queue.iter() {
+        put_queued(queued, dst);
+    }
+
+    dst.put_u32(session.expiry_interval.map_or(u32::MAX, |interval| interval.as_secs() as u32));
+}
+
+fn put_queued(queued: &QueuedMessage, dst: &mut Vec<u8>) {
+    dst.put_u8(queued.qos as u8);
+    put_message(&queued.message, dst);
+}
+
+fn put_message(message: &PUBLISH, dst: &mut Vec<u8>) {
+    put_text(&message.topic, dst);
+    dst.put_u8(message.qos as u8);
+    dst.put_u8(message.dup as u8 | (message.retain as u8) << 1);
+    // 0 is not a valid Packet Identifier, which stands for None.
+    dst.put_u16(message.id.unwrap_or(0));
+    message.properties.to_bytes(dst);
+    dst.put_u32(message.payload.len() as u32);
+    dst.extend_from_slice(&message.payload);
+}
+
+fn read_qos(cursor: &mut usize, bytes: &[u8]) -> Option<Qos> {
+    Qos::from_byte(&property::read_u8(cursor, bytes).ok()?).ok()
+}
+
+fn read_session(cursor: &mut usize, bytes: &[u8]) -> Option<Session> {
+    let mut session = Session {
+        last_id: property::read_u16(cursor, bytes).ok()?,
+        ..Session::default()
+    };
+
+    for _ in 0..property::read_u32(cursor, bytes).ok()? {
+        let topic = property::read_text(cursor, bytes).ok()?;
+        // the Subscription Options byte including the Maximum Qos.
+        let (qos, options) = SubscriptionOptions::from_byte(&property::read_u8(cursor, bytes).ok()?).ok()?;
+        session.subscriptions.insert(DesignatedSubscription::from((topic, qos, options)));
+    }
+
+    for _ in 0..property::read_u32(cursor, bytes).ok()? {
+        session.received.insert(property::read_u16(cursor, bytes).ok()?);
+    }
+
+    for _ in 0..property::read_u32(cursor, bytes).ok()? {
+        let id = property::read_u16(cursor, bytes).ok()?;
+        let qos = read_qos(cursor, bytes)?;
+        let flags = property::read_u8(cursor, bytes).ok()?;
+        session.inflight.insert(id, InflightMessage {
+            qos,
+            retain: flags & 1 != 0,
+            message: Arc::new(read_message(cursor, bytes)?),
+            released: flags & 2 != 0,
+        });
+    }
+
+    for _ in 0..property::read_u32(cursor, bytes).ok()? {
+        session.queue.push_back(read_queued(cursor, bytes)?);
+    }
+    // u32::MAX stands for a Session which never expires.
+    let interval = property::read_u32(cursor, bytes).ok()?;
+    session.expiry_interval = (interval != u32::MAX).then(|| Duration::from_secs(interval as u64));
+
+    Some(session)
+}
+
+fn read_queued(cursor: &mut usize, bytes: &[u8]) -> Option<QueuedMessage> {
+    Some(QueuedMessage {
+        qos: read_qos(cursor, bytes)?,
+        message: Arc::new(read_message(cursor, bytes)?),
+    })
+}
+
+fn read_message(cursor: &mut usize, bytes: &[u8]) -> Option<PUBLISH> {
+    let topic = property::read_text(cursor, bytes).ok()?;
+    let qos = read_qos(cursor, bytes)?;
+    let flags = property::read_u8(cursor, bytes).ok()?;
+    let id = property::read_u16(cursor, bytes).ok()?;
+    let properties = Properties::from_bytes(cursor, bytes).ok()?;
+    let len = property::read_u32(cursor, bytes).ok()? as usize;
+    let payload = Bytes::copy_from_slice(property::read_slice(cursor, len, bytes).ok()?);
+
+    Some(PUBLISH {
+        dup: flags & 1 != 0,
+        qos,
+        retain: flags & 2 != 0,
+        topic,
+        id: (id != 0).then_some(id),
+        payload,
+        // counted from now on, as the Server might have been restarted since it's received.
+        expires_at: expires_at(&properties),
+        properties,
+        origin: None,
+    })
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot read or write the session file: {0:?}")]
+    ReadError(#[from] std::io::Error),
+    #[error("corrupted record at offset {0} of the session file")]
+    Corrupted(usize),
 }
\ No newline at end of file
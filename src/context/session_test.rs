@@ -0,0 +1,157 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::handler::{DesignatedSubscription, InflightMessage, QueuedMessage, Session};
+use crate::message::{Qos, RetainHandling, SubscriptionOptions};
+use crate::message::property::{Properties, Property};
+use crate::message::request::PUBLISH;
+
+use super::session::*;
+
+fn session_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("telesteller-sessions-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn session() -> Session {
+    let message = Arc::new(PUBLISH {
+        dup: false,
+        qos: Qos::AssuredDelivery,
+        retain: false,
+        topic: "a/b".to_owned(),
+        id: Some(7),
+        payload: Bytes::from("payload"),
+        properties: Properties(vec![Property::UserProperty("k".to_owned(), "v".to_owned())]),
+        origin: None,
+        expires_at: None,
+    });
+
+    let mut session = Session::default();
+    session.subscriptions.insert(DesignatedSubscription::from(("a/+".to_owned(), Qos::AssuredDelivery, SubscriptionOptions::default())));
+    session.subscriptions.insert(DesignatedSubscription::from(("c/#".to_owned(), Qos::FireAndForget, SubscriptionOptions {
+        no_local: true,
+        retain_as_published: true,
+        retain_handling: RetainHandling::IfNew,
+    })));
+    session.received.insert(3);
+    session.inflight.insert(1, InflightMessage { qos: Qos::AcknowledgedDeliver, retain: true, message: message.clone(), released: false });
+    session.inflight.insert(2, InflightMessage { qos: Qos::AssuredDelivery, retain: false, message: message.clone(), released: true });
+    session.queue.push_back(QueuedMessage { qos: Qos::AcknowledgedDeliver, message });
+    session.last_id = 2;
+    session.expiry_interval = Some(Duration::from_secs(60));
+    session
+}
+
+#[test]
+fn test_session_file() {
+    let path = session_file("reopen");
+    {
+        let mut manager = SessionManager::new(0, Some(&path), 2).unwrap();
+        manager.put("a", session());
+        manager.put("b", Session::default());
+        manager.put("c", Session::default());
+        manager.evict("b");
+    }
+
+    let manager = SessionManager::new(0, Some(&path), 2).unwrap();
+    assert_eq!(manager.get("a"), Some(&session()));
+    // subscriptions are equal by the Topic Filter only.
+    assert_eq!(manager.get("a").unwrap().subscriptions.get("c/#").unwrap().options, session().subscriptions.get("c/#").unwrap().options);
+    assert_eq!(manager.get("b"), None);
+    assert_eq!(manager.get("c"), Some(&Session::default()));
+}
+
+#[test]
+fn test_session_file_incomplete() {
+    let path = session_file("incomplete");
+    {
+        let mut manager = SessionManager::new(0, Some(&path), 2).unwrap();
+        manager.put("a", session());
+    }
+    // the Server crashed while writing a record.
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01]).unwrap();
+
+    let manager = SessionManager::new(0, Some(&path), 2).unwrap();
+    assert_eq!(manager.get("a"), Some(&session()));
+}
+
+#[test]
+fn test_session_file_corrupted() {
+    let path = session_file("corrupted");
+    std::fs::write(&path, [0x00, 0x00, 0x00, 0x03, 0x05, 0x00, 0x00]).unwrap();
+
+    match SessionManager::new(0, Some(&path), 2) {
+        Err(Error::Corrupted(0)) => {}
+        result => panic!("expected Error::Corrupted(0), got {:?}.", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_session_file_compaction() {
+    let path = session_file("compaction");
+    {
+        let mut manager = SessionManager::new(0, Some(&path), 2).unwrap();
+        manager.put("a", session());
+        manager.put("b", Session::default());
+    }
+    // the file is written by another thread until the manager is dropped.
+    let len = std::fs::metadata(&path).unwrap().len();
+    {
+        let mut manager = SessionManager::new(0, Some(&path), 2).unwrap();
+        for _ in 0..4096 {
+            manager.put("b", Session::default());
+        }
+        manager.evict("b");
+    }
+
+    // a record of "b" is less than 32 bytes, and no more than 1024 redundant records are kept.
+    assert!(std::fs::metadata(&path).unwrap().len() < len + 32 * 1024);
+    let manager = SessionManager::new(0, Some(&path), 2).unwrap();
+    assert_eq!(manager.get("a"), Some(&session()));
+    // subscriptions are equal by the Topic Filter only.
+    assert_eq!(manager.get("a").unwrap().subscriptions.get("c/#").unwrap().options, session().subscriptions.get("c/#").unwrap().options);
+    assert_eq!(manager.get("b"), None);
+}
+
+#[test]
+fn test_enqueue() {
+    let path = session_file("enqueue");
+    let message = |payload: &'static str| QueuedMessage {
+        qos: Qos::AcknowledgedDeliver,
+        message: Arc::new(PUBLISH {
+            dup: false,
+            qos: Qos::AssuredDelivery,
+            retain: false,
+            topic: "a/b".to_owned(),
+            id: Some(1),
+            payload: Bytes::from(payload),
+            properties: Properties::default(),
+            origin: None,
+            expires_at: None,
+        }),
+    };
+    {
+        let mut manager = SessionManager::new(0, Some(&path), 2).unwrap();
+        manager.put("a", Session::default());
+        assert_eq!(manager.enqueue("a", message("1")), Ok(()));
+        assert_eq!(manager.enqueue("a", message("2")), Ok(()));
+        // the queue is full.
+        assert_eq!(manager.enqueue("a", message("3")), Err(1));
+        assert_eq!(manager.enqueue("a", message("4")), Err(2));
+        // messages are not queued without a Session.
+        assert_eq!(manager.enqueue("b", message("1")), Ok(()));
+        assert_eq!(manager.get("b"), None);
+
+        assert_eq!(manager.take_dropped("a"), 2);
+        assert_eq!(manager.take_dropped("a"), 0);
+    }
+
+    let manager = SessionManager::new(0, Some(&path), 2).unwrap();
+    assert_eq!(manager.get("a").unwrap().queue, vec![message("1"), message("2")]);
+}
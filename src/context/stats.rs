@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::message::Qos;
+use crate::message::codec::DecodeError;
+use crate::message::property::Properties;
+use crate::message::request::PUBLISH;
+use crate::server::{SyncSessionManager, SyncWorkerManager};
+use crate::util::Shutdown;
+
+/// Counters of the Server since it started, which are shared by every connection.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started: Instant,
+    /// packets received, indexed by the MQTT Control Packet type.
+    received: [AtomicU64; 16],
+    /// packets sent, indexed by the MQTT Control Packet type.
+    sent: [AtomicU64; 16],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// connections accepted, of which the TLS or WebSocket handshake has succeeded.
+    connections: AtomicU64,
+    parsing_errors: AtomicU64,
+    io_errors: AtomicU64,
+    /// messages dropped as the broadcast channel of a subscription is full.
+    lagged: AtomicU64,
+}
+
+/// names of MQTT Control Packet types, see MQTT 5.0 spec 2.1.2.
+pub(crate) const PACKET_TYPES: [&str; 16] = [
+    "RESERVED", "CONNECT", "CONNACK", "PUBLISH", "PUBACK", "PUBREC", "PUBREL", "PUBCOMP",
+    "SUBSCRIBE", "SUBACK", "UNSUBSCRIBE", "UNSUBACK", "PINGREQ", "PINGRESP", "DISCONNECT", "AUTH",
+];
+
+/// MQTT Control Packet type of PUBLISH, see MQTT 3.1.1 spec 2.2.1.
+const PUBLISH_TYPE: usize = 3;
+
+impl Stats {
+    /// counts the bytes of a frame received, whether it could be parsed or not.
+    pub(crate) fn receive_bytes(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// counts a packet parsed, of which `header` is the first byte.
+    pub(crate) fn receive(&self, header: u8) {
+        self.received[(header >> 4) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a packet sent, of which `header` is the first byte.
+    pub(crate) fn send(&self, header: u8, len: usize) {
+        self.sent[(header >> 4) as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connect(&self) { self.connections.fetch_add(1, Ordering::Relaxed); }
+
+    pub(crate) fn decode_error(&self, err: &DecodeError) {
+        match err {
+            DecodeError::Parsing(_) => self.parsing_errors.fetch_add(1, Ordering::Relaxed),
+            DecodeError::IO(_) => self.io_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub(crate) fn lag(&self, skipped: u64) { self.lagged.fetch_add(skipped, Ordering::Relaxed); }
+
+    pub(crate) fn uptime(&self) -> Duration { self.started.elapsed() }
+
+    pub(crate) fn received_of(&self, packet_type: usize) -> u64 { self.received[packet_type].load(Ordering::Relaxed) }
+    pub(crate) fn sent_of(&self, packet_type: usize) -> u64 { self.sent[packet_type].load(Ordering::Relaxed) }
+    pub(crate) fn connections(&self) -> u64 { self.connections.load(Ordering::Relaxed) }
+    pub(crate) fn parsing_errors(&self) -> u64 { self.parsing_errors.load(Ordering::Relaxed) }
+    pub(crate) fn io_errors(&self) -> u64 { self.io_errors.load(Ordering::Relaxed) }
+    pub(crate) fn lagged(&self) -> u64 { self.lagged.load(Ordering::Relaxed) }
+
+    pub(crate) fn received(&self) -> u64 { self.received.iter().map(|n| n.load(Ordering::Relaxed)).sum() }
+    pub(crate) fn sent(&self) -> u64 { self.sent.iter().map(|n| n.load(Ordering::Relaxed)).sum() }
+    pub(crate) fn publish_received(&self) -> u64 { self.received[PUBLISH_TYPE].load(Ordering::Relaxed) }
+    pub(crate) fn publish_sent(&self) -> u64 { self.sent[PUBLISH_TYPE].load(Ordering::Relaxed) }
+    pub(crate) fn bytes_received(&self) -> u64 { self.bytes_received.load(Ordering::Relaxed) }
+    pub(crate) fn bytes_sent(&self) -> u64 { self.bytes_sent.load(Ordering::Relaxed) }
+
+    pub(crate) fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            received: Default::default(),
+            sent: Default::default(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            parsing_errors: AtomicU64::new(0),
+            io_errors: AtomicU64::new(0),
+            lagged: AtomicU64::new(0),
+        }
+    }
+}
+
+/// publishes the statistics to the conventional `$SYS/broker/...` topics every `interval`, as
+/// retained messages so that new subscribers get them at once, until the Server shuts down.
+pub(crate) async fn publish_sys(interval: Duration,
+                                stats: Arc<Stats>,
+                                worker_manager: Arc<SyncWorkerManager>,
+                                session_manager: Arc<SyncSessionManager>,
+                                mut shutdown: Shutdown) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.poll() => return,
+        }
+
+        let (connected, total) = {
+            let sessions = session_manager.read().await;
+            (sessions.connected(), sessions.total())
+        };
+        let subscriptions = worker_manager.read().await.subscriptions();
+        let topics = [
+            ("version", format!("telesteller {}", env!("CARGO_PKG_VERSION"))),
+            ("uptime", format!("{} seconds", stats.uptime().as_secs())),
+            ("clients/connected", connected.to_string()),
+            ("clients/total", total.to_string()),
+            ("messages/received", stats.received().to_string()),
+            ("messages/sent", stats.sent().to_string()),
+            ("publish/messages/received", stats.publish_received().to_string()),
+            ("publish/messages/sent", stats.publish_sent().to_string()),
+            ("bytes/received", stats.bytes_received().to_string()),
+            ("bytes/sent", stats.bytes_sent().to_string()),
+            ("subscriptions/count", subscriptions.to_string()),
+        ];
+
+        let worker_manager = worker_manager.read().await;
+        for (topic, payload) in topics {
+            let topic = format!("$SYS/broker/{}", topic);
+            let message = PUBLISH {
+                dup: false,
+                qos: Qos::FireAndForget,
+                retain: true,
+                topic: topic.clone(),
+                id: None,
+                payload: Bytes::from(payload),
+                properties: Properties::default(),
+                origin: None,
+                expires_at: None,
+            };
+            // no subscriber is listening on the topic, which is fine as the message is retained.
+            if let Err(err) = worker_manager.dispatch(&topic, message).await {
+                debug!(send_error = ?err, topic = &topic[..], "failed to dispatch $SYS message.");
+            }
+        }
+    }
+}
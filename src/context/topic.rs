@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+const SEPARATOR: char = '/';
+const SINGLE_LEVEL: &str = "+";
+const MULTI_LEVEL: &str = "#";
+const SHARE_PREFIX: &str = "$share/";
+
+/// checks whether the Topic Filter is valid according to MQTT 3.1.1 spec 4.7.1, that is, wildcards
+/// should occupy an entire level, and the multi-level wildcard should only be the last level.
+///
+/// A Shared Subscription is valid if its Share Name is not empty and contains no wildcard, and
+/// the filter following it is valid, see MQTT 5 spec 4.8.2.
+pub(crate) fn is_valid_filter(filter: &str) -> bool {
+    match parse_shared(filter) {
+        Some((group, filter)) => !group.is_empty() && !group.contains(['+', '#']) && parse_shared(filter).is_none() && is_valid_plain_filter(filter),
+        None => !filter.starts_with(SHARE_PREFIX) && is_valid_plain_filter(filter),
+    }
+}
+
+/// splits a Shared Subscription `$share/{ShareName}/{filter}` into the Share Name and the Topic
+/// Filter, or returns None if it's an ordinary Topic Filter.
+pub(crate) fn parse_shared(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix(SHARE_PREFIX)?.split_once(SEPARATOR)
+}
+
+fn is_valid_plain_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+
+    let mut levels = filter.split(SEPARATOR).peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            MULTI_LEVEL if levels.peek().is_some() => return false,
+            SINGLE_LEVEL | MULTI_LEVEL => {}
+            _ if level.contains(['+', '#']) => return false,
+            _ => {}
+        }
+    }
+
+    true
+}
+
+/// checks whether the Topic Name is valid for PUBLISH, which must not contain wildcards.
+pub(crate) fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+/// checks whether the Topic Name matches the Topic Filter according to MQTT 3.1.1 spec 4.7.
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    // topics starting with '$' are not matched by a filter starting with a wildcard, see 4.7.2.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split(SEPARATOR);
+    let mut topic = topic.split(SEPARATOR);
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some(MULTI_LEVEL), _) => return true,
+            (Some(SINGLE_LEVEL), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// checks whether every Topic Name matched by the Topic Filter `sub` is also matched by `filter`,
+/// which tells if a subscription of `sub` is within `filter`.
+pub(crate) fn covers(filter: &str, sub: &str) -> bool {
+    if sub.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split(SEPARATOR);
+    let mut sub = sub.split(SEPARATOR);
+    loop {
+        match (filter.next(), sub.next()) {
+            (Some(MULTI_LEVEL), _) => return true,
+            (Some(SINGLE_LEVEL), Some(s)) if s != MULTI_LEVEL => {}
+            (Some(f), Some(s)) if f == s => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// checks whether some Topic Name is matched by both of the Topic Filters, which tells if a
+/// subscription of one of them could receive messages of the other.
+pub(crate) fn overlaps(a: &str, b: &str) -> bool {
+    let wildcard = |filter: &str| filter.starts_with('+') || filter.starts_with('#');
+    if (a.starts_with('$') && wildcard(b)) || (b.starts_with('$') && wildcard(a)) {
+        return false;
+    }
+
+    let mut a = a.split(SEPARATOR);
+    let mut b = b.split(SEPARATOR);
+    loop {
+        match (a.next(), b.next()) {
+            (Some(MULTI_LEVEL), _) | (_, Some(MULTI_LEVEL)) => return true,
+            (Some(SINGLE_LEVEL), Some(_)) | (Some(_), Some(SINGLE_LEVEL)) => {}
+            (Some(x), Some(y)) if x == y => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// A tree of Topic Filters split by levels, which finds every filter matching a Topic Name without
+/// scanning all of the filters.
+pub(crate) struct TopicTree<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    value: Option<T>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Node {
+            value: None,
+            children: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool { self.value.is_none() && self.children.is_empty() }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        match levels.split_first() {
+            None => self.value.take(),
+            Some((level, rest)) => {
+                let child = self.children.get_mut(*level)?;
+                let removed = child.remove(rest);
+                if child.is_empty() {
+                    self.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+
+    fn values<'a>(&'a self, out: &mut Vec<&'a T>) {
+        out.extend(self.value.as_ref());
+        for child in self.children.values() {
+            child.values(out);
+        }
+    }
+
+    fn matches<'a>(&'a self, levels: &[&str], root: bool, out: &mut Vec<&'a T>) {
+        let dollar = root && levels.first().is_some_and(|level| level.starts_with('$'));
+
+        // the multi-level wildcard also matches the parent level, so "a/#" matches "a".
+        if !dollar {
+            if let Some(value) = self.children.get(MULTI_LEVEL).and_then(|child| child.value.as_ref()) {
+                out.push(value);
+            }
+        }
+
+        match levels.split_first() {
+            None => out.extend(self.value.as_ref()),
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.matches(rest, false, out);
+                }
+                if !dollar {
+                    if let Some(child) = self.children.get(SINGLE_LEVEL) {
+                        child.matches(rest, false, out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub(crate) fn get(&self, filter: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in filter.split(SEPARATOR) {
+            node = node.children.get(level)?;
+        }
+        node.value.as_ref()
+    }
+
+    pub(crate) fn get_mut(&mut self, filter: &str) -> Option<&mut T> {
+        let mut node = &mut self.root;
+        for level in filter.split(SEPARATOR) {
+            node = node.children.get_mut(level)?;
+        }
+        node.value.as_mut()
+    }
+
+    /// returns the value of the filter, which is inserted by `f` if absent.
+    pub(crate) fn get_or_insert_with(&mut self, filter: &str, f: impl FnOnce() -> T) -> &mut T {
+        let mut node = &mut self.root;
+        for level in filter.split(SEPARATOR) {
+            node = node.children.entry(level.to_owned()).or_insert_with(Node::new);
+        }
+        node.value.get_or_insert_with(f)
+    }
+
+    pub(crate) fn insert(&mut self, filter: &str, value: T) -> Option<T> {
+        let mut node = &mut self.root;
+        for level in filter.split(SEPARATOR) {
+            node = node.children.entry(level.to_owned()).or_insert_with(Node::new);
+        }
+        node.value.replace(value)
+    }
+
+    pub(crate) fn remove(&mut self, filter: &str) -> Option<T> {
+        self.root.remove(&filter.split(SEPARATOR).collect::<Vec<_>>())
+    }
+
+    /// returns values of every filter matching the Topic Name.
+    pub(crate) fn matches(&self, topic: &str) -> Vec<&T> {
+        let mut out = Vec::new();
+        self.root.matches(&topic.split(SEPARATOR).collect::<Vec<_>>(), true, &mut out);
+        out
+    }
+
+    /// returns values of every filter.
+    pub(crate) fn values(&self) -> Vec<&T> {
+        let mut out = Vec::new();
+        self.root.values(&mut out);
+        out
+    }
+
+    pub(crate) fn new() -> Self {
+        TopicTree {
+            root: Node::new()
+        }
+    }
+}
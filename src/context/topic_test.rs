@@ -0,0 +1,144 @@
+#![allow(non_snake_case)]
+
+use super::topic::*;
+
+#[test]
+fn test_is_valid_filter() {
+    for filter in ["#", "+", "a/b", "a/+/c", "a/#", "+/+/#", "/", "a//b", "$SYS/#"].iter() {
+        assert!(is_valid_filter(filter), "{} should be valid", filter);
+    }
+    for filter in ["", "a/#/c", "a#", "a/b+", "a/+b/c", "##"].iter() {
+        assert!(!is_valid_filter(filter), "{} should be invalid", filter);
+    }
+}
+
+#[test]
+fn test_is_valid_filter_shared() {
+    for filter in ["$share/group/a/b", "$share/group/#", "$share/group/+/b", "$share/group//"].iter() {
+        assert!(is_valid_filter(filter), "{} should be valid", filter);
+    }
+    for filter in ["$share/group", "$share/group/", "$share//a", "$share/gr+oup/a", "$share/#/a", "$share/group/a/#/b",
+        "$share/group/$share/other/a"].iter() {
+        assert!(!is_valid_filter(filter), "{} should be invalid", filter);
+    }
+
+    assert_eq!(parse_shared("$share/group/a/+"), Some(("group", "a/+")));
+    assert_eq!(parse_shared("a/b"), None);
+    assert_eq!(parse_shared("$share/group"), None);
+}
+
+#[test]
+fn test_is_valid_topic() {
+    assert!(is_valid_topic("a/b/c"));
+    assert!(is_valid_topic("$SYS/broker/uptime"));
+    assert!(!is_valid_topic(""));
+    assert!(!is_valid_topic("a/+/c"));
+    assert!(!is_valid_topic("a/#"));
+}
+
+#[test]
+fn test_matches() {
+    let cases = [
+        ("sport/tennis/player1/#", "sport/tennis/player1", true),
+        ("sport/tennis/player1/#", "sport/tennis/player1/ranking", true),
+        ("sport/tennis/player1/#", "sport/tennis/player1/score/wimbledon", true),
+        ("sport/#", "sport", true),
+        ("sport/tennis/+", "sport/tennis/player1", true),
+        ("sport/tennis/+", "sport/tennis/player1/tournament", false),
+        ("sport/+", "sport", false),
+        ("sport/+", "sport/", true),
+        ("+/+", "/finance", true),
+        ("/+", "/finance", true),
+        ("+", "/finance", false),
+        ("#", "a/b/c", true),
+        ("#", "$SYS/broker/uptime", false),
+        ("+/monitor/Clients", "$SYS/monitor/Clients", false),
+        ("$SYS/#", "$SYS/broker/uptime", true),
+        ("$SYS/monitor/+", "$SYS/monitor/Clients", true),
+        ("a/b", "a/b", true),
+        ("a/b", "a/b/c", false),
+    ];
+
+    for (filter, topic, expected) in cases.iter() {
+        assert_eq!(matches(filter, topic), *expected, "matches({}, {})", filter, topic);
+    }
+}
+
+#[test]
+fn test_TopicTree_matches() {
+    let filters = ["sport/tennis/player1/#", "sport/#", "sport/tennis/+", "sport/+", "+/+", "/+", "+",
+        "#", "$SYS/#", "+/monitor/Clients", "$SYS/monitor/+", "a/b", "sport/tennis/player1"];
+    let topics = ["sport/tennis/player1", "sport/tennis/player1/ranking", "sport", "sport/", "/finance",
+        "a/b", "a/b/c", "$SYS/broker/uptime", "$SYS/monitor/Clients", "x"];
+
+    let mut tree = TopicTree::new();
+    for filter in filters.iter() {
+        tree.insert(filter, *filter);
+    }
+
+    // TopicTree should agree with matches() on every filter.
+    for topic in topics.iter() {
+        let mut expected = filters.iter().filter(|filter| matches(filter, topic)).cloned().collect::<Vec<_>>();
+        let mut actual = tree.matches(topic).into_iter().cloned().collect::<Vec<_>>();
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected, "TopicTree::matches({})", topic);
+    }
+}
+
+#[test]
+fn test_TopicTree_remove() {
+    let mut tree = TopicTree::new();
+    tree.insert("a/b", 1);
+    tree.insert("a/b/c", 2);
+    tree.insert("a/#", 3);
+
+    assert_eq!(tree.remove("a/b"), Some(1));
+    assert_eq!(tree.remove("a/b"), None);
+    assert_eq!(tree.get("a/b/c"), Some(&2));
+    assert_eq!(tree.matches("a/b"), vec![&3]);
+
+    let mut values = tree.values();
+    values.sort();
+    assert_eq!(values, vec![&2, &3]);
+
+    assert_eq!(tree.remove("a/b/c"), Some(2));
+    assert_eq!(tree.remove("a/#"), Some(3));
+    assert!(tree.matches("a/b/c").is_empty());
+    assert!(tree.get("a").is_none());
+}
+
+#[test]
+fn test_covers() {
+    assert!(covers("a/#", "a/#"));
+    assert!(covers("a/#", "a/+/c"));
+    assert!(covers("a/#", "a"));
+    assert!(covers("a/+", "a/+"));
+    assert!(covers("a/+", "a/b"));
+    assert!(covers("#", "a/b/#"));
+    assert!(covers("a/b", "a/b"));
+
+    assert!(!covers("a/+", "a/#"));
+    assert!(!covers("a/b", "a/+"));
+    assert!(!covers("a/+", "a/b/c"));
+    assert!(!covers("a/b/#", "a/#"));
+    assert!(!covers("#", "$SYS/#"));
+    assert!(covers("$SYS/#", "$SYS/broker/+"));
+}
+
+#[test]
+fn test_overlaps() {
+    assert!(overlaps("a/#", "a/secret"));
+    assert!(overlaps("a/secret", "a/#"));
+    assert!(overlaps("a/+", "a/secret"));
+    assert!(overlaps("a/+/c", "a/b/+"));
+    assert!(overlaps("a/#", "a"));
+    assert!(overlaps("#", "a/b"));
+    assert!(overlaps("a/b", "a/b"));
+
+    assert!(!overlaps("a/+", "a/b/c"));
+    assert!(!overlaps("a/b", "a/c"));
+    assert!(!overlaps("a/b/#", "a/c/#"));
+    assert!(!overlaps("+/#", "$SYS/broker"));
+    assert!(overlaps("$SYS/#", "$SYS/broker"));
+}
@@ -0,0 +1,150 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::context::{Stats, topic};
+use crate::handler::pub_sub::message_stream;
+use crate::message::Qos;
+use crate::message::property::Properties;
+use crate::message::request::PUBLISH;
+use crate::server::SyncWorkerManager;
+
+/// A handle of a running Server, through which the program embedding it publishes and subscribes
+/// in process, without connecting as an MQTT client. Neither authentication nor ACL applies.
+#[derive(Clone)]
+pub struct Handle {
+    pub(crate) worker_manager: Arc<SyncWorkerManager>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) shutdown_tx: broadcast::Sender<()>,
+}
+
+/// A message received from a [`Subscription`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: Qos,
+    /// only set for retained messages sent once subscribed.
+    pub retain: bool,
+}
+
+impl Handle {
+    /// dispatches the message to subscribers like a PUBLISH of clients, which is fine even if
+    /// there's no subscriber.
+    pub async fn publish(&self, topic: &str, payload: impl Into<Bytes>, qos: Qos, retain: bool) -> Result<(), Error> {
+        if !topic::is_valid_topic(topic) {
+            return Err(Error::InvalidTopic(topic.to_owned()));
+        }
+
+        let message = PUBLISH {
+            dup: false,
+            qos,
+            retain,
+            topic: topic.to_owned(),
+            id: None,
+            payload: payload.into(),
+            properties: Properties::default(),
+            origin: None,
+            expires_at: None,
+        };
+        if let Err(err) = self.worker_manager.read().await.dispatch(topic, message).await {
+            debug!(send_error = ?err, topic, "failed to dispatch.");
+        }
+
+        Ok(())
+    }
+
+    /// subscribes to the Topic Filter, starting with its retained messages. The subscription is
+    /// removed once the returned Stream is dropped.
+    pub async fn subscribe(&self, filter: &str) -> Result<Subscription, Error> {
+        if !topic::is_valid_filter(filter) {
+            return Err(Error::InvalidFilter(filter.to_owned()));
+        }
+
+        let mut worker_manager = self.worker_manager.write().await;
+        let subscriber = worker_manager.subscribe(filter).await;
+        let retained = worker_manager.retained(filter).await.into_iter()
+            .map(|message| Message::new(&message, true))
+            .collect::<Vec<_>>();
+        let lagged = Arc::new(AtomicU64::new(0));
+        let messages = message_stream(subscriber, filter.to_owned(), self.stats.clone(), Some(lagged.clone()))
+            .map(|message| Message::new(&message, false));
+
+        Ok(Subscription {
+            filter: filter.to_owned(),
+            stream: Some(Box::pin(stream::iter(retained).chain(messages))),
+            worker_manager: self.worker_manager.clone(),
+            lagged,
+        })
+    }
+
+    /// signals the Server to shut down, see [`crate::Server::serve`].
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+}
+
+impl Message {
+    fn new(message: &PUBLISH, retain: bool) -> Self {
+        Message {
+            topic: message.topic.clone(),
+            payload: message.payload.clone(),
+            qos: message.qos,
+            retain,
+        }
+    }
+}
+
+/// The Stream of messages of a Topic Filter subscribed by [`Handle::subscribe`].
+pub struct Subscription {
+    filter: String,
+    stream: Option<Pin<Box<dyn Stream<Item=Message> + Send>>>,
+    worker_manager: Arc<SyncWorkerManager>,
+    lagged: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// number of messages skipped so far, because the Stream is not polled fast enough to keep up
+    /// with the messages published.
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.stream.as_mut() {
+            Some(stream) => stream.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // the subscriber is dropped beforehand, so that the Publisher without any subscriber left
+        // could be removed.
+        self.stream.take();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let (filter, worker_manager) = (std::mem::take(&mut self.filter), self.worker_manager.clone());
+            runtime.spawn(async move { worker_manager.write().await.unsubscribe(&filter) });
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid Topic Name `{0}`")]
+    InvalidTopic(String),
+    #[error("invalid Topic Filter `{0}`")]
+    InvalidFilter(String),
+}
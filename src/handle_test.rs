@@ -0,0 +1,136 @@
+#![allow(non_snake_case)]
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::handle::Error;
+use crate::listener::Listener;
+use crate::message::property::Properties;
+use crate::message::request::PUBLISH;
+use crate::server_test::{connect, packet, publish, read, subscribe, text};
+use crate::{Handle, Message, Qos, Server};
+
+/// starts a Server built without any listener.
+fn serve() -> Handle {
+    let mut server = Server::builder().sys_interval(Duration::from_secs(0)).build().unwrap();
+    let handle = server.handle();
+    tokio::spawn(async move { server.serve().await });
+    handle
+}
+
+fn message(topic: &str, payload: &'static str, qos: Qos, retain: bool) -> Message {
+    Message { topic: topic.to_owned(), payload: Bytes::from(payload), qos, retain }
+}
+
+#[tokio::test]
+async fn test_publish_subscribe() {
+    let handle = serve();
+    handle.publish("a/retained", "retained", Qos::FireAndForget, true).await.unwrap();
+
+    let mut subscription = handle.subscribe("a/+").await.unwrap();
+    handle.publish("a/b", "message", Qos::AcknowledgedDeliver, false).await.unwrap();
+    handle.publish("b", "not subscribed", Qos::FireAndForget, false).await.unwrap();
+    handle.publish("a/c", "retained again", Qos::FireAndForget, true).await.unwrap();
+
+    assert_eq!(subscription.next().await.unwrap(), message("a/retained", "retained", Qos::FireAndForget, true));
+    assert_eq!(subscription.next().await.unwrap(), message("a/b", "message", Qos::AcknowledgedDeliver, false));
+    assert_eq!(subscription.next().await.unwrap(), message("a/c", "retained again", Qos::FireAndForget, false));
+
+    assert!(matches!(handle.publish("a/+", "", Qos::FireAndForget, false).await, Err(Error::InvalidTopic(_))));
+    assert!(matches!(handle.subscribe("a/#/b").await, Err(Error::InvalidFilter(_))));
+}
+
+#[tokio::test]
+async fn test_subscription_dropped() {
+    let handle = serve();
+    let subscription = handle.subscribe("a/b").await.unwrap();
+    drop(subscription);
+
+    // the Publisher without any subscriber is removed, so that nothing is sent to.
+    tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            let worker_manager = handle.worker_manager.read().await;
+            if worker_manager.subscriptions() == 0 && worker_manager.dispatch("a/b", PUBLISH {
+                dup: false,
+                qos: Qos::FireAndForget,
+                retain: false,
+                topic: "a/b".to_owned(),
+                id: None,
+                payload: Bytes::new(),
+                properties: Properties::default(),
+                origin: None,
+                expires_at: None,
+            }).await.is_ok() {
+                break;
+            }
+            drop(worker_manager);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_subscription_lagged() {
+    let handle = serve();
+    let mut subscription = handle.subscribe("a/b").await.unwrap();
+    // the channel of a topic holds 1024 messages, so the oldest 6 are skipped.
+    for i in 0..1030 {
+        handle.publish("a/b", i.to_string(), Qos::FireAndForget, false).await.unwrap();
+    }
+
+    assert_eq!(subscription.next().await.unwrap(), message("a/b", "6", Qos::FireAndForget, false));
+    assert_eq!(subscription.lagged(), 6);
+    assert_eq!(handle.stats.lagged(), 6);
+}
+
+#[tokio::test]
+async fn test_clients() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::builder().listener(Listener::tcp(listener)).max_connections(8).build().unwrap();
+    let handle = server.handle();
+    let serving = tokio::spawn(async move { server.serve().await });
+
+    let (mut client, _) = connect(addr, "c", true, None).await;
+    subscribe(&mut client, "to/client", 0).await;
+    handle.publish("to/client", "hello", Qos::FireAndForget, false).await.unwrap();
+    assert_eq!(read(&mut client).await.unwrap(), packet(0x30, [text("to/client"), b"hello".to_vec()].concat()));
+
+    let mut subscription = handle.subscribe("to/handle").await.unwrap();
+    publish(&mut client, "to/handle", "hi").await;
+    assert_eq!(subscription.next().await.unwrap(), message("to/handle", "hi", Qos::FireAndForget, false));
+
+    handle.shutdown();
+    serving.await.unwrap().unwrap();
+    assert_eq!(read(&mut client).await, None);
+}
+
+#[tokio::test]
+async fn test_shutdown_without_listener() {
+    let mut server = Server::builder().build().unwrap();
+    let handle = server.handle();
+    let serving = tokio::spawn(async move { server.serve().await });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!serving.is_finished());
+    handle.shutdown();
+    serving.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_builder_subsecond_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::builder().listener(Listener::tcp(listener)).connect_timeout(Duration::from_millis(500)).build().unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    // the timeout is not truncated to 0 seconds, which would close the connection at once.
+    let start = tokio::time::Instant::now();
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(400) && elapsed < Duration::from_millis(1500), "closed after {:?}", elapsed);
+}
@@ -1,65 +1,212 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::SinkExt;
+use tokio::sync::oneshot;
 use tracing::{debug, warn};
 
-use crate::handler::{Connection, Session, State};
-use crate::message::{request::CONNECT, response};
+use crate::handler::{Connection, disconnect, InflightMessage, MessageStream, offline, Session, State};
+use crate::handler::pub_sub::expires_at;
+use crate::message::{ReasonCode, request::CONNECT, response};
 use crate::message::codec::Transport;
-use crate::message::request::{DISCONNECT, SUBSCRIBE};
-use crate::require_state;
-use crate::server::{SyncSessionManager, SyncWorkerManager};
+use crate::message::property::{Properties, Property};
+use crate::message::request::{AUTH, DISCONNECT, PUBLISH, SUBSCRIBE, Will};
+use crate::{require_state, send};
+use crate::context::auth::{Access, AuthManager, Rejection};
+use crate::server::{SyncAuthManager, SyncSessionManager, SyncWorkerManager};
 
 impl CONNECT {
-    #[tracing::instrument(name = "CONNECT::apply", level = "debug", skip(transport, worker_manager, session_manager))]
+    #[tracing::instrument(name = "CONNECT::apply", level = "debug", skip(transport, worker_manager, session_manager, auth_manager))]
     pub(crate) async fn apply(
-        self,
+        mut self,
         conn: &mut Connection,
         transport: &mut Transport,
         worker_manager: &mut Arc<SyncWorkerManager>,
         session_manager: &mut Arc<SyncSessionManager>,
+        auth_manager: &Arc<SyncAuthManager>,
     ) -> Result<(), ()> {
         require_state!(CONNECT requires State::Established, conn);
         debug!("CONNECT received.");
 
-        if self.protocol_version != 4 {
-            transport.send(Box::new(response::CONNACK {
+        if !matches!((self.protocol_name, self.protocol_version), ("MQIsdp", 3) | ("MQTT", 4) | ("MQTT", 5)) {
+            send!(response::CONNACK {
                 session_present: false,
                 return_code: response::CONNACKReturnCode::UnacceptableProtocol,
-            })).await;
+                properties: Properties::default(),
+            } => transport);
+            return Err(());
+        }
+
+        // MQTT 3.1 requires a Client Identifier of 1 to 23 characters, see MQTT 3.1 spec 3.1.
+        if self.protocol_version == 3 && !(1..=23).contains(&self.client_id.chars().count()) {
+            debug!(addr = ?&conn.addr, client_id = &self.client_id[..], "Client Identifier rejected by MQTT 3.1.");
+            send!(response::CONNACK {
+                session_present: false,
+                return_code: response::CONNACKReturnCode::IdentifierRejected,
+                properties: Properties::default(),
+            } => transport);
             return Err(());
         }
 
-        let mut session = session_manager.write().await;
-        if self.clean_session {
-            session.evict(&self.client_id);
+        // enhanced authentication of MQTT 5 is not supported, see MQTT 5.0 spec 4.12.
+        if self.properties.iter().any(|p| matches!(p, Property::AuthenticationMethod(_))) {
+            debug!(addr = ?&conn.addr, "Authentication Method is not supported, the connection will be closed.");
+            send!(response::CONNACK {
+                session_present: false,
+                return_code: response::CONNACKReturnCode::NotAuthorized,
+                properties: Properties::default(),
+            } => transport);
+            return Err(());
+        }
+
+        let authentication = AuthManager::authenticate(auth_manager, &self.client_id, self.username.as_deref(), self.password.as_deref()).await;
+        if let Err(rejection) = authentication {
+            debug!(addr = ?&conn.addr, username = ?&self.username, rejection = ?rejection, "authentication failed.");
+            send!(response::CONNACK {
+                session_present: false,
+                return_code: match rejection {
+                    Rejection::BadUsernameOrPassword => response::CONNACKReturnCode::BadUsernameOrPassword,
+                    Rejection::NotAuthorized => response::CONNACKReturnCode::NotAuthorized,
+                },
+                properties: Properties::default(),
+            } => transport);
+            return Err(());
+        }
+
+        // the Will Message is discarded if the client could not publish it by itself.
+        if let Some(will) = &self.will {
+            if !auth_manager.read().await.authorize(&self.client_id, self.username.as_deref(), &will.topic, Access::Write) {
+                debug!(addr = ?&conn.addr, topic = &will.topic[..], "Will Message denied by ACL, which is discarded.");
+                self.will = None;
+            }
+        }
+
+        // the connection of the same client is closed before its Session is taken over, see MQTT
+        // 3.1.1 spec 3.1.4.
+        let (registration, replaced) = session_manager.write().await.register(&self.client_id);
+        conn.registration = Some(registration);
+        if let Some(replaced) = replaced {
+            let (takeover, closed) = oneshot::channel();
+            if replaced.send(takeover).is_ok() {
+                debug!(addr = ?&conn.addr, client_id = &self.client_id[..], "waiting for the existing connection to be closed.");
+                let _ = closed.await;
+            }
         }
 
-        let session = session.get(&self.client_id);
-        let session_present = session.is_some();
-        let session = session.map(|s| s.clone())
-            .unwrap_or(Session::default());
+        let (session_present, mut session) = {
+            // messages received while the client is offline are all queued before it's resumed.
+            let parked = session_manager.write().await.unpark(&self.client_id);
+            if let Some(parked) = parked {
+                conn.streams = offline::stop(parked).await;
+            }
+
+            let mut sessions = session_manager.write().await;
+            let dropped = sessions.take_dropped(&self.client_id);
+            if dropped > 0 {
+                warn!(addr = ?&conn.addr, client_id = &self.client_id[..], dropped,
+                      "messages were dropped while the client was offline, as its queue was full.");
+            }
+            if self.clean_session {
+                sessions.evict(&self.client_id);
+                conn.streams = MessageStream::default();
+            }
+
+            let session = sessions.get(&self.client_id);
+            (session.is_some(), session.cloned().unwrap_or_default())
+        };
+        session.expiry_interval = expiry_interval(self.session_expiry_interval());
         let subscriptions = session.subscriptions.clone();
 
         debug!(addr = ?&conn.addr, session_present, session = ?&session, "Session retrieved or created");
+        // Session Present flag does not exist in MQTT 3.1, of which the byte is reserved.
+        let resumed = session_present;
+        let session_present = session_present && self.protocol_version != 3;
         conn.state = State::Connected(self, session);
 
-        // TODO: ClientID / Username / Password verification
-        transport.send(Box::new(response::CONNACK {
+        send!(response::CONNACK {
             session_present,
             return_code: response::CONNACKReturnCode::Accepted,
-        })).await;
+            // availability of MQTT 5 features, which is simply ignored by MQTT 3.1.1.
+            properties: Properties(vec![
+                Property::SubscriptionIdentifierAvailable(0),
+                Property::SharedSubscriptionAvailable(1),
+            ]),
+        } => transport);
 
+        if resumed {
+            CONNECT::retransmit(conn, transport).await?;
+            CONNECT::deliver_queued(conn, transport, session_manager).await?;
+        }
 
         // if there's subscriptions in the previous session, restore them by
         // entering subscribed mode immediately.
-        if session_present && !subscriptions.is_empty() {
-            SUBSCRIBE::subscribe(&subscriptions.iter().map(|e| (e.topic.clone(), e.qos)).collect(),
-                                 None,
-                                 conn,
-                                 transport,
-                                 worker_manager,
-                                 &mut session_manager.clone()).await;
+        if resumed && !subscriptions.is_empty() {
+            return SUBSCRIBE::subscribe(&subscriptions.iter().map(|e| (e.topic.clone(), e.qos, e.options)).collect::<Vec<_>>(),
+                                        None,
+                                        conn,
+                                        transport,
+                                        worker_manager,
+                                        &mut session_manager.clone(),
+                                        auth_manager).await;
+        }
+
+        Ok(())
+    }
+
+    /// whether the Session should be kept after the connection is closed, which is decided by Clean
+    /// Session in MQTT 3.1.1, or by a non-zero Session Expiry Interval in MQTT 5.
+    pub(crate) fn persistent(&self) -> bool { self.session_expiry_interval() > 0 }
+
+    /// Session Expiry Interval in seconds, see MQTT 5.0 spec 3.1.2.11.2, which is either 0 or
+    /// 0xFFFFFFFF for never by Clean Session in MQTT 3.1.1.
+    fn session_expiry_interval(&self) -> u32 {
+        match self.protocol_version {
+            5 => session_expiry_interval(&self.properties).unwrap_or(0),
+            _ if self.clean_session => 0,
+            _ => u32::MAX,
+        }
+    }
+
+    /// re-sends every unacknowledged message of a resumed Session with DUP flag set, or the PUBREL
+    /// of it if it has been released, according to MQTT 3.1.1 spec 4.4.
+    async fn retransmit(conn: &Connection, transport: &mut Transport) -> Result<(), ()> {
+        if let State::Connected(_, Session { inflight, .. }) = &conn.state {
+            for (id, message) in inflight.iter() {
+                if message.released {
+                    send!(response::PUBREL { id: *id, reason_code: ReasonCode::SUCCESS } => transport);
+                } else {
+                    send!(message.frame(*id, true) => transport);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// sends messages queued while the client is offline, which are tracked as in-flight ones
+    /// since then.
+    async fn deliver_queued(conn: &mut Connection, transport: &mut Transport, session_manager: &Arc<SyncSessionManager>) -> Result<(), ()> {
+        let (client_id, session) = match &mut conn.state {
+            State::Connected(CONNECT { client_id, .. }, session) if !session.queue.is_empty() => (client_id, session),
+            _ => return Ok(()),
+        };
+        debug!(queued = session.queue.len(), "delivering messages queued while the client is offline.");
+
+        let mut frames = Vec::with_capacity(session.queue.len());
+        while let Some(queued) = session.queue.pop_front() {
+            if queued.message.expired() {
+                continue;
+            }
+            let id = session.next_id();
+            let inflight = InflightMessage { qos: queued.qos, retain: false, message: queued.message, released: false };
+            frames.push(inflight.frame(id, false));
+            session.inflight.insert(id, inflight);
+        }
+        // the queue in the saved Session is replaced, so that it won't be delivered twice.
+        session_manager.write().await.put(client_id, session.clone());
+
+        for frame in frames {
+            send!(frame => transport);
         }
 
         Ok(())
@@ -67,27 +214,104 @@ impl CONNECT {
 }
 
 impl DISCONNECT {
-    #[tracing::instrument(name = "DISCONNECT::apply", level = "debug", skip(transport, session_manager))]
+    #[tracing::instrument(name = "DISCONNECT::apply", level = "debug", skip(transport, worker_manager, session_manager))]
     pub(crate) async fn apply(
         self,
         conn: &mut Connection,
         transport: &mut Transport,
+        worker_manager: &Arc<SyncWorkerManager>,
         session_manager: &mut Arc<SyncSessionManager>,
     ) -> Result<(), ()> {
         require_state!(CONNECT requires State::Connected(..), conn);
         debug!("DISCONNECT received.");
 
-        let (client_id, clean_session, session) = match &conn.state {
-            State::Connected(CONNECT { client_id, clean_session, .. }, session) => (client_id, clean_session, session),
+        // a MQTT 5 client may change the Session Expiry Interval as it disconnects, unless it was
+        // 0, see MQTT 5.0 spec 3.14.2.2.2.
+        let interval = session_expiry_interval(&self.properties);
+        if let (Some(interval), State::Connected(connect, _)) = (interval, &conn.state) {
+            if interval > 0 && !connect.persistent() {
+                warn!(addr = ?conn.addr, "Session Expiry Interval set by DISCONNECT while it was 0, the connection will be closed.");
+                disconnect(conn, transport, ReasonCode::PROTOCOL_ERROR).await;
+                return Err(());
+            }
+        }
+
+        let (connect, mut session) = match std::mem::replace(&mut conn.state, State::Disconnected) {
+            State::Connected(connect, session) => (connect, session),
             _ => return Err(()),
         };
-        if !clean_session {
-            session_manager.write().await.put(&client_id, session.clone());
+        if let Some(interval) = interval {
+            session.expiry_interval = expiry_interval(interval);
+        }
+        if interval.map_or(connect.persistent(), |interval| interval > 0) {
+            let streams = std::mem::take(&mut conn.streams);
+            offline::park(&connect.client_id, Some(session), streams, worker_manager, session_manager, &conn.stats).await;
+        } else {
+            // the Session ends with the connection, including the one resumed without Clean Start.
+            session_manager.write().await.evict(&connect.client_id);
         }
+        let synced = session_manager.read().await.synced();
+        synced.await;
 
-        conn.state = State::Disconnected;
-        transport.close().await;
+        // a MQTT 5 client may ask for the Will Message to be published anyway.
+        if let (ReasonCode::DISCONNECT_WITH_WILL_MESSAGE, Some(will)) = (self.reason_code, connect.will) {
+            will.publish(worker_manager).await;
+        }
+
+        let _ = transport.close().await;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Session Expiry Interval of CONNECT or DISCONNECT of MQTT 5, if any.
+fn session_expiry_interval(properties: &Properties) -> Option<u32> {
+    properties.iter().find_map(|p| match p {
+        Property::SessionExpiryInterval(interval) => Some(*interval),
+        _ => None,
+    })
+}
+
+/// how long the Session is kept once the client goes offline, which is forever for 0xFFFFFFFF.
+fn expiry_interval(session_expiry_interval: u32) -> Option<Duration> {
+    (session_expiry_interval != u32::MAX).then(|| Duration::from_secs(session_expiry_interval as u64))
+}
+
+impl Will {
+    /// publishes the Will Message, which happens when the connection is closed ungracefully.
+    #[tracing::instrument(name = "Will::publish", level = "debug", skip(worker_manager))]
+    pub(crate) async fn publish(self, worker_manager: &Arc<SyncWorkerManager>) {
+        let topic = self.topic.clone();
+        // Will Delay Interval is the only Will Property that is not a property of PUBLISH.
+        let properties = Properties(self.properties.0.into_iter()
+            .filter(|p| !matches!(p, Property::WillDelayInterval(_)))
+            .collect());
+        let message = PUBLISH {
+            dup: false,
+            qos: self.qos,
+            retain: self.retain,
+            topic: self.topic,
+            id: None,
+            payload: self.payload,
+            expires_at: expires_at(&properties),
+            properties,
+            origin: None,
+        };
+
+        match worker_manager.read().await.dispatch(&topic, message).await {
+            Err(err) => debug!(send_error = ?err, topic = &topic[..], "failed to dispatch Will Message."),
+            Ok(_) => debug!("Will Message dispatch successfully."),
+        }
+    }
+}
+
+impl AUTH {
+    /// enhanced authentication is not supported, so AUTH is never expected, see MQTT 5.0 spec 4.12.
+    #[tracing::instrument(name = "AUTH::apply", level = "debug", skip(transport))]
+    pub(crate) async fn apply(self, conn: &Connection, transport: &mut Transport) -> Result<(), ()> {
+        warn!(addr = ?conn.addr, "AUTH received while no Authentication Method is in use, the connection will be closed.");
+        disconnect(conn, transport, ReasonCode::PROTOCOL_ERROR).await;
+
+        Err(())
+    }
+}
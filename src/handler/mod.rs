@@ -1,34 +1,48 @@
-use std::collections::HashSet;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::{self, Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::TcpStream;
 use tokio::sync::Semaphore;
-use tokio_stream::StreamExt;
-use tokio_util::codec::Framed;
-use tracing::warn;
+use tokio::time::Instant;
+use futures::SinkExt;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use tracing::{debug, info, warn};
 
-use crate::message::codec::{DecodeError, MQTT311, Transport};
-use crate::message::Qos;
-use crate::message::request::{CONNECT, Request};
-use crate::server::{SyncSessionManager, SyncWorkerManager};
+use crate::context::auth::Access;
+use crate::context::session::{Registration, Takeover};
+use crate::context::Stats;
+use crate::message::codec::{DecodeError, Transport};
+use crate::message::{Qos, ReasonCode, response, SubscriptionOptions};
+use crate::message::request::{CONNECT, PUBLISH, Request};
+use crate::server::{SyncAuthManager, SyncSessionManager, SyncWorkerManager};
+use crate::util::Shutdown;
 
 mod conn;
-mod pub_sub;
+pub(crate) mod offline;
+pub(crate) mod pub_sub;
 mod ping;
+mod qos;
 
-type Subscription = (String, Qos);
+type Subscription = (String, Qos, SubscriptionOptions);
 
-#[derive(Clone, Eq, Hash)]
+/// Streams of messages of the subscriptions, keyed by the Topic Filter.
+pub(crate) type MessageStream = StreamMap<String, Pin<Box<dyn Stream<Item=Arc<PUBLISH>> + Send + Sync>>>;
+
+#[derive(Clone, Eq)]
 pub(crate) struct DesignatedSubscription {
-    topic: String,
-    qos: Qos,
+    pub(crate) topic: String,
+    pub(crate) qos: Qos,
+    pub(crate) options: SubscriptionOptions,
 }
 
 impl Debug for DesignatedSubscription {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "({}, {:?})", &self.topic, &self.qos)
+        write!(f, "({}, {:?}, {:?})", &self.topic, &self.qos, &self.options)
     }
 }
 
@@ -36,29 +50,87 @@ impl PartialEq for DesignatedSubscription {
     fn eq(&self, other: &Self) -> bool { self.topic == other.topic }
 }
 
+impl Hash for DesignatedSubscription {
+    fn hash<H: Hasher>(&self, state: &mut H) { self.topic.hash(state) }
+}
+
+impl Borrow<str> for DesignatedSubscription {
+    fn borrow(&self) -> &str { &self.topic }
+}
+
 impl From<Subscription> for DesignatedSubscription {
-    fn from(subscription: (String, Qos)) -> Self {
+    fn from(subscription: Subscription) -> Self {
         DesignatedSubscription {
             topic: subscription.0,
             qos: subscription.1,
+            options: subscription.2,
+        }
+    }
+}
+
+/// An outbound PUBLISH with Qos 1+ that has been sent to the client but not yet acknowledged.
+#[derive(PartialEq, Clone, Debug)]
+pub(crate) struct InflightMessage {
+    pub(crate) qos: Qos,
+    pub(crate) retain: bool,
+    pub(crate) message: Arc<PUBLISH>,
+    /// whether PUBREC has been received and PUBREL sent for this Qos 2 message, which
+    /// leaves it awaiting only for PUBCOMP.
+    pub(crate) released: bool,
+}
+
+impl InflightMessage {
+    fn frame(&self, id: u16, dup: bool) -> response::PUBLISH {
+        response::PUBLISH {
+            dup,
+            qos: self.qos,
+            retain: self.retain,
+            topic: self.message.topic.clone(),
+            id: Some(id),
+            payload: self.message.payload.clone(),
+            properties: self.message.forwarded_properties(),
         }
     }
 }
 
+/// A Qos 1+ message received while the client is offline, with the Qos it should be sent with.
 #[derive(PartialEq, Clone, Debug)]
+pub(crate) struct QueuedMessage {
+    pub(crate) qos: Qos,
+    pub(crate) message: Arc<PUBLISH>,
+}
+
+#[derive(PartialEq, Clone, Debug, Default)]
 pub(crate) struct Session {
-    pub(crate) subscriptions: HashSet<DesignatedSubscription>
+    pub(crate) subscriptions: HashSet<DesignatedSubscription>,
+    /// keyed by the Packet Identifier the message was sent with.
+    pub(crate) inflight: BTreeMap<u16, InflightMessage>,
+    /// Packet Identifiers of inbound Qos 2 messages which have been dispatched but not yet
+    /// released by PUBREL, so that a redelivered PUBLISH will not be dispatched twice.
+    pub(crate) received: HashSet<u16>,
+    pub(crate) last_id: u16,
+    /// messages queued while the client is offline, which are sent after CONNACK of the next
+    /// connection, see MQTT 3.1.1 spec 3.1.2.4.
+    pub(crate) queue: VecDeque<QueuedMessage>,
+    /// how long the Session is kept once the client goes offline, which is forever if None, see
+    /// MQTT 5.0 spec 3.1.2.11.2.
+    pub(crate) expiry_interval: Option<Duration>,
 }
 
-impl Default for Session {
-    fn default() -> Self {
-        Session {
-            subscriptions: HashSet::default()
+impl Session {
+    /// allocates a Packet Identifier which is non-zero and not used by any in-flight message.
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            if self.last_id != 0 && !self.inflight.contains_key(&self.last_id) {
+                return self.last_id;
+            }
         }
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum State {
     /// TCP connection just established, and no handshake packages received.
     Established,
@@ -70,10 +142,143 @@ enum State {
     Cleaning,
 }
 
-#[derive(Debug)]
 pub(crate) struct Connection {
     state: State,
     addr: SocketAddr,
+    /// when the last packet was received from the client, or when the connection is established.
+    last_active: Instant,
+    /// how long CONNECT is waited for once the connection is established.
+    connect_timeout: Duration,
+    /// the entry in the registry of live connections, which is made by CONNECT.
+    registration: Option<Registration>,
+    /// held until the connection is closed, once a new connection takes over the Session.
+    taken_over: Option<Takeover>,
+    /// whether the connection is closed by the Server for shutting down, which is not the fault of
+    /// the client, so the Will Message is not published.
+    shutting_down: bool,
+    /// streams of the subscriptions, which are handed over to the offline task once the client
+    /// goes offline, and back to the next connection, so that no message is lost in between.
+    streams: MessageStream,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+}
+
+impl Debug for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("state", &self.state)
+            .field("addr", &self.addr)
+            .field("last_active", &self.last_active)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("registration", &self.registration)
+            .field("taken_over", &self.taken_over)
+            .field("shutting_down", &self.shutting_down)
+            .field("streams", &self.streams.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Connection {
+    /// the deadline of the next packet from the client, which is one and a half times the Keep Alive
+    /// since the last one, according to MQTT 3.1.1 spec 3.1.2.10. Keep Alive of 0 turns it off.
+    /// CONNECT is expected within the connect timeout, see MQTT 3.1.1 spec 3.1.4.
+    fn deadline(&self) -> Option<Instant> {
+        match &self.state {
+            State::Established => Some(self.last_active + self.connect_timeout),
+            State::Connected(CONNECT { keep_alive, .. }, _) if *keep_alive > 0 =>
+                Some(self.last_active + Duration::from_millis(*keep_alive as u64 * 1500)),
+            _ => None,
+        }
+    }
+
+    /// checks whether the connected client can access the topic according to the ACL.
+    async fn authorize(&self, auth_manager: &Arc<SyncAuthManager>, topic: &str, access: Access) -> bool {
+        match &self.state {
+            State::Connected(CONNECT { client_id, username, .. }, _) =>
+                auth_manager.read().await.authorize(client_id, username.as_deref(), topic, access),
+            _ => false,
+        }
+    }
+
+    /// whether MQTT 5 is negotiated by CONNECT.
+    fn is_v5(&self) -> bool {
+        matches!(&self.state, State::Connected(CONNECT { protocol_version: 5, .. }, _))
+    }
+}
+
+/// tells a MQTT 5 client why the connection is about to be closed by the Server, which is not
+/// possible in MQTT 3.1.1.
+async fn disconnect(conn: &Connection, transport: &mut Transport, reason_code: ReasonCode) {
+    if conn.is_v5() {
+        if let Err(err) = transport.send(Box::new(response::DISCONNECT { reason_code })).await {
+            debug!(err = ?err, "failed to send DISCONNECT.");
+        }
+    }
+}
+
+/// waits until a new connection with the same Client Identifier takes over the Session, which
+/// never completes if the connection is not registered.
+async fn taken_over(registration: &mut Option<Registration>) -> Takeover {
+    let takeover = match registration {
+        Some(Registration { takeover, .. }) => takeover.await,
+        None => futures::future::pending().await,
+    };
+    // the receiver is spent and must not be polled again, while the registration has been
+    // replaced by the new connection, which needs no deregistration.
+    registration.take();
+
+    match takeover {
+        Ok(takeover) => takeover,
+        Err(_) => futures::future::pending().await,
+    }
+}
+
+/// reads the next packet from the client. None is returned if the connection is closed, the
+/// Keep Alive is exceeded, the Session is taken over by a new connection, or the Server is
+/// shutting down.
+async fn next_request(conn: &mut Connection, transport: &mut Transport) -> Option<Result<Request, DecodeError>> {
+    // taken over while the caller stopped waiting for DISCONNECT to be sent.
+    if conn.taken_over.is_some() {
+        return None;
+    }
+    let deadline = conn.deadline();
+    let next = async {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, transport.next()).await.ok(),
+            None => Some(transport.next().await),
+        }
+    };
+
+    tokio::select! {
+        request = next => match request {
+            Some(request) => {
+                conn.last_active = Instant::now();
+                request
+            }
+            None if matches!(conn.state, State::Established) => {
+                warn!(addr = ?conn.addr, "CONNECT not received in time, the connection will be closed.");
+                None
+            }
+            None => {
+                warn!(addr = ?conn.addr, "Keep Alive exceeded, the connection will be closed.");
+                disconnect(conn, transport, ReasonCode::KEEP_ALIVE_TIMEOUT).await;
+                None
+            }
+        },
+        takeover = taken_over(&mut conn.registration) => {
+            info!(addr = ?conn.addr, "Session taken over by a new connection, the connection will be closed.");
+            // held before DISCONNECT is sent, as the caller might stop waiting for it.
+            conn.taken_over = Some(takeover);
+            disconnect(conn, transport, ReasonCode::SESSION_TAKEN_OVER).await;
+            None
+        }
+        _ = conn.shutdown.poll() => {
+            debug!(addr = ?conn.addr, "Server is shutting down, the connection will be closed.");
+            conn.shutting_down = true;
+            disconnect(conn, transport, ReasonCode::SERVER_SHUTTING_DOWN).await;
+            None
+        }
+    }
 }
 
 pub(crate) struct Handler {
@@ -81,10 +286,20 @@ pub(crate) struct Handler {
     transport: Transport,
     worker_manager: Arc<SyncWorkerManager>,
     session_manager: Arc<SyncSessionManager>,
-    // shutdown: Shutdown,
+    auth_manager: Arc<SyncAuthManager>,
     max_connections: Arc<Semaphore>,
 }
 
+#[macro_export]
+macro_rules! send {
+    ($frame:expr => $transport:expr) => {
+        if let Err(err) = $transport.send(Box::new($frame)).await {
+            warn!(err = ?err, "failed to send frame, the connection will be closed.");
+            return Err(());
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! require_state {
     ($type:ident requires $expected:pat, $s:expr) => {
@@ -102,79 +317,179 @@ macro_rules! require_state {
 #[allow(non_snake_case)]
 impl Handler {
     pub async fn serve(&mut self) {
-        while let Some(request) = self.transport.next().await {
+        self.process().await;
+
+        // the connection is closed without DISCONNECT, which is an ungraceful disconnection.
+        if let State::Connected(..) = self.connection.state {
+            self.clean().await;
+        }
+
+        if let Some(registration) = self.connection.registration.take() {
+            self.session_manager.write().await.deregister(&registration);
+        }
+        // the new connection could take over the Session from now on.
+        self.connection.taken_over.take();
+    }
+
+    #[tracing::instrument(name = "Handler::clean", level = "debug", skip(self), fields(addr = ?self.connection.addr))]
+    async fn clean(&mut self) {
+        let (connect, session) = match std::mem::replace(&mut self.connection.state, State::Cleaning) {
+            State::Connected(connect, session) => (connect, session),
+            _ => return,
+        };
+        debug!(client_id = &connect.client_id[..], "connection closed ungracefully.");
+
+        // the Session is kept however the connection is closed, including being taken over, so
+        // it could be resumed by the next connection, which is about to connect in case of takeover.
+        if connect.persistent() {
+            let streams = std::mem::take(&mut self.connection.streams);
+            offline::park(&connect.client_id, Some(session), streams, &self.worker_manager, &self.session_manager, &self.connection.stats).await;
+        } else {
+            // the Session ends with the connection, including the one resumed without Clean Start.
+            self.session_manager.write().await.evict(&connect.client_id);
+        }
+        // the Session is persisted before the new connection could take it over.
+        let synced = self.session_manager.read().await.synced();
+        synced.await;
+
+        match connect.will {
+            Some(_) if self.connection.shutting_down => debug!("Will Message discarded, as the Server is shutting down."),
+            Some(will) => will.publish(&self.worker_manager).await,
+            None => {}
+        }
+
+        self.connection.state = State::Disconnected;
+    }
+
+    async fn process(&mut self) {
+        while let Some(request) = next_request(&mut self.connection, &mut self.transport).await {
             match request {
                 Ok(request) =>
                     match request {
                         Request::CONNECT(request) => {
-                            if let Err(_) = request.apply(&mut self.connection,
-                                                          &mut self.transport,
-                                                          &mut self.worker_manager,
-                                                          &mut self.session_manager).await {
+                            if request.apply(&mut self.connection,
+                                             &mut self.transport,
+                                             &mut self.worker_manager,
+                                             &mut self.session_manager,
+                                             &self.auth_manager).await.is_err() {
                                 return; // Err indicates the Network Connection should be closed.
                             }
                         }
                         Request::SUBSCRIBE(request) => {
-                            if let Err(_) = request.apply(&mut self.connection,
-                                                          &mut self.transport,
-                                                          &mut self.worker_manager,
-                                                          &mut self.session_manager).await {
+                            if request.apply(&mut self.connection,
+                                             &mut self.transport,
+                                             &mut self.worker_manager,
+                                             &mut self.session_manager,
+                                             &self.auth_manager).await.is_err() {
                                 return;
                             }
                         }
-                        Request::UNSUBSCRIBE(_) => {
+                        Request::UNSUBSCRIBE(request) => {
                             // if there's subscriptions in the previous session, the connection has
                             // already intercepted by an infinite loop in CONNECT::apply, so code here
-                            // only executed when a user trying to Unsubscribe without any subscriptions.
-                            // this may causes a Protocol Violation, but here we just do nothing.
+                            // only executed when a user trying to Unsubscribe without any active
+                            // subscriptions, which only needs to be acknowledged.
+                            if request.apply(&mut self.connection,
+                                             &mut self.transport,
+                                             &self.worker_manager,
+                                             &self.session_manager).await.is_err() {
+                                return;
+                            }
                         }
                         Request::PUBLISH(request) => {
-                            if let Err(_) = request.apply(&self.connection,
-                                                          &mut self.transport,
-                                                          &mut self.worker_manager).await {
+                            if request.apply(&mut self.connection,
+                                             &mut self.transport,
+                                             &self.worker_manager,
+                                             &self.auth_manager).await.is_err() {
+                                return;
+                            }
+                        }
+                        Request::PUBACK(request) => {
+                            if request.apply(&mut self.connection, &mut self.transport).await.is_err() {
+                                return;
+                            }
+                        }
+                        Request::PUBREC(request) => {
+                            if request.apply(&mut self.connection, &mut self.transport).await.is_err() {
+                                return;
+                            }
+                        }
+                        Request::PUBREL(request) => {
+                            if request.apply(&mut self.connection, &mut self.transport).await.is_err() {
+                                return;
+                            }
+                        }
+                        Request::PUBCOMP(request) => {
+                            if request.apply(&mut self.connection, &mut self.transport).await.is_err() {
                                 return;
                             }
                         }
                         Request::PINGREQ(request) => {
-                            if let Err(_) = request.apply(&self.connection, &mut self.transport).await {
+                            if request.apply(&self.connection, &mut self.transport).await.is_err() {
                                 return;
                             }
                         }
                         Request::DISCONNECT(request) => {
-                            if let Err(_) = request.apply(&mut self.connection,
-                                                          &mut self.transport,
-                                                          &mut self.session_manager).await {
+                            if request.apply(&mut self.connection,
+                                             &mut self.transport,
+                                             &self.worker_manager,
+                                             &mut self.session_manager).await.is_err() {
+                                return;
+                            }
+                        }
+                        Request::AUTH(request) => {
+                            if request.apply(&self.connection, &mut self.transport).await.is_err() {
                                 return;
                             }
                         }
                     }
                 Err(err) => {
-                    log_error(&self.connection.addr, err);
+                    log_error(&self.connection, &err);
+                    if let DecodeError::Parsing(_) = err {
+                        disconnect(&self.connection, &mut self.transport, ReasonCode::MALFORMED_PACKET).await;
+                    }
                     return;
                 }
             }
         }
     }
 
-    pub fn new(transport: Framed<TcpStream, MQTT311>,
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(transport: Transport,
                addr: SocketAddr,
                worker_manager: Arc<SyncWorkerManager>,
                session_manager: Arc<SyncSessionManager>,
-               max_connections: Arc<Semaphore>) -> Handler {
+               auth_manager: Arc<SyncAuthManager>,
+               stats: Arc<Stats>,
+               shutdown: Shutdown,
+               max_connections: Arc<Semaphore>,
+               connect_timeout: Duration) -> Handler {
         Handler {
             connection: Connection {
                 addr,
                 state: State::Established,
+                last_active: Instant::now(),
+                connect_timeout,
+                registration: None,
+                taken_over: None,
+                shutting_down: false,
+                streams: StreamMap::new(),
+                stats,
+                shutdown,
             },
             transport,
             worker_manager,
             session_manager,
+            auth_manager,
             max_connections,
         }
     }
 }
 
-fn log_error(addr: &SocketAddr, err: DecodeError) {
+fn log_error(conn: &Connection, err: &DecodeError) {
+    conn.stats.decode_error(err);
+
+    let addr = &conn.addr;
     match err {
         DecodeError::Parsing(err) =>
             warn!(addr = ?addr, err = "DecodeError::Parsing", underlying_err = ?err),
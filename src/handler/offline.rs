@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::{FutureExt, StreamExt};
+use tokio::sync::oneshot::{self, error::TryRecvError};
+use tracing::{debug, warn, Instrument};
+
+use crate::context::session::Parked;
+use crate::context::Stats;
+use crate::handler::{DesignatedSubscription, MessageStream, QueuedMessage, Session};
+use crate::handler::pub_sub::{is_local, message_stream};
+use crate::message::Qos;
+use crate::message::request::PUBLISH;
+use crate::server::{SyncSessionManager, SyncWorkerManager};
+
+/// saves the Session of a client which goes offline, and queues Qos 1+ messages of its
+/// subscriptions until it connects again, or until the Session expires. The Session is already
+/// saved if `session` is None, e.g. restored as the Server starts, of which the Session Expiry
+/// Interval is counted from then on.
+///
+/// `live` are the streams of the connection just closed, which are kept with the messages they
+/// buffered, so that no message is lost while the subscriptions are moved.
+pub(crate) async fn park(client_id: &str,
+                         session: Option<Session>,
+                         mut live: MessageStream,
+                         worker_manager: &Arc<SyncWorkerManager>,
+                         session_manager: &Arc<SyncSessionManager>,
+                         stats: &Arc<Stats>) {
+    let (subscriptions, expiry_interval) = match &session {
+        Some(session) => (session.subscriptions.clone(), session.expiry_interval),
+        None => match session_manager.read().await.get(client_id) {
+            Some(session) => (session.subscriptions.clone(), session.expiry_interval),
+            None => return,
+        },
+    };
+
+    let mut streams = MessageStream::new();
+    let mut subscribed = HashMap::new();
+    {
+        let mut worker_manager = worker_manager.write().await;
+        for subscription in subscriptions.iter().filter(|s| s.qos > Qos::FireAndForget) {
+            let stream = match live.remove(subscription.topic.as_str()) {
+                Some(stream) => stream,
+                None => message_stream(worker_manager.subscribe(&subscription.topic).await, subscription.topic.clone(), stats.clone(), None),
+            };
+            streams.insert(subscription.topic.clone(), stream);
+            subscribed.insert(subscription.topic.clone(), subscription.clone());
+        }
+    }
+
+    // the Session is saved and the task parked at once, so that the next connection of the client
+    // always finds the task to stop.
+    let mut sessions = session_manager.write().await;
+    if let Some(session) = session {
+        sessions.put(client_id, session);
+    }
+    if streams.is_empty() && expiry_interval.is_none() {
+        return;
+    }
+
+    let (stop, mut stopped) = oneshot::channel();
+    let owned_client_id = client_id.to_owned();
+    let session_manager = session_manager.clone();
+    let task = tokio::spawn(async move {
+        let client_id = owned_client_id;
+        let expired = async {
+            match expiry_interval {
+                Some(interval) => tokio::time::sleep(interval).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::pin!(expired);
+        loop {
+            tokio::select! {
+                Some((topic, message)) = streams.next() =>
+                    enqueue(&client_id, &subscribed[&topic], message, &session_manager).await,
+                result = &mut stopped => match result {
+                    // the streams are handed over to the next connection, with the messages not
+                    // received yet.
+                    Ok(_) => return streams,
+                    Err(_) => break,
+                },
+                _ = &mut expired => {
+                    // the task is stopped or replaced with the lock held, which might happen
+                    // while waiting for it.
+                    let mut sessions = session_manager.write().await;
+                    match stopped.try_recv() {
+                        Ok(_) => return streams,
+                        Err(TryRecvError::Closed) => break,
+                        Err(TryRecvError::Empty) => {
+                            debug!("Session expired.");
+                            sessions.expire(&client_id);
+                            return MessageStream::default();
+                        }
+                    }
+                }
+            }
+        }
+
+        // replaced by another task, so messages received before are queued as well.
+        while let Some(Some((topic, message))) = streams.next().now_or_never() {
+            enqueue(&client_id, &subscribed[&topic], message, &session_manager).await;
+        }
+        MessageStream::default()
+    }.instrument(tracing::debug_span!("offline", client_id)));
+    sessions.park(client_id, Parked { stop, task });
+}
+
+/// stops queueing messages for the client, which returns the streams of its subscriptions to be
+/// handed over to the next connection.
+pub(crate) async fn stop(parked: Parked) -> MessageStream {
+    let _ = parked.stop.send(());
+    parked.task.await.unwrap_or_default()
+}
+
+async fn enqueue(client_id: &str, subscription: &DesignatedSubscription, message: Arc<PUBLISH>, session_manager: &Arc<SyncSessionManager>) {
+    let qos = message.qos.min(subscription.qos);
+    if qos == Qos::FireAndForget || message.expired() || (subscription.options.no_local && is_local(&message, client_id)) {
+        return;
+    }
+
+    match session_manager.write().await.enqueue(client_id, QueuedMessage { qos, message }) {
+        Ok(_) => debug!("message queued for the offline client."),
+        // reported once, while the total is reported when the client connects again.
+        Err(1) => warn!("queue of the offline client is full, newly incoming messages will be dropped."),
+        Err(_) => {}
+    }
+}
@@ -1,166 +1,467 @@
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use futures::{SinkExt, StreamExt};
-use tokio::io::AsyncWriteExt;
 use tokio::sync::{broadcast::error::RecvError};
-use tokio_stream::{Stream, StreamMap};
+use tokio::time::Instant;
+use tokio_stream::Stream;
 use tracing::{debug, error, warn};
 
-use crate::handler::{Connection, DesignatedSubscription, log_error, Subscription};
-use crate::message::{Qos, Request, response};
-use crate::message::codec::Transport;
-use crate::message::request::{PUBLISH, SUBSCRIBE};
-use crate::require_state;
-use crate::server::{SyncSessionManager, SyncWorkerManager};
+use crate::context::auth::Access;
+use crate::context::pub_sub::Subscriber;
+use crate::context::Stats;
+use crate::context::topic;
+use crate::handler::{Connection, DesignatedSubscription, disconnect, InflightMessage, log_error, MessageStream, next_request, Subscription};
+use crate::message::{Qos, ReasonCode, Request, response, RetainHandling, SubscriptionOptions};
+use crate::message::property::{Properties, Property};
+use crate::message::codec::{DecodeError, Transport};
+use crate::message::request::{CONNECT, Origin, PUBLISH, SUBSCRIBE, UNSUBSCRIBE};
+use crate::{require_state, send};
+use crate::server::{SyncAuthManager, SyncSessionManager, SyncWorkerManager};
+use crate::util::ext::BoolExt;
 
 use super::State;
 
 impl PUBLISH {
-    #[tracing::instrument(name = "PUBLISH::apply", level = "debug", skip(transport, worker_manager))]
+    #[tracing::instrument(name = "PUBLISH::apply", level = "debug", skip(transport, worker_manager, auth_manager))]
     pub(crate) async fn apply(
-        self,
-        conn: &Connection,
+        mut self,
+        conn: &mut Connection,
         transport: &mut Transport,
         worker_manager: &Arc<SyncWorkerManager>,
+        auth_manager: &Arc<SyncAuthManager>,
     ) -> Result<(), ()> {
-        require_state!(PUBLISH requires State::Connected(..), &conn);
+        require_state!(PUBLISH requires State::Connected(..), conn);
         debug!("PUBLISH received.");
 
+        if !topic::is_valid_topic(&self.topic) {
+            warn!(addr = ?conn.addr, topic = &self.topic[..], "PUBLISH to an invalid Topic Name, the connection will be closed.");
+            disconnect(conn, transport, ReasonCode::TOPIC_NAME_INVALID).await;
+            return Err(());
+        }
+        // Topic Alias Maximum is not sent in CONNACK, which means no alias is accepted.
+        if self.properties.iter().any(|p| matches!(p, Property::TopicAlias(_))) {
+            warn!(addr = ?conn.addr, "PUBLISH with a Topic Alias, the connection will be closed.");
+            disconnect(conn, transport, ReasonCode::TOPIC_ALIAS_INVALID).await;
+            return Err(());
+        }
+        // Subscription Identifiers are only sent by the Server, see MQTT 5.0 spec 3.3.2.3.8.
+        if self.properties.iter().any(|p| matches!(p, Property::SubscriptionIdentifier(_))) {
+            warn!(addr = ?conn.addr, "PUBLISH with a Subscription Identifier, the connection will be closed.");
+            disconnect(conn, transport, ReasonCode::PROTOCOL_ERROR).await;
+            return Err(());
+        }
+        self.expires_at = expires_at(&self.properties);
+
+        let (qos, id) = (self.qos, self.id);
+        if let State::Connected(CONNECT { client_id, .. }, session) = &mut conn.state {
+            // a Qos 2 message is dispatched once its Packet Identifier is stored, and duplicates are
+            // only acknowledged until the identifier is released by PUBREL.
+            if let (Qos::AssuredDelivery, Some(id)) = (qos, id) {
+                if !session.received.insert(id) {
+                    debug!(id, "duplicated Qos 2 PUBLISH received, which will not be dispatched again.");
+                    send!(response::PUBREC { id, reason_code: ReasonCode::SUCCESS } => transport);
+                    return Ok(());
+                }
+            }
+            // so that subscriptions with No Local of the same client could skip it.
+            self.origin = Some(Origin::Client(client_id.clone()));
+        }
+
         let topic = self.topic.clone();
-        match worker_manager.read().await.dispatch(&topic, self).await {
-            Err(err) => {
-                // TODO: Qos 1+ requires failure informing mechanism
-                debug!(send_error = ?err, topic = &topic[..], "failed to dispatch.");
+        // a denied message is acknowledged as usual, but dropped silently.
+        if !conn.authorize(auth_manager, &topic, Access::Write).await {
+            debug!(topic = &topic[..], "PUBLISH denied by ACL, the message is dropped.");
+        } else {
+            match worker_manager.read().await.dispatch(&topic, self).await {
+                Err(err) => {
+                    // no subscriber is listening on the topic, which is not an error to the publisher.
+                    debug!(send_error = ?err, topic = &topic[..], "failed to dispatch.");
+                }
+                Ok(_) => { debug!("message dispatch successfully."); }
             }
-            Ok(_) => { debug!("message dispatch successfully."); }
+        }
+
+        match (qos, id) {
+            (Qos::AcknowledgedDeliver, Some(id)) => send!(response::PUBACK { id, reason_code: ReasonCode::SUCCESS } => transport),
+            (Qos::AssuredDelivery, Some(id)) => send!(response::PUBREC { id, reason_code: ReasonCode::SUCCESS } => transport),
+            _ => {}
         }
 
         Ok(())
     }
 }
 
-type MessageStream = StreamMap<String, Pin<Box<dyn Stream<Item=Arc<PUBLISH>> + Send>>>;
+/// when a message received just now expires by its Message Expiry Interval, if any.
+pub(crate) fn expires_at(properties: &Properties) -> Option<Instant> {
+    properties.iter().find_map(|p| match p {
+        Property::MessageExpiryInterval(interval) => Some(Instant::now() + Duration::from_secs(*interval as u64)),
+        _ => None,
+    })
+}
+
+impl PUBLISH {
+    /// whether the Message Expiry Interval has passed, after which the message is no longer
+    /// forwarded, see MQTT 5.0 spec 3.3.2.3.3.
+    pub(crate) fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// properties sent to subscribers, of which the Message Expiry Interval is the time left.
+    pub(crate) fn forwarded_properties(&self) -> Properties {
+        let expires_in = self.expires_at.map(|expires_at| expires_at.saturating_duration_since(Instant::now()));
+        Properties(self.properties.iter().map(|p| match (p, expires_in) {
+            // rounded up, so that it's not 0 until it expires.
+            (Property::MessageExpiryInterval(_), Some(expires_in)) =>
+                Property::MessageExpiryInterval((expires_in.as_millis() as u64).div_ceil(1000) as u32),
+            _ => p.clone(),
+        }).collect())
+    }
+}
+
+/// forwards a message received from the subscription of `filter` to the client. Messages with an
+/// effective Qos of 1+ are tracked in the Session until acknowledged.
+///
+/// `retain` should only be set when sending retained messages to a newly created subscription.
+async fn deliver(filter: &str, message: Arc<PUBLISH>, retain: bool, conn: &mut Connection, transport: &mut Transport) -> Result<(), ()> {
+    let (client_id, session) = match &mut conn.state {
+        State::Connected(CONNECT { client_id, .. }, session) => (client_id, session),
+        _ => return Err(()),
+    };
+
+    if message.expired() {
+        debug!(topic = &message.topic[..], "message expired, which is not forwarded.");
+        return Ok(());
+    }
+    let (granted_qos, options) = session.subscriptions.get(filter)
+        .map_or((Qos::FireAndForget, SubscriptionOptions::default()), |s| (s.qos, s.options));
+    if options.no_local && is_local(&message, client_id) {
+        debug!(topic = &message.topic[..], "message published by the client itself is not forwarded, as No Local is set.");
+        return Ok(());
+    }
+    let retain = retain || (options.retain_as_published && message.retain);
+    let qos = message.qos.min(granted_qos);
+    let frame = match (qos > Qos::FireAndForget).if_so_then(|| session.next_id()) {
+        Some(id) => {
+            let inflight = InflightMessage { qos, retain, message, released: false };
+            let frame = inflight.frame(id, false);
+            session.inflight.insert(id, inflight);
+            frame
+        }
+        None => response::PUBLISH {
+            dup: false,
+            qos,
+            retain,
+            topic: message.topic.clone(),
+            id: None,
+            payload: message.payload.clone(),
+            properties: message.forwarded_properties(),
+        }
+    };
+    send!(frame => transport);
+
+    Ok(())
+}
+
+/// whether the message is published by the connection of the client, see No Local of MQTT 5.0
+/// spec 3.8.3.1.
+pub(crate) fn is_local(message: &PUBLISH, client_id: &str) -> bool {
+    matches!(&message.origin, Some(Origin::Client(origin)) if origin == client_id)
+}
+
+/// turns the Subscriber of `topic` into a Stream of messages, which ends once the Publisher is gone.
+/// The connection is told by the span where the Stream is polled. Messages skipped by lagging are
+/// counted in `stats`, and in `lagged` as well if given.
+pub(crate) fn message_stream(mut subscriber: Subscriber, topic: String, stats: Arc<Stats>, lagged: Option<Arc<AtomicU64>>)
+    -> Pin<Box<dyn Stream<Item=Arc<PUBLISH>> + Send + Sync>> {
+    Box::pin(async_stream::stream! {
+        loop {
+            match subscriber.recv().await {
+                Ok(msg) => yield msg,
+                // Lagged happened when the channel is full, we print a log and do nothing.
+                Err(RecvError::Lagged(skipped)) => {
+                    stats.lag(skipped);
+                    if let Some(lagged) = &lagged {
+                        lagged.fetch_add(skipped, Ordering::Relaxed);
+                    }
+                    error!(topic = &topic[..], skipped,
+                            "broadcast channel of the topic is full, newly incoming messages publishing to this topic will be discarded directly.");
+                }
+                Err(_) => break,
+            }
+        }
+    })
+}
 
 impl SUBSCRIBE {
-    #[tracing::instrument(name = "SUBSCRIBE::apply", level = "debug", skip(transport, worker_manager, session_manager))]
+    #[tracing::instrument(name = "SUBSCRIBE::apply", level = "debug", skip(transport, worker_manager, session_manager, auth_manager))]
     pub(crate) async fn apply(
         self,
         conn: &mut Connection,
         transport: &mut Transport,
         worker_manager: &mut Arc<SyncWorkerManager>,
         session_manager: &mut Arc<SyncSessionManager>,
+        auth_manager: &Arc<SyncAuthManager>,
     ) -> Result<(), ()> {
         debug!("SUBSCRIBE received.");
+        self.verify(conn, transport).await?;
+
+        SUBSCRIBE::subscribe(&self.subscriptions, Some(self.id), conn, transport, worker_manager, session_manager, auth_manager).await
+    }
 
-        SUBSCRIBE::subscribe(&self.subscriptions, Some(self.id), conn, transport, worker_manager, session_manager).await?;
-        transport.send(Box::new(response::SUBACK {
-            id: self.id,
-            granted_qos: self.subscriptions.iter().map(|_| Some(Qos::FireAndForget)).collect(),
-        })).await;
+    /// Subscription Identifiers are not available as told by CONNACK, see MQTT 5.0 spec 3.8.2.1.2.
+    async fn verify(&self, conn: &Connection, transport: &mut Transport) -> Result<(), ()> {
+        if self.properties.iter().any(|p| matches!(p, Property::SubscriptionIdentifier(_))) {
+            warn!(addr = ?conn.addr, "SUBSCRIBE with a Subscription Identifier, the connection will be closed.");
+            disconnect(conn, transport, ReasonCode::SUBSCRIPTION_IDENTIFIERS_NOT_SUPPORTED).await;
+            return Err(());
+        }
 
         Ok(())
     }
 
-    #[tracing::instrument(name = "SUBSCRIBE::subscribe", level = "debug", skip(transport, worker_manager, session_manager))]
+    #[tracing::instrument(name = "SUBSCRIBE::subscribe", level = "debug", skip(transport, worker_manager, session_manager, auth_manager))]
     pub(crate) async fn subscribe(
-        topics: &Vec<Subscription>,
+        topics: &[Subscription],
         reply_to: Option<u16>,
         conn: &mut Connection,
         transport: &mut Transport,
         worker_manager: &mut Arc<SyncWorkerManager>,
         session_manager: &mut Arc<SyncSessionManager>,
+        auth_manager: &Arc<SyncAuthManager>,
     ) -> Result<(), ()> {
         require_state!(SUBSCRIBE requires State::Connected(..), &conn);
 
-        let mut subscriptions = StreamMap::new();
+        // streams are handed over by the offline task if the Session is resumed, and back to it
+        // once the client goes offline again.
+        let mut subscriptions = std::mem::take(&mut conn.streams);
+        let result = SUBSCRIBE::subscribed(topics, reply_to, conn, transport, &mut subscriptions, worker_manager, session_manager, auth_manager).await;
+        conn.streams = subscriptions;
 
-        SUBSCRIBE::subscribe_topics(&topics, (transport, reply_to), conn, &mut subscriptions, worker_manager).await;
+        result
+    }
+
+    /// serves the connection, while forwarding messages of the subscriptions to the client.
+    #[allow(clippy::too_many_arguments)]
+    async fn subscribed(
+        topics: &[Subscription],
+        reply_to: Option<u16>,
+        conn: &mut Connection,
+        transport: &mut Transport,
+        subscriptions: &mut MessageStream,
+        worker_manager: &mut Arc<SyncWorkerManager>,
+        session_manager: &mut Arc<SyncSessionManager>,
+        auth_manager: &Arc<SyncAuthManager>,
+    ) -> Result<(), ()> {
+        SUBSCRIBE::subscribe_topics(topics, (transport, reply_to), conn, subscriptions, worker_manager, session_manager, auth_manager).await?;
 
         loop {
             tokio::select! {
                 Some((topic, message)) = subscriptions.next() => {
                     debug!(topic = &topic[..], "received message from subscription");
-                    transport.get_mut().write(&message.raw).await;
+                    deliver(&topic, message, false, conn, transport).await?;
                 }
-                Some(request) = transport.next() => {
+                request = next_request(conn, transport) => {
                     match request {
-                        Ok(request) =>
+                        Some(Ok(request)) =>
                             match request {
                                 Request::SUBSCRIBE(request) => {
-                                    SUBSCRIBE::subscribe_topics(&topics,
+                                    request.verify(conn, transport).await?;
+                                    SUBSCRIBE::subscribe_topics(&request.subscriptions,
                                                                 (transport, Some(request.id)),
                                                                 conn,
-                                                                &mut subscriptions,
-                                                                worker_manager).await;
+                                                                subscriptions,
+                                                                worker_manager,
+                                                                session_manager,
+                                                                auth_manager).await?;
                                 }
-                                Request::UNSUBSCRIBE(request) => SUBSCRIBE::unsubscribe_topics(&request.topics, &mut subscriptions),
-                                Request::PUBLISH(request) => request.apply(conn, transport, worker_manager).await?,
+                                Request::UNSUBSCRIBE(request) => {
+                                    SUBSCRIBE::unsubscribe_topics(&request.topics, subscriptions);
+                                    request.apply(conn, transport, worker_manager, session_manager).await?;
+                                }
+                                Request::PUBLISH(request) => request.apply(conn, transport, worker_manager, auth_manager).await?,
+                                Request::PUBACK(request) => request.apply(conn, transport).await?,
+                                Request::PUBREC(request) => request.apply(conn, transport).await?,
+                                Request::PUBREL(request) => request.apply(conn, transport).await?,
+                                Request::PUBCOMP(request) => request.apply(conn, transport).await?,
                                 Request::PINGREQ(request) => request.apply(conn, transport).await?,
                                 Request::DISCONNECT(request) => {
-                                    request.apply(conn, transport, session_manager).await?;
+                                    conn.streams = std::mem::take(subscriptions);
+                                    request.apply(conn, transport, worker_manager, session_manager).await?;
                                     return Ok(());
                                 },
+                                Request::AUTH(request) => request.apply(conn, transport).await?,
                                 _ => return Err(())
                             }
-                        Err(err) => log_error(&conn.addr, err),
+                        Some(Err(err)) => {
+                            log_error(conn, &err);
+                            if let DecodeError::Parsing(_) = err {
+                                disconnect(conn, transport, ReasonCode::MALFORMED_PACKET).await;
+                            }
+                            return Err(());
+                        }
+                        None => return Err(()),
                     }
                 }
             }
         }
     }
 
-    #[tracing::instrument(name = "SUBSCRIBE::subscribe_topics", level = "debug", skip(reply_to, subscriptions, worker_manager))]
+    #[tracing::instrument(name = "SUBSCRIBE::subscribe_topics", level = "debug", skip(reply_to, subscriptions, worker_manager, session_manager, auth_manager))]
     async fn subscribe_topics(
-        topics: &Vec<Subscription>,
+        topics: &[Subscription],
         reply_to: (&mut Transport, Option<u16>),
         connection: &mut Connection,
         subscriptions: &mut MessageStream,
         worker_manager: &mut Arc<SyncWorkerManager>,
-    ) {
-        let session = match &mut connection.state {
-            State::Connected(_, session) => session,
-            _ => return,
+        session_manager: &Arc<SyncSessionManager>,
+        auth_manager: &Arc<SyncAuthManager>,
+    ) -> Result<(), ()> {
+        if topics.iter().any(|(topic, _, options)| options.no_local && topic::parse_shared(topic).is_some()) {
+            warn!(addr = ?connection.addr, "No Local set on a Shared Subscription, the connection will be closed.");
+            disconnect(connection, reply_to.0, ReasonCode::PROTOCOL_ERROR).await;
+            return Err(());
+        }
+
+        let mut authorized = Vec::with_capacity(topics.len());
+        for (topic, ..) in topics {
+            // a Shared Subscription is authorized by the Topic Filter following the Share Name.
+            let filter = topic::parse_shared(topic).map_or(&topic[..], |(_, filter)| filter);
+            authorized.push(connection.authorize(auth_manager, filter, Access::Read).await);
+        }
+
+        let (connect, session) = match &mut connection.state {
+            State::Connected(connect, session) => (connect, session),
+            _ => return Ok(()),
         };
 
         let mut granted_qos = Vec::new();
-        for (topic, qos) in topics {
-            session.subscriptions.insert(DesignatedSubscription { topic: topic.clone(), qos: qos.clone() });
-
-            let topic_handle = topic.clone();
-
-            let mut subscriber = worker_manager.write().await.subscribe(&topic).await;
-
-            let addr = connection.addr.clone();
-            let topic = topic.clone();
-            let stream = Box::pin(async_stream::stream! {
-                loop {
-                    match subscriber.recv().await {
-                        Ok(msg) => yield msg,
-                        // Lagged happened when the channel is full, we print a log and do nothing.
-                        Err(RecvError::Lagged(_)) =>
-                            error!(addr = ?addr, topic = &topic[..],
-                                    "broadcast channel of the topic is full, newly incoming messages publishing to this topic will be discarded directly."),
-                        Err(_) => break,
-                    }
+        let mut retained = Vec::new();
+        for ((topic, qos, options), authorized) in topics.iter().zip(authorized) {
+            if !topic::is_valid_filter(topic) {
+                debug!(topic = &topic[..], "invalid Topic Filter, subscription rejected.");
+                granted_qos.push(Err(ReasonCode::TOPIC_FILTER_INVALID));
+                continue;
+            }
+            if !authorized {
+                debug!(topic = &topic[..], "SUBSCRIBE denied by ACL, subscription rejected.");
+                // a restored subscription which is no longer allowed is removed as well.
+                session.subscriptions.remove(topic.as_str());
+                subscriptions.remove(topic.as_str());
+                granted_qos.push(Err(ReasonCode::NOT_AUTHORIZED));
+                continue;
+            }
+
+            let existed = session.subscriptions.replace(DesignatedSubscription {
+                topic: topic.clone(),
+                qos: *qos,
+                options: *options,
+            }).is_some();
+
+            // the stream is kept if it's handed over, or the subscription is replaced, so that
+            // messages it buffered are not lost.
+            if !subscriptions.contains_key(topic.as_str()) {
+                let subscriber = worker_manager.write().await.subscribe(topic).await;
+                subscriptions.insert(topic.clone(), message_stream(subscriber, topic.clone(), connection.stats.clone(), None));
+            }
+            // retained messages are only sent for a new subscription, rather than a restored one.
+            let send_retained = match options.retain_handling {
+                RetainHandling::Always => true,
+                RetainHandling::IfNew => !existed,
+                RetainHandling::Never => false,
+            };
+            if reply_to.1.is_some() && send_retained {
+                for message in worker_manager.read().await.retained(topic).await {
+                    retained.push((topic.clone(), message));
                 }
-            });
+            }
 
-            subscriptions.insert(topic_handle, stream);
-            granted_qos.push(Some(Qos::FireAndForget));
+            granted_qos.push(Ok(*qos));
         }
 
-        if let (transport, Some(id)) = reply_to {
-            transport.send(Box::new(response::SUBACK {
-                id,
-                granted_qos,
-            })).await;
+        // the persisted Session is updated too, so the subscriptions will be restored even if the
+        // Server restarts before the connection is closed.
+        if connect.persistent() {
+            let synced = {
+                let mut sessions = session_manager.write().await;
+                let mut persisted = sessions.get(&connect.client_id).cloned().unwrap_or_default();
+                persisted.subscriptions = session.subscriptions.clone();
+                persisted.expiry_interval = session.expiry_interval;
+                sessions.put(&connect.client_id, persisted);
+                sessions.synced()
+            };
+            synced.await;
         }
+
+        let (transport, reply_to) = reply_to;
+        if let Some(id) = reply_to {
+            send!(response::SUBACK { id, granted_qos } => transport);
+        }
+
+        for (topic, message) in retained {
+            deliver(&topic, message, true, connection, transport).await?;
+        }
+
+        Ok(())
     }
 
-    fn unsubscribe_topics(topics: &Vec<String>, subscriptions: &mut MessageStream) {
+    fn unsubscribe_topics(topics: &[String], subscriptions: &mut MessageStream) {
         for topic in topics.iter() {
             subscriptions.remove(topic);
         }
     }
-}
\ No newline at end of file
+}
+
+impl UNSUBSCRIBE {
+    /// removes the subscriptions from the Session and replies UNSUBACK. Streams of the subscriptions
+    /// should have been dropped beforehand, so that Publishers without any subscriber left could be
+    /// removed as well.
+    #[tracing::instrument(name = "UNSUBSCRIBE::apply", level = "debug", skip(transport, worker_manager, session_manager))]
+    pub(crate) async fn apply(
+        self,
+        conn: &mut Connection,
+        transport: &mut Transport,
+        worker_manager: &Arc<SyncWorkerManager>,
+        session_manager: &Arc<SyncSessionManager>,
+    ) -> Result<(), ()> {
+        require_state!(UNSUBSCRIBE requires State::Connected(..), conn);
+        debug!("UNSUBSCRIBE received.");
+
+        let mut reason_codes = Vec::with_capacity(self.topics.len());
+        if let State::Connected(CONNECT { client_id, .. }, session) = &mut conn.state {
+            for topic in self.topics.iter() {
+                reason_codes.push(match session.subscriptions.remove(topic.as_str()) {
+                    true => ReasonCode::SUCCESS,
+                    false => ReasonCode::NO_SUBSCRIPTION_EXISTED,
+                });
+            }
+
+            // the persisted Session is updated too, so the subscriptions won't be restored even if
+            // the Server restarts before the connection is closed, which is durable before UNSUBACK.
+            let synced = {
+                let mut sessions = session_manager.write().await;
+                if let Some(mut persisted) = sessions.get(client_id).cloned() {
+                    for topic in self.topics.iter() {
+                        persisted.subscriptions.remove(topic.as_str());
+                    }
+                    sessions.put(client_id, persisted);
+                }
+                sessions.synced()
+            };
+            synced.await;
+        }
+
+        {
+            let mut worker_manager = worker_manager.write().await;
+            for topic in self.topics.iter() {
+                worker_manager.unsubscribe(topic);
+            }
+        }
+
+        send!(response::UNSUBACK { id: self.id, reason_codes } => transport);
+
+        Ok(())
+    }
+}
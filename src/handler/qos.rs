@@ -0,0 +1,103 @@
+use futures::SinkExt;
+use tracing::{debug, warn};
+
+use crate::handler::Connection;
+use crate::message::codec::Transport;
+use crate::message::request::{PUBACK, PUBCOMP, PUBREC, PUBREL};
+use crate::message::{Qos, ReasonCode, response};
+use crate::{require_state, send};
+
+use super::{disconnect, State};
+
+impl PUBACK {
+    #[tracing::instrument(name = "PUBACK::apply", level = "debug", skip(transport))]
+    pub(crate) async fn apply(self, conn: &mut Connection, transport: &mut Transport) -> Result<(), ()> {
+        require_state!(PUBACK requires State::Connected(..), conn);
+        debug!("PUBACK received.");
+
+        if let State::Connected(_, session) = &mut conn.state {
+            match session.inflight.get(&self.id) {
+                Some(message) if message.qos == Qos::AcknowledgedDeliver => { session.inflight.remove(&self.id); }
+                Some(_) => return mismatched("PUBACK", self.id, conn, transport).await,
+                None => debug!(id = self.id, "PUBACK received for an unknown Packet Identifier, ignored."),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PUBREC {
+    #[tracing::instrument(name = "PUBREC::apply", level = "debug", skip(transport))]
+    pub(crate) async fn apply(self, conn: &mut Connection, transport: &mut Transport) -> Result<(), ()> {
+        require_state!(PUBREC requires State::Connected(..), conn);
+        debug!("PUBREC received.");
+
+        let mut reason_code = ReasonCode::SUCCESS;
+        if let State::Connected(_, session) = &mut conn.state {
+            match session.inflight.get_mut(&self.id) {
+                // the exchange ends with a failed PUBREC, see MQTT 5.0 spec 4.3.3.
+                Some(message) if message.qos == Qos::AssuredDelivery && self.reason_code.0 >= 0x80 => {
+                    debug!(id = self.id, reason_code = self.reason_code.0, "PUBREC of failure received, the message is dropped.");
+                    session.inflight.remove(&self.id);
+                    return Ok(());
+                }
+                Some(message) if message.qos == Qos::AssuredDelivery => message.released = true,
+                Some(_) => return mismatched("PUBREC", self.id, conn, transport).await,
+                None => {
+                    debug!(id = self.id, "PUBREC received for an unknown Packet Identifier.");
+                    reason_code = ReasonCode::PACKET_IDENTIFIER_NOT_FOUND;
+                }
+            }
+        }
+        send!(response::PUBREL { id: self.id, reason_code } => transport);
+
+        Ok(())
+    }
+}
+
+impl PUBREL {
+    #[tracing::instrument(name = "PUBREL::apply", level = "debug", skip(transport))]
+    pub(crate) async fn apply(self, conn: &mut Connection, transport: &mut Transport) -> Result<(), ()> {
+        require_state!(PUBREL requires State::Connected(..), conn);
+        debug!("PUBREL received.");
+
+        let mut reason_code = ReasonCode::SUCCESS;
+        if let State::Connected(_, session) = &mut conn.state {
+            if !session.received.remove(&self.id) {
+                debug!(id = self.id, "PUBREL received for an unknown Packet Identifier.");
+                reason_code = ReasonCode::PACKET_IDENTIFIER_NOT_FOUND;
+            }
+        }
+        // PUBCOMP is always replied, in case of the previous one was lost.
+        send!(response::PUBCOMP { id: self.id, reason_code } => transport);
+
+        Ok(())
+    }
+}
+
+impl PUBCOMP {
+    #[tracing::instrument(name = "PUBCOMP::apply", level = "debug", skip(transport))]
+    pub(crate) async fn apply(self, conn: &mut Connection, transport: &mut Transport) -> Result<(), ()> {
+        require_state!(PUBCOMP requires State::Connected(..), conn);
+        debug!("PUBCOMP received.");
+
+        if let State::Connected(_, session) = &mut conn.state {
+            match session.inflight.get(&self.id) {
+                Some(message) if message.qos == Qos::AssuredDelivery => { session.inflight.remove(&self.id); }
+                Some(_) => return mismatched("PUBCOMP", self.id, conn, transport).await,
+                None => debug!(id = self.id, "PUBCOMP received for an unknown Packet Identifier, ignored."),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// closes the connection acknowledging a message in flight with the acknowledgement of the other
+/// Qos, which is a Protocol Error.
+async fn mismatched(kind: &str, id: u16, conn: &Connection, transport: &mut Transport) -> Result<(), ()> {
+    warn!(addr = ?conn.addr, id, kind, "acknowledgement received for a message of the other Qos, the connection will be closed.");
+    disconnect(conn, transport, ReasonCode::PROTOCOL_ERROR).await;
+    Err(())
+}
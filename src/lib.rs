@@ -1,9 +1,37 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub use handle::{Handle, Message, Subscription};
+pub use message::Qos;
 pub use opt::Opt;
-pub use server::Server;
+pub use server::{Server, ServerBuilder};
 
 pub(crate) mod util;
 pub(crate) mod context;
 pub(crate) mod message;
 pub(crate) mod handler;
 pub mod server;
-pub mod opt;
\ No newline at end of file
+#[cfg(test)]
+mod server_test;
+pub mod opt;
+pub mod config;
+#[cfg(test)]
+mod config_test;
+pub mod listener;
+pub mod tls;
+#[cfg(test)]
+mod tls_test;
+pub mod ws;
+#[cfg(test)]
+mod ws_test;
+pub mod metrics;
+#[cfg(test)]
+mod metrics_test;
+pub(crate) mod bridge;
+#[cfg(test)]
+mod bridge_test;
+pub mod handle;
+#[cfg(test)]
+mod handle_test;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
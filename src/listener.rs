@@ -0,0 +1,73 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::config::{self, ListenerConfig};
+use crate::tls::{self, TlsListener};
+use crate::ws::WsListener;
+
+pub(crate) enum Transport {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Ws(WsListener),
+}
+
+/// A listener of MQTT connections over TCP, TLS or WebSocket, whose connections could be limited
+/// besides `max_connection` of the whole Server.
+pub struct Listener {
+    pub(crate) transport: Transport,
+    pub(crate) max_connections: Option<Arc<Semaphore>>,
+}
+
+impl Listener {
+    pub fn tcp(listener: TcpListener) -> Listener { Listener::new(Transport::Tcp(listener)) }
+    pub fn tls(listener: TlsListener) -> Listener { Listener::new(Transport::Tls(listener)) }
+    pub fn ws(listener: WsListener) -> Listener { Listener::new(Transport::Ws(listener)) }
+
+    fn new(transport: Transport) -> Listener {
+        Listener {
+            transport,
+            max_connections: None,
+        }
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Listener {
+        self.max_connections = Some(Arc::new(Semaphore::new(max_connections)));
+        self
+    }
+
+    pub async fn bind(config: &ListenerConfig) -> Result<Listener, config::Error> {
+        let listener = match config {
+            ListenerConfig::Tcp { addr, .. } => Listener::tcp(TcpListener::bind(addr).await?),
+            ListenerConfig::Tls { addr, cert, key, ca, .. } => {
+                let acceptor = tls::acceptor(cert, key, ca.as_deref())?;
+                Listener::tls(TlsListener::bind(addr, acceptor).await?)
+            }
+            ListenerConfig::Ws { addr, path, .. } => Listener::ws(WsListener::bind(addr, path).await?),
+        };
+
+        Ok(match config.max_connections() {
+            Some(max_connections) => listener.max_connections(max_connections),
+            None => listener,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.transport {
+            Transport::Tcp(listener) => listener.local_addr(),
+            Transport::Tls(listener) => listener.listener.local_addr(),
+            Transport::Ws(listener) => listener.listener.local_addr(),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> &'static str {
+        match &self.transport {
+            Transport::Tcp(_) => "TCP",
+            Transport::Tls(_) => "TLS",
+            Transport::Ws(_) => "WebSocket",
+        }
+    }
+}
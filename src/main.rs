@@ -1,24 +1,107 @@
 use std::sync::Arc;
 
-use structopt::StructOpt;
-use tokio::net::TcpListener;
 use tokio::sync::{broadcast, Semaphore};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
+use telesteller::config::{self, Error};
+use telesteller::listener::Listener;
+use telesteller::metrics::MetricsListener;
 use telesteller::Opt;
 use telesteller::Server;
+#[cfg(unix)]
+use telesteller::server::Reloader;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let opt = Opt::from_args();
-    let filter = tracing_subscriber::EnvFilter::try_new(&opt.log_filter)?;
-    tracing_subscriber::fmt().with_env_filter(filter).pretty().try_init()?;
+    let opt = match Opt::load() {
+        Ok(opt) => opt,
+        // prints the usage, or the help message if asked.
+        Err(Error::ArgumentError(err)) => err.exit(),
+        Err(err) => return Err(err.into()),
+    };
+    if opt.check_config {
+        config::check(&opt)?;
+        println!("the configuration is valid.");
+        return Ok(());
+    }
 
-    let listener = TcpListener::bind(&opt.addr).await?;
+    let filter = EnvFilter::try_new(&opt.log_filter)?;
+    let subscriber = tracing_subscriber::fmt().pretty().with_env_filter(filter).with_filter_reloading();
+    let filter_handle = subscriber.reload_handle();
+    subscriber.try_init()?;
+
+    let mut listeners = Vec::new();
+    for config in opt.listeners() {
+        listeners.push(Listener::bind(&config).await?);
+    }
+    let metrics_listener = match &opt.metrics_addr {
+        Some(addr) => Some(MetricsListener::bind(addr).await?),
+        None => None,
+    };
     let (shutdown_tx, _) = broadcast::channel(1);
+    let signal_tx = shutdown_tx.clone();
+    tokio::spawn(async move {
+        signal().await;
+        let _ = signal_tx.send(());
+    });
     let semaphore = Semaphore::new(opt.max_connection);
 
-    let mut server = Server::new(opt, listener, shutdown_tx, Arc::new(semaphore));
+    let mut server = Server::new(opt, listeners, metrics_listener, shutdown_tx, Arc::new(semaphore))?;
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let reloader = server.reloader();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                reload(&reloader, |filter| filter_handle.reload(filter)).await;
+            }
+        });
+    }
     server.serve().await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// waits for SIGINT or SIGTERM, the latter of which is what process managers stop services with.
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen to SIGTERM.");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+
+/// reloads the configuration on SIGHUP, which is kept as is if anything is invalid.
+#[cfg(unix)]
+async fn reload(reloader: &Reloader, reload_filter: impl Fn(EnvFilter) -> Result<(), tracing_subscriber::reload::Error>) {
+    let result = async {
+        let opt = Opt::load()?;
+        let filter = EnvFilter::try_new(&opt.log_filter)?;
+        let reload = reloader.prepare(&opt)?;
+        // the only part which may fail to be applied goes first, before anything is swapped in.
+        reload_filter(filter)?;
+        reload.apply().await;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(opt)
+    }.await;
+
+    match result {
+        Ok(opt) => info!(log_filter = &opt.log_filter[..],
+                         max_connection = opt.max_connection,
+                         password_file = ?opt.password_file,
+                         acl_file = ?opt.acl_file,
+                         deny_anonymous = opt.deny_anonymous,
+                         "configuration reloaded."),
+        Err(err) => error!(err = %err, "failed to reload the configuration, the previous one is kept."),
+    }
+}
@@ -0,0 +1,137 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::message::{Qos, ReasonCode, Request, SubscriptionOptions};
+use crate::message::codec::{DecodeError, EncodeError, split_frame};
+use crate::message::property::{self, Properties};
+use crate::message::request::{self, PUBLISH};
+use crate::message::response::{self, ResponseFrame, write_frame};
+
+/// Control Packets sent by the Server as a client of another broker, which is only used by bridges.
+/// Only MQTT 5 is spoken, whose No Local keeps messages sent by the bridge from coming back.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outgoing {
+    CONNECT {
+        client_id: String,
+        keep_alive: u16,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// subscribes with No Local and Retain As Published.
+    SUBSCRIBE {
+        id: u16,
+        filters: Vec<(String, Qos)>,
+    },
+    PUBLISH(response::PUBLISH),
+    PUBACK(u16),
+    PINGREQ,
+    DISCONNECT,
+}
+
+/// Control Packets received from another broker.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Incoming {
+    CONNACK {
+        session_present: bool,
+        reason_code: ReasonCode,
+    },
+    /// the Reason Code of each Topic Filter subscribed, of which 0x80 or above means failure.
+    SUBACK {
+        id: u16,
+        reason_codes: Vec<ReasonCode>,
+    },
+    PUBLISH(PUBLISH),
+    PUBACK(u16),
+    PINGRESP,
+    DISCONNECT(ReasonCode),
+}
+
+fn put_text(text: &str, dst: &mut Vec<u8>) {
+    dst.put_u16(text.len() as u16);
+    dst.extend_from_slice(text.as_bytes());
+}
+
+/// The codec of a connection to another broker, which encodes Requests and decodes Responses
+/// contrary to [`super::codec::Codec`].
+pub(crate) struct ClientCodec;
+
+impl Encoder<Outgoing> for ClientCodec {
+    type Error = EncodeError;
+
+    fn encode(&mut self, item: Outgoing, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Outgoing::CONNECT { client_id, keep_alive, username, password } => {
+                let mut payload = Vec::new();
+                put_text("MQTT", &mut payload);
+                // Protocol Version 5 with Clean Start set, see MQTT 5.0 spec 3.1.2.
+                let flags = 0x02 | if username.is_some() { 0x80 } else { 0 } | if password.is_some() { 0x40 } else { 0 };
+                payload.extend_from_slice(&[0x05, flags]);
+                payload.put_u16(keep_alive);
+                Properties::default().to_bytes(&mut payload);
+                put_text(&client_id, &mut payload);
+                for text in username.iter().chain(password.iter()) {
+                    put_text(text, &mut payload);
+                }
+                write_frame(0x10, &payload, dst);
+            }
+            Outgoing::SUBSCRIBE { id, filters } => {
+                let mut payload = id.to_be_bytes().to_vec();
+                Properties::default().to_bytes(&mut payload);
+                let options = SubscriptionOptions { no_local: true, retain_as_published: true, ..SubscriptionOptions::default() };
+                for (filter, qos) in filters {
+                    put_text(&filter, &mut payload);
+                    payload.push(options.to_byte(qos));
+                }
+                write_frame(0x82, &payload, dst);
+            }
+            Outgoing::PUBLISH(publish) => publish.to_bytes_v5(dst)?,
+            Outgoing::PUBACK(id) => response::PUBACK { id, reason_code: ReasonCode::SUCCESS }.to_bytes_v5(dst)?,
+            Outgoing::PINGREQ => write_frame(0xc0, &[], dst),
+            Outgoing::DISCONNECT => write_frame(0xe0, &[], dst),
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Incoming;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = match split_frame(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut cursor = 1;
+        while frame.get(cursor).ok_or(request::Error::MalformedRequest)? & 128 != 0 {
+            cursor += 1;
+        }
+        let body = &frame[(cursor + 1)..];
+        let incoming = match frame[0] >> 4 {
+            // Properties are ignored, as none is asked for.
+            0b0010 => match body {
+                [flags, reason_code, ..] => Incoming::CONNACK { session_present: flags & 0x01 != 0, reason_code: ReasonCode(*reason_code) },
+                _ => return Err(request::Error::MalformedRequest.into()),
+            },
+            0b1001 => {
+                let mut cursor = 0;
+                let id = property::read_u16(&mut cursor, body)?;
+                Properties::from_bytes(&mut cursor, body)?;
+                Incoming::SUBACK { id, reason_codes: body[cursor..].iter().map(|code| ReasonCode(*code)).collect() }
+            }
+            0b1101 => Incoming::PINGRESP,
+            // Reason Code can be omitted for a normal disconnection.
+            0b1110 => Incoming::DISCONNECT(body.first().map_or(ReasonCode::NORMAL_DISCONNECTION, |code| ReasonCode(*code))),
+            // PUBLISH and PUBACK are the same in both directions.
+            _ => match Request::from_bytes_v5(frame)? {
+                Request::PUBLISH(publish) => Incoming::PUBLISH(publish),
+                Request::PUBACK(puback) => Incoming::PUBACK(puback.id),
+                _ => return Err(request::Error::MalformedRequest.into()),
+            },
+        };
+
+        Ok(Some(incoming))
+    }
+}
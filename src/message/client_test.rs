@@ -0,0 +1,72 @@
+#![allow(non_snake_case)]
+
+use bytes::{Bytes, BytesMut};
+use hex_literal::hex;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::client::*;
+use super::{Qos, ReasonCode};
+use super::property::{Properties, Property};
+use super::response;
+
+fn encode(item: Outgoing) -> Vec<u8> {
+    let mut dst = BytesMut::new();
+    ClientCodec.encode(item, &mut dst).unwrap();
+    dst.to_vec()
+}
+
+fn decode(src: &[u8]) -> Incoming {
+    ClientCodec.decode(&mut BytesMut::from(src)).unwrap().unwrap()
+}
+
+#[test]
+fn test_write_CONNECT_SUBSCRIBE() {
+    assert_eq!(encode(Outgoing::CONNECT { client_id: "b".to_owned(), keep_alive: 60, username: None, password: None }),
+               hex!("10 0e 00 04 4d 51 54 54 05 02 00 3c 00 00 01 62"));
+    assert_eq!(encode(Outgoing::CONNECT {
+        client_id: "b".to_owned(),
+        keep_alive: 60,
+        username: Some("u".to_owned()),
+        password: Some("p".to_owned()),
+    }), hex!("10 14 00 04 4d 51 54 54 05 c2 00 3c 00 00 01 62 00 01 75 00 01 70"));
+    // with No Local and Retain As Published.
+    assert_eq!(encode(Outgoing::SUBSCRIBE { id: 1, filters: vec![("a/#".to_owned(), Qos::AcknowledgedDeliver)] }),
+               hex!("82 09 00 01 00 00 03 61 2f 23 0d"));
+}
+
+#[test]
+fn test_write_PUBLISH_PINGREQ_DISCONNECT() {
+    assert_eq!(encode(Outgoing::PUBLISH(response::PUBLISH {
+        dup: false,
+        qos: Qos::AcknowledgedDeliver,
+        retain: true,
+        topic: "a".to_owned(),
+        id: Some(2),
+        payload: Bytes::from("x"),
+        properties: Properties(vec![Property::UserProperty("k".to_owned(), "v".to_owned())]),
+    })), hex!("33 0e 00 01 61 00 02 07 26 00 01 6b 00 01 76 78"));
+    assert_eq!(encode(Outgoing::PUBACK(2)), hex!("40 02 00 02"));
+    assert_eq!(encode(Outgoing::PINGREQ), hex!("c0 00"));
+    assert_eq!(encode(Outgoing::DISCONNECT), hex!("e0 00"));
+}
+
+#[test]
+fn test_read_responses() {
+    assert_eq!(decode(&hex!("20 05 01 00 02 21 00")), Incoming::CONNACK { session_present: true, reason_code: ReasonCode::SUCCESS });
+    assert_eq!(decode(&hex!("90 05 00 01 00 01 87")), Incoming::SUBACK { id: 1, reason_codes: vec![ReasonCode(0x01), ReasonCode::NOT_AUTHORIZED] });
+    assert_eq!(decode(&hex!("40 02 00 02")), Incoming::PUBACK(2));
+    assert_eq!(decode(&hex!("40 04 00 02 10 00")), Incoming::PUBACK(2));
+    assert_eq!(decode(&hex!("d0 00")), Incoming::PINGRESP);
+    assert_eq!(decode(&hex!("e0 01 8b")), Incoming::DISCONNECT(ReasonCode::SERVER_SHUTTING_DOWN));
+    assert_eq!(decode(&hex!("e0 00")), Incoming::DISCONNECT(ReasonCode::NORMAL_DISCONNECTION));
+    match decode(&hex!("30 05 00 01 61 00 78")) {
+        Incoming::PUBLISH(publish) => {
+            assert_eq!(publish.topic, "a");
+            assert_eq!(publish.payload, Bytes::from("x"));
+        }
+        incoming => panic!("unexpected {:?}", incoming),
+    }
+
+    assert!(ClientCodec.decode(&mut BytesMut::from(&hex!("20 01 00")[..])).is_err());
+    assert!(ClientCodec.decode(&mut BytesMut::from(&hex!("20 02")[..])).unwrap().is_none());
+}
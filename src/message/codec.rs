@@ -1,15 +1,22 @@
 use std::io;
+use std::sync::Arc;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Bytes, BytesMut};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
-use crate::get;
+use crate::context::Stats;
 use crate::message::{Request, ResponseFrame};
 
 pub(crate) struct MQTT311;
 
-pub(crate) type Transport = Framed<tokio::net::TcpStream, MQTT311>;
+/// The underlying stream of a connection, which could be TCP, TLS or WebSocket.
+pub(crate) trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+pub(crate) type Transport = Framed<Box<dyn Socket>, Codec>;
 
 #[derive(Error, Debug)]
 pub(crate) enum EncodeError {
@@ -35,45 +42,143 @@ pub(crate) enum DecodeError {
     IO(#[from] std::io::Error),
 }
 
+/// splits a frame from `src` by its Remaining Length, according to MQTT 3.1.1 spec 2.2.3.
+pub(super) fn split_frame(src: &mut BytesMut) -> Result<Option<Bytes>, DecodeError> {
+    let mut cursor = 1;
+    let mut multiplier = 1;
+    let mut length = 0;
+    loop {
+        let byte = match src.get(cursor) {
+            Some(byte) => usize::from(*byte),
+            None => return Ok(None),
+        };
+        length += (byte & 127) * multiplier;
+        if multiplier > 128 * 128 * 128 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame is too large.").into());
+        }
+        multiplier *= 128;
+        cursor += 1;
+
+        if (byte & 128) == 0 { break; }
+    }
+    length += cursor;
+
+    if src.len() < length {
+        src.reserve(length - src.len());
+        Ok(None)
+    } else {
+        Ok(Some(src.split_to(length).freeze()))
+    }
+}
+
 impl Decoder for MQTT311 {
     type Item = Request;
     type Error = DecodeError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 2 {
-            return Ok(None);
+        match split_frame(src)? {
+            Some(frame) => Request::from_bytes(frame).map(Some).map_err(DecodeError::Parsing),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) struct MQTT5;
+
+impl Encoder<Box<dyn ResponseFrame + Sync + Send>> for MQTT5 {
+    type Error = EncodeError;
+
+    fn encode(&mut self, item: Box<dyn ResponseFrame + Sync + Send>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.to_bytes_v5(dst).map_err(|e| e.into())
+    }
+}
+
+impl Decoder for MQTT5 {
+    type Item = Request;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match split_frame(src)? {
+            Some(frame) => Request::from_bytes_v5(frame).map(Some).map_err(DecodeError::Parsing),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The codec of a connection, which starts as MQTT 3.1.1 and switches to MQTT 5 once a CONNECT
+/// with Protocol Level 5 is received, so that clients of both versions can share the same listener.
+/// Packets are counted in Stats as they're encoded or decoded.
+pub(crate) struct Codec {
+    version: Version,
+    stats: Arc<Stats>,
+}
+
+enum Version {
+    MQTT311(MQTT311),
+    MQTT5(MQTT5),
+}
+
+impl Codec {
+    pub(crate) fn new(stats: Arc<Stats>) -> Self {
+        Codec {
+            version: Version::MQTT311(MQTT311),
+            stats,
         }
+    }
+}
+
+/// peeks the Protocol Level of a CONNECT frame, without parsing the whole frame.
+fn protocol_level(frame: &[u8]) -> Option<u8> {
+    if frame.first()? >> 4 != 0b0001 {
+        return None;
+    }
+
+    let mut cursor = 1;
+    while frame.get(cursor)? & 128 != 0 {
+        cursor += 1;
+    }
+    let name_len = u16::from_be_bytes([*frame.get(cursor + 1)?, *frame.get(cursor + 2)?]) as usize;
+    frame.get(cursor + 3 + name_len).copied()
+}
+
+impl Encoder<Box<dyn ResponseFrame + Sync + Send>> for Codec {
+    type Error = EncodeError;
 
-        // Decode variable-length Remaining Length field, according to MQTT311 spec 2.2.3
-        let mut cursor = 1;
-        let mut multiplier = 1;
-        let mut length = 0;
-        loop {
-            let byte = usize::from(*get!(cursor, src));
-            length += (byte & 127) * multiplier;
-            if multiplier > 128 * 128 * 128 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData,
-                                          format!("Frame is too large.")).into());
-            }
-            multiplier *= 128;
-            cursor += 1;
-
-            if (byte & 128) == 0 { break; }
+    fn encode(&mut self, item: Box<dyn ResponseFrame + Sync + Send>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        match &mut self.version {
+            Version::MQTT311(codec) => codec.encode(item, dst)?,
+            Version::MQTT5(codec) => codec.encode(item, dst)?,
         }
-        length += 2;
-        // println!("length: {}  len: {}", length, src.len());
-        // println!("{:?}", src.to_vec());
-
-        if src.len() < length {
-            src.reserve(length - src.len());
-            return Ok(None);
-        } else {
-            let data = get!(..length, src).to_vec();
-            src.advance(length);
-
-            Request::from_bytes(data.into())
-                .map(|r| Some(r))
-                .map_err(|e| DecodeError::Parsing(e))
+
+        if let Some(header) = dst.get(start) {
+            self.stats.send(*header, dst.len() - start);
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Decoder for Codec {
+    type Item = Request;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = match split_frame(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        self.stats.receive_bytes(frame.len());
+        if let (Version::MQTT311(_), Some(5)) = (&self.version, protocol_level(&frame)) {
+            self.version = Version::MQTT5(MQTT5);
+        }
+        let header = frame[0];
+        let request = match self.version {
+            Version::MQTT311(_) => Request::from_bytes(frame),
+            Version::MQTT5(_) => Request::from_bytes_v5(frame),
+        }?;
+        self.stats.receive(header);
+
+        Ok(Some(request))
+    }
+}
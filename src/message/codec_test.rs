@@ -8,7 +8,8 @@ use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use super::codec::*;
-use super::Qos;
+use super::{Qos, ReasonCode};
+use super::property::Properties;
 use super::response::*;
 
 #[tokio::test]
@@ -39,14 +40,15 @@ async fn test_read_CONNECT_SUBSCRIBE_PUBLISH() {
 async fn test_write_CONNACK_SUBACK() {
     let stream: Cursor<&mut [u8]> = Cursor::default();
     let mut transport = FramedWrite::new(stream, MQTT311);
-    transport.send(Box::new(CONNACK {
+    transport.feed(Box::new(CONNACK {
         session_present: true,
         return_code: CONNACKReturnCode::Accepted,
-    })).await;
-    transport.send(Box::new(SUBACK {
+        properties: Properties::default(),
+    })).await.unwrap();
+    transport.feed(Box::new(SUBACK {
         id: 41235,
-        granted_qos: vec![Some(Qos::AcknowledgedDeliver), None, Some(Qos::FireAndForget), Some(Qos::AssuredDelivery)],
-    })).await;
+        granted_qos: vec![Ok(Qos::AcknowledgedDeliver), Err(ReasonCode::NOT_AUTHORIZED), Ok(Qos::FireAndForget), Ok(Qos::AssuredDelivery)],
+    })).await.unwrap();
 
     assert_eq!(transport.write_buffer().to_vec(), &hex!("
         20 02 01 00
@@ -4,13 +4,19 @@ pub(crate) use response::ResponseFrame;
 pub(crate) mod request;
 pub(crate) mod response;
 pub(crate) mod codec;
+pub(crate) mod client;
+pub(crate) mod property;
 
 #[cfg(test)]
 mod request_test;
 #[cfg(test)]
 mod codec_test;
 #[cfg(test)]
+mod client_test;
+#[cfg(test)]
 mod response_test;
+#[cfg(test)]
+mod property_test;
 
 #[macro_export]
 macro_rules! get {
@@ -21,10 +27,11 @@ macro_rules! get {
 
 #[macro_export]
 macro_rules! pub_struct {
-    ($name:ident { $($field:ident: $t:ty,)*} ) => {
+    ($(#[$attr:meta])* $name:ident { $($(#[$field_attr:meta])* $field:ident: $t:ty,)*} ) => {
+        $(#[$attr])*
         #[derive(Debug, PartialEq)]
         pub(crate) struct $name {
-            $(pub(crate) $field: $t),*
+            $($(#[$field_attr])* pub(crate) $field: $t),*
         }
     }
 }
@@ -34,4 +41,61 @@ pub enum Qos {
     FireAndForget = 0,
     AcknowledgedDeliver = 1,
     AssuredDelivery = 2,
-}
\ No newline at end of file
+}
+
+/// Subscription Options of MQTT 5 other than the Maximum Qos, see MQTT 5.0 spec 3.8.3.1, which
+/// are all off in MQTT 3.1.1.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Hash)]
+pub(crate) struct SubscriptionOptions {
+    /// messages are not forwarded to the connection which published them.
+    pub(crate) no_local: bool,
+    /// RETAIN flag of forwarded messages is kept as published, rather than cleared.
+    pub(crate) retain_as_published: bool,
+    pub(crate) retain_handling: RetainHandling,
+}
+
+/// whether retained messages are sent when the subscription is made.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Hash)]
+pub(crate) enum RetainHandling {
+    #[default]
+    Always = 0,
+    /// only if the subscription does not exist yet.
+    IfNew = 1,
+    Never = 2,
+}
+
+impl SubscriptionOptions {
+    /// the Subscription Options byte of MQTT 5 with the Maximum Qos.
+    pub(crate) fn to_byte(self, qos: Qos) -> u8 {
+        qos as u8 | (self.no_local as u8) << 2 | (self.retain_as_published as u8) << 3 | (self.retain_handling as u8) << 4
+    }
+}
+
+/// Reason Code of MQTT 5, see MQTT 5.0 spec 2.4. The same value may have different names in
+/// different packets, so this is not an enum.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub(crate) struct ReasonCode(pub(crate) u8);
+
+impl ReasonCode {
+    pub(crate) const SUCCESS: ReasonCode = ReasonCode(0x00);
+    pub(crate) const NORMAL_DISCONNECTION: ReasonCode = ReasonCode(0x00);
+    pub(crate) const DISCONNECT_WITH_WILL_MESSAGE: ReasonCode = ReasonCode(0x04);
+    pub(crate) const NO_SUBSCRIPTION_EXISTED: ReasonCode = ReasonCode(0x11);
+    pub(crate) const UNSPECIFIED_ERROR: ReasonCode = ReasonCode(0x80);
+    pub(crate) const MALFORMED_PACKET: ReasonCode = ReasonCode(0x81);
+    pub(crate) const PROTOCOL_ERROR: ReasonCode = ReasonCode(0x82);
+    pub(crate) const UNSUPPORTED_PROTOCOL_VERSION: ReasonCode = ReasonCode(0x84);
+    pub(crate) const CLIENT_IDENTIFIER_NOT_VALID: ReasonCode = ReasonCode(0x85);
+    pub(crate) const BAD_USER_NAME_OR_PASSWORD: ReasonCode = ReasonCode(0x86);
+    pub(crate) const NOT_AUTHORIZED: ReasonCode = ReasonCode(0x87);
+    pub(crate) const SERVER_UNAVAILABLE: ReasonCode = ReasonCode(0x88);
+    pub(crate) const SERVER_SHUTTING_DOWN: ReasonCode = ReasonCode(0x8B);
+    pub(crate) const KEEP_ALIVE_TIMEOUT: ReasonCode = ReasonCode(0x8D);
+    pub(crate) const SESSION_TAKEN_OVER: ReasonCode = ReasonCode(0x8E);
+    pub(crate) const TOPIC_FILTER_INVALID: ReasonCode = ReasonCode(0x8F);
+    pub(crate) const TOPIC_NAME_INVALID: ReasonCode = ReasonCode(0x90);
+    pub(crate) const PACKET_IDENTIFIER_NOT_FOUND: ReasonCode = ReasonCode(0x92);
+    pub(crate) const TOPIC_ALIAS_INVALID: ReasonCode = ReasonCode(0x94);
+    pub(crate) const SUBSCRIPTION_IDENTIFIERS_NOT_SUPPORTED: ReasonCode = ReasonCode(0xA1);
+}
+
@@ -0,0 +1,194 @@
+use bytes::{BufMut, Bytes};
+
+use super::request::{Error, TextType};
+use super::response::put_length;
+
+/// Properties of a MQTT 5 packet, see MQTT 5.0 spec 2.2.2. Properties are kept in the order they
+/// were received, as User Property may appear multiple times and its order should be preserved.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct Properties(pub(crate) Vec<Property>);
+
+/// names follow MQTT 5.0 spec 2.2.2.2, where User Property is one of them.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Bytes),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Bytes),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQos(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8),
+}
+
+/// reads a Variable Byte Integer, see MQTT 5.0 spec 1.5.5.
+pub(crate) fn read_varint(cursor: &mut usize, bytes: &[u8]) -> Result<usize, Error> {
+    let mut multiplier = 1;
+    let mut value = 0;
+    loop {
+        let byte = *bytes.get(*cursor).ok_or(Error::MalformedRequest)? as usize;
+        *cursor += 1;
+        value += (byte & 127) * multiplier;
+        if (byte & 128) == 0 {
+            return Ok(value);
+        }
+        if multiplier >= 128 * 128 * 128 {
+            return Err(Error::MalformedRequest);
+        }
+        multiplier *= 128;
+    }
+}
+
+pub(crate) fn read_slice<'a>(cursor: &mut usize, len: usize, bytes: &'a [u8]) -> Result<&'a [u8], Error> {
+    let slice = bytes.get(*cursor..(*cursor + len)).ok_or(Error::MalformedRequest)?;
+    *cursor += len;
+    Ok(slice)
+}
+
+pub(crate) fn read_u8(cursor: &mut usize, bytes: &[u8]) -> Result<u8, Error> { Ok(read_slice(cursor, 1, bytes)?[0]) }
+
+pub(crate) fn read_u16(cursor: &mut usize, bytes: &[u8]) -> Result<u16, Error> {
+    let slice = read_slice(cursor, 2, bytes)?;
+    Ok(u16::from_be_bytes([slice[0], slice[1]]))
+}
+
+pub(crate) fn read_u32(cursor: &mut usize, bytes: &[u8]) -> Result<u32, Error> {
+    let slice = read_slice(cursor, 4, bytes)?;
+    Ok(u32::from_be_bytes([slice[0], slice[1], slice[2], slice[3]]))
+}
+
+fn read_binary(cursor: &mut usize, bytes: &[u8]) -> Result<Bytes, Error> {
+    let len = read_u16(cursor, bytes)? as usize;
+    Ok(Bytes::copy_from_slice(read_slice(cursor, len, bytes)?))
+}
+
+pub(crate) fn read_text(cursor: &mut usize, bytes: &[u8]) -> Result<String, Error> {
+    let len = read_u16(cursor, bytes)? as usize;
+    String::from_utf8(read_slice(cursor, len, bytes)?.to_vec()).map_err(|err| Error::NonUTF8Text(TextType::Property, err))
+}
+
+impl Properties {
+    /// reads Properties from `cursor`, which starts with the Property Length, and moves `cursor` to
+    /// the end of the Properties.
+    pub(crate) fn from_bytes(cursor: &mut usize, bytes: &[u8]) -> Result<Properties, Error> {
+        let len = read_varint(cursor, bytes)?;
+        let end = *cursor + len;
+        if end > bytes.len() {
+            return Err(Error::MalformedRequest);
+        }
+
+        let bytes = &bytes[..end];
+        let mut properties = Vec::new();
+        while *cursor < end {
+            let property = match read_varint(cursor, bytes)? {
+                0x01 => Property::PayloadFormatIndicator(read_u8(cursor, bytes)?),
+                0x02 => Property::MessageExpiryInterval(read_u32(cursor, bytes)?),
+                0x03 => Property::ContentType(read_text(cursor, bytes)?),
+                0x08 => Property::ResponseTopic(read_text(cursor, bytes)?),
+                0x09 => Property::CorrelationData(read_binary(cursor, bytes)?),
+                0x0B => Property::SubscriptionIdentifier(read_varint(cursor, bytes)? as u32),
+                0x11 => Property::SessionExpiryInterval(read_u32(cursor, bytes)?),
+                0x12 => Property::AssignedClientIdentifier(read_text(cursor, bytes)?),
+                0x13 => Property::ServerKeepAlive(read_u16(cursor, bytes)?),
+                0x15 => Property::AuthenticationMethod(read_text(cursor, bytes)?),
+                0x16 => Property::AuthenticationData(read_binary(cursor, bytes)?),
+                0x17 => Property::RequestProblemInformation(read_u8(cursor, bytes)?),
+                0x18 => Property::WillDelayInterval(read_u32(cursor, bytes)?),
+                0x19 => Property::RequestResponseInformation(read_u8(cursor, bytes)?),
+                0x1A => Property::ResponseInformation(read_text(cursor, bytes)?),
+                0x1C => Property::ServerReference(read_text(cursor, bytes)?),
+                0x1F => Property::ReasonString(read_text(cursor, bytes)?),
+                0x21 => Property::ReceiveMaximum(read_u16(cursor, bytes)?),
+                0x22 => Property::TopicAliasMaximum(read_u16(cursor, bytes)?),
+                0x23 => Property::TopicAlias(read_u16(cursor, bytes)?),
+                0x24 => Property::MaximumQos(read_u8(cursor, bytes)?),
+                0x25 => Property::RetainAvailable(read_u8(cursor, bytes)?),
+                0x26 => Property::UserProperty(read_text(cursor, bytes)?, read_text(cursor, bytes)?),
+                0x27 => Property::MaximumPacketSize(read_u32(cursor, bytes)?),
+                0x28 => Property::WildcardSubscriptionAvailable(read_u8(cursor, bytes)?),
+                0x29 => Property::SubscriptionIdentifierAvailable(read_u8(cursor, bytes)?),
+                0x2A => Property::SharedSubscriptionAvailable(read_u8(cursor, bytes)?),
+                _ => return Err(Error::MalformedRequest),
+            };
+            properties.push(property);
+        }
+
+        Ok(Properties(properties))
+    }
+
+    /// writes Properties, starting with the Property Length.
+    pub(crate) fn to_bytes(&self, dst: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        for property in self.0.iter() {
+            property.to_bytes(&mut data);
+        }
+
+        put_length(data.len(), dst);
+        dst.extend_from_slice(&data);
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item=&Property> { self.0.iter() }
+}
+
+fn put_binary(value: &[u8], dst: &mut Vec<u8>) {
+    dst.put_u16(value.len() as u16);
+    dst.extend_from_slice(value);
+}
+
+impl Property {
+    fn to_bytes(&self, dst: &mut Vec<u8>) {
+        match self {
+            Property::PayloadFormatIndicator(v) => { dst.put_u8(0x01); dst.put_u8(*v) }
+            Property::MessageExpiryInterval(v) => { dst.put_u8(0x02); dst.put_u32(*v) }
+            Property::ContentType(v) => { dst.put_u8(0x03); put_binary(v.as_bytes(), dst) }
+            Property::ResponseTopic(v) => { dst.put_u8(0x08); put_binary(v.as_bytes(), dst) }
+            Property::CorrelationData(v) => { dst.put_u8(0x09); put_binary(v, dst) }
+            Property::SubscriptionIdentifier(v) => { dst.put_u8(0x0B); put_length(*v as usize, dst) }
+            Property::SessionExpiryInterval(v) => { dst.put_u8(0x11); dst.put_u32(*v) }
+            Property::AssignedClientIdentifier(v) => { dst.put_u8(0x12); put_binary(v.as_bytes(), dst) }
+            Property::ServerKeepAlive(v) => { dst.put_u8(0x13); dst.put_u16(*v) }
+            Property::AuthenticationMethod(v) => { dst.put_u8(0x15); put_binary(v.as_bytes(), dst) }
+            Property::AuthenticationData(v) => { dst.put_u8(0x16); put_binary(v, dst) }
+            Property::RequestProblemInformation(v) => { dst.put_u8(0x17); dst.put_u8(*v) }
+            Property::WillDelayInterval(v) => { dst.put_u8(0x18); dst.put_u32(*v) }
+            Property::RequestResponseInformation(v) => { dst.put_u8(0x19); dst.put_u8(*v) }
+            Property::ResponseInformation(v) => { dst.put_u8(0x1A); put_binary(v.as_bytes(), dst) }
+            Property::ServerReference(v) => { dst.put_u8(0x1C); put_binary(v.as_bytes(), dst) }
+            Property::ReasonString(v) => { dst.put_u8(0x1F); put_binary(v.as_bytes(), dst) }
+            Property::ReceiveMaximum(v) => { dst.put_u8(0x21); dst.put_u16(*v) }
+            Property::TopicAliasMaximum(v) => { dst.put_u8(0x22); dst.put_u16(*v) }
+            Property::TopicAlias(v) => { dst.put_u8(0x23); dst.put_u16(*v) }
+            Property::MaximumQos(v) => { dst.put_u8(0x24); dst.put_u8(*v) }
+            Property::RetainAvailable(v) => { dst.put_u8(0x25); dst.put_u8(*v) }
+            Property::UserProperty(k, v) => {
+                dst.put_u8(0x26);
+                put_binary(k.as_bytes(), dst);
+                put_binary(v.as_bytes(), dst);
+            }
+            Property::MaximumPacketSize(v) => { dst.put_u8(0x27); dst.put_u32(*v) }
+            Property::WildcardSubscriptionAvailable(v) => { dst.put_u8(0x28); dst.put_u8(*v) }
+            Property::SubscriptionIdentifierAvailable(v) => { dst.put_u8(0x29); dst.put_u8(*v) }
+            Property::SharedSubscriptionAvailable(v) => { dst.put_u8(0x2A); dst.put_u8(*v) }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+#![allow(non_snake_case)]
+
+use hex_literal::hex;
+
+use super::property::*;
+use super::request::{Error, TextType};
+
+#[test]
+fn test_read_varint() {
+    for (bytes, expected) in [(&hex!("00")[..], 0), (&hex!("7f")[..], 127), (&hex!("80 01")[..], 128),
+                              (&hex!("ff ff ff 7f")[..], 268_435_455)] {
+        let mut cursor = 0;
+        assert_eq!(read_varint(&mut cursor, bytes).unwrap(), expected);
+        assert_eq!(cursor, bytes.len());
+    }
+
+    let mut cursor = 0;
+    assert_eq!(read_varint(&mut cursor, &hex!("ff ff ff ff 01")).unwrap_err(), Error::MalformedRequest);
+}
+
+#[test]
+fn test_Properties() {
+    let bytes = hex!("
+        12 11 00 00 00 78 26 00 01 6b 00 01 76 23 00 05
+        0b 80 01 ff
+    ");
+    let mut cursor = 0;
+    let properties = Properties::from_bytes(&mut cursor, &bytes).unwrap();
+
+    assert_eq!(properties, Properties(vec![
+        Property::SessionExpiryInterval(120),
+        Property::UserProperty("k".to_owned(), "v".to_owned()),
+        Property::TopicAlias(5),
+        Property::SubscriptionIdentifier(128),
+    ]));
+    // the byte after Properties is left unread.
+    assert_eq!(cursor, bytes.len() - 1);
+
+    let mut encoded = Vec::new();
+    properties.to_bytes(&mut encoded);
+    assert_eq!(encoded, &bytes[..bytes.len() - 1]);
+}
+
+#[test]
+fn test_Properties_malformed() {
+    // length exceeds the frame.
+    let mut cursor = 0;
+    assert_eq!(Properties::from_bytes(&mut cursor, &hex!("05 11 00")).unwrap_err(), Error::MalformedRequest);
+
+    // unknown identifier.
+    let mut cursor = 0;
+    assert_eq!(Properties::from_bytes(&mut cursor, &hex!("02 7f 00")).unwrap_err(), Error::MalformedRequest);
+
+    // non UTF-8 text.
+    let mut cursor = 0;
+    match Properties::from_bytes(&mut cursor, &hex!("04 03 00 01 ff")).unwrap_err() {
+        Error::NonUTF8Text(text_type, _) => assert_eq!(text_type, TextType::Property),
+        err => panic!("expected Error::NonUTF8Text, got {:?}.", err),
+    }
+}
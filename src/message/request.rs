@@ -3,9 +3,11 @@ use std::string;
 use bytes::Bytes;
 use derive_more::From;
 use thiserror::Error;
+use tokio::time::Instant;
 
 use crate::{get, pub_struct};
-use crate::message::Qos;
+use crate::message::{Qos, ReasonCode, RetainHandling, SubscriptionOptions};
+use crate::message::property::{Properties, read_varint};
 use crate::util::ext::BoolExt;
 
 macro_rules! get_bit {
@@ -17,9 +19,10 @@ macro_rules! assert_byte {
         assert!((0..=7).contains(&$a));
         assert!((0..=7).contains(&$b));
 
+        let expected: u8 = $expected;
         for i in ($a..=$b) {
-            // println!("get {} of {:b}  result: {} expected: {}", i, $subject, get_bit!(i, $subject), get_bit!(i, $expected));
-            if get_bit!(i, $subject) != get_bit!(i, $expected) {
+            // println!("get {} of {:b}  result: {} expected: {}", i, $subject, get_bit!(i, $subject), get_bit!(i, expected));
+            if get_bit!(i, $subject) != get_bit!(i, expected) {
                 return Err(Error::MalformedRequest);
             }
         }
@@ -65,26 +68,68 @@ fn u16(bytes: &[u8]) -> u16 {
     out
 }
 
+/// returns the length of the Fixed Header, which varies with the Remaining Length, see MQTT 3.1.1 spec 2.2.
+#[inline]
+fn fixed_header_len(bytes: &[u8]) -> Result<usize, Error> {
+    let mut cursor = 1;
+    read_varint(&mut cursor, bytes)?;
+    Ok(cursor)
+}
+
+/// reads the Packet Identifier right after the Fixed Header, which MQTT 5 may follow with a Reason
+/// Code and Properties that are ignored.
+#[inline]
+fn packet_id(bytes: &[u8]) -> Result<u16, Error> {
+    let cursor = fixed_header_len(bytes)?;
+    Ok(u16(get!(cursor..(cursor + 2), bytes)))
+}
+
+/// reads Properties if the frame is of MQTT 5, or returns empty Properties otherwise.
+#[inline]
+fn properties_if(v5: bool, cursor: &mut usize, bytes: &[u8]) -> Result<Properties, Error> {
+    if v5 { Properties::from_bytes(cursor, bytes) } else { Ok(Properties::default()) }
+}
+
 #[derive(Debug, From)]
 pub(crate) enum Request {
     CONNECT(CONNECT),
     SUBSCRIBE(SUBSCRIBE),
     UNSUBSCRIBE(UNSUBSCRIBE),
     PUBLISH(PUBLISH),
+    PUBACK(PUBACK),
+    PUBREC(PUBREC),
+    PUBREL(PUBREL),
+    PUBCOMP(PUBCOMP),
     PINGREQ(PINGREQ),
     DISCONNECT(DISCONNECT),
+    AUTH(AUTH),
 }
 
 impl Request {
-    pub(crate) fn from_bytes(bytes: Bytes) -> Result<Self, Error> {
+    pub(crate) fn from_bytes(bytes: Bytes) -> Result<Self, Error> { Request::parse(bytes, false) }
+
+    pub(crate) fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> { Request::parse(bytes, true) }
+
+    fn parse(bytes: Bytes, v5: bool) -> Result<Self, Error> {
+        macro_rules! frame {
+            ($type:ident) => {
+                if v5 { Ok($type::from_bytes_v5(bytes)?.into()) } else { Ok($type::from_bytes(bytes)?.into()) }
+            };
+        }
+
         match get!(0, bytes) >> 4 {
-            0b0001 => Ok(CONNECT::from_bytes(bytes)?.into()),
-            0b1000 => Ok(SUBSCRIBE::from_bytes(bytes)?.into()),
-            0b1010 => Ok(UNSUBSCRIBE::from_bytes(bytes)?.into()),
-            0b0011 => Ok(PUBLISH::from_bytes(bytes)?.into()),
-            0b1100 => Ok(PINGREQ::from_bytes(bytes)?.into()),
-            0b1110 => Ok(DISCONNECT::from_bytes(bytes)?.into()),
-            _ => return Err(Error::InvalidHeader(get!(0, bytes) >> 4))
+            0b0001 => frame!(CONNECT),
+            0b1000 => frame!(SUBSCRIBE),
+            0b1010 => frame!(UNSUBSCRIBE),
+            0b0011 => frame!(PUBLISH),
+            0b0100 => frame!(PUBACK),
+            0b0101 => frame!(PUBREC),
+            0b0110 => frame!(PUBREL),
+            0b0111 => frame!(PUBCOMP),
+            0b1100 => frame!(PINGREQ),
+            0b1110 => frame!(DISCONNECT),
+            0b1111 if v5 => frame!(AUTH),
+            _ => Err(Error::InvalidHeader(get!(0, bytes) >> 4))
         }
     }
 }
@@ -105,6 +150,7 @@ pub enum TextType {
     WillTopic,
     Username,
     Topic,
+    Property,
 }
 
 impl Qos {
@@ -119,7 +165,7 @@ impl Qos {
     }
 
     #[inline]
-    fn from_byte(b: &u8) -> Result<Qos, Error> {
+    pub(crate) fn from_byte(b: &u8) -> Result<Qos, Error> {
         match b {
             0b00 => Ok(Qos::FireAndForget),
             0b01 => Ok(Qos::AcknowledgedDeliver),
@@ -129,11 +175,37 @@ impl Qos {
     }
 }
 
+impl SubscriptionOptions {
+    /// parses the Subscription Options byte of MQTT 5, of which reserved bits and Retain Handling
+    /// of 3 are malformed, see MQTT 5.0 spec 3.8.3.1.
+    pub(crate) fn from_byte(b: &u8) -> Result<(Qos, SubscriptionOptions), Error> {
+        if b & 0b1100_0000 != 0 {
+            return Err(Error::MalformedRequest);
+        }
+        let retain_handling = match (b >> 4) & 0b11 {
+            0 => RetainHandling::Always,
+            1 => RetainHandling::IfNew,
+            2 => RetainHandling::Never,
+            _ => return Err(Error::MalformedRequest),
+        };
+
+        Ok((Qos::from_byte(&(b & 0b11))?, SubscriptionOptions {
+            no_local: b & 0b0100 != 0,
+            retain_as_published: b & 0b1000 != 0,
+            retain_handling,
+        }))
+    }
+}
+
 pub trait RequestFrame {
     fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized;
+
+    /// parses the frame of MQTT 5, which is the same as MQTT 3.1.1 unless overridden.
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized { Self::from_bytes(bytes) }
 }
 
 pub_struct!(CONNECT {
+    protocol_name: &'static str,
     protocol_version: u8,
     clean_session: bool,
     keep_alive: u16,
@@ -141,6 +213,7 @@ pub_struct!(CONNECT {
     will: Option<Will>,
     username: Option<String>,
     password: Option<Bytes>,
+    properties: Properties,
 });
 
 pub_struct!(Will {
@@ -148,27 +221,39 @@ pub_struct!(Will {
     retain: bool,
     topic: String,
     payload: Bytes,
+    properties: Properties,
 });
 
-impl RequestFrame for CONNECT {
-    fn from_bytes(bytes: Bytes) -> Result<Self, Error> {
+impl CONNECT {
+    fn parse(bytes: Bytes, v5: bool) -> Result<Self, Error> {
         assert_byte!(4 to 7 of get!(0, bytes), 0);
-        if get!(2..=7, bytes) != [0, 4, 77, 81, 84, 84] { // 00 04 M Q T T
-            return Err(Error::MalformedRequest);
-        }
 
-        let connect_flags = get!(9, bytes);
-
-        let mut cursor = 12;
+        let mut cursor = fixed_header_len(&bytes)?;
+        // MQIsdp is the Protocol Name of MQTT 3.1, which pairs with Protocol Level 3.
+        let protocol_name = match consume_item!(cursor of bytes) {
+            b"MQTT" => "MQTT",
+            b"MQIsdp" => "MQIsdp",
+            _ => return Err(Error::MalformedRequest),
+        };
+
+        let protocol_version = *get!(cursor, bytes);
+        let connect_flags = *get!(cursor + 1, bytes);
+        assert_byte!(7 to 7 of connect_flags, 0);
+        let keep_alive = u16(get!((cursor + 2)..(cursor + 4), bytes));
+        cursor += 4;
+
+        let properties = properties_if(v5, &mut cursor, &bytes)?;
         let client_id = into_text!(ClientId whichis consume_item!(cursor of bytes));
 
         let maybe_will =
             get_bit!(5, connect_flags).if_so_then(|| {
+                let properties = properties_if(v5, &mut cursor, &bytes)?;
                 Ok(Will {
                     qos: Qos::from_bits(get_bit!(3, connect_flags), get_bit!(4, connect_flags))?,
                     retain: get_bit!(2, connect_flags),
                     topic: into_text!(WillTopic whichis consume_item!(cursor of bytes)),
                     payload: Bytes::copy_from_slice(consume_item!(cursor of bytes)),
+                    properties,
                 })
             });
 
@@ -182,72 +267,103 @@ impl RequestFrame for CONNECT {
             });
 
         Ok(CONNECT {
-            protocol_version: *get!(8, bytes),
+            protocol_name,
+            protocol_version,
             clean_session: get_bit!(6, connect_flags),
-            keep_alive: u16(&bytes[10..=11]),
+            keep_alive,
             client_id,
             will: unpack!(maybe_will),
             username: unpack!(maybe_username),
             password: unpack!(maybe_password),
+            properties,
         })
     }
 }
 
+impl RequestFrame for CONNECT {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> { CONNECT::parse(bytes, false) }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> { CONNECT::parse(bytes, true) }
+}
+
 pub_struct!(SUBSCRIBE {
     id: u16,
-    subscriptions: Vec<(String, Qos)>,
+    subscriptions: Vec<(String, Qos, SubscriptionOptions)>,
+    properties: Properties,
 });
 
-impl RequestFrame for SUBSCRIBE {
-    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+impl SUBSCRIBE {
+    fn parse(bytes: Bytes, v5: bool) -> Result<Self, Error> {
         assert_byte!(4 to 7 of get!(0, bytes), 0b0010);
 
         let len = bytes.len();
+        let mut cursor = fixed_header_len(&bytes)? + 2;
+        let id = u16(get!((cursor - 2)..cursor, bytes));
+        let properties = properties_if(v5, &mut cursor, &bytes)?;
+
         let mut subscriptions = Vec::new();
-        let mut cursor = 4;
         loop {
             if cursor >= len { break; }
 
             let v = consume_item!(cursor of bytes);
             let topic = into_text!(Topic whichis v);
-            subscriptions.push((topic, Qos::from_byte(get!(cursor, bytes))?));
+            let (qos, options) = match v5 {
+                true => SubscriptionOptions::from_byte(get!(cursor, bytes))?,
+                false => (Qos::from_byte(get!(cursor, bytes))?, SubscriptionOptions::default()),
+            };
+            subscriptions.push((topic, qos, options));
             cursor += 1;
         }
 
         Ok(SUBSCRIBE {
-            id: u16(get!(2..=3, bytes)),
+            id,
             subscriptions,
+            properties,
         })
     }
 }
 
+impl RequestFrame for SUBSCRIBE {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized { SUBSCRIBE::parse(bytes, false) }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized { SUBSCRIBE::parse(bytes, true) }
+}
+
 pub_struct!(UNSUBSCRIBE {
     id: u16,
     topics: Vec<String>,
 });
 
-impl RequestFrame for UNSUBSCRIBE {
-    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+impl UNSUBSCRIBE {
+    fn parse(bytes: Bytes, v5: bool) -> Result<Self, Error> {
         assert_byte!(4 to 7 of get!(0, bytes), 0b0010);
 
         let len = bytes.len();
+        let mut cursor = fixed_header_len(&bytes)? + 2;
+        let id = u16(get!((cursor - 2)..cursor, bytes));
+        properties_if(v5, &mut cursor, &bytes)?;
+
         let mut topics = Vec::new();
-        let mut cursor = 4;
         loop {
             if cursor >= len { break; }
 
             let v = consume_item!(cursor of bytes);
             topics.push(into_text!(Topic whichis v));
-            cursor += 1;
         }
 
         Ok(UNSUBSCRIBE {
-            id: u16(get!(2..=3, bytes)),
+            id,
             topics,
         })
     }
 }
 
+impl RequestFrame for UNSUBSCRIBE {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized { UNSUBSCRIBE::parse(bytes, false) }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized { UNSUBSCRIBE::parse(bytes, true) }
+}
+
 pub_struct!(PUBLISH {
     dup: bool,
     qos: Qos,
@@ -255,30 +371,128 @@ pub_struct!(PUBLISH {
     topic: String,
     id: Option<u16>,
     payload: Bytes,
-    raw: Bytes, // in order to route the frame to Subscriptions
+    properties: Properties,
+    /// where the message comes from, which is not a part of the packet.
+    origin: Option<Origin>,
+    /// when the message expires by its Message Expiry Interval, which is not a part of the packet
+    /// either.
+    expires_at: Option<Instant>,
 });
 
-impl RequestFrame for PUBLISH {
-    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
-        let flags = bytes[0];
+/// the source of a message dispatched by the Server.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Origin {
+    /// published by the connection of the Client Identifier.
+    Client(String),
+    /// imported by the bridge of the name.
+    Bridge(String),
+}
+
+impl PUBLISH {
+    fn parse(bytes: Bytes, v5: bool) -> Result<Self, Error> {
+        let flags = *get!(0, bytes);
         let qos = Qos::from_bits(get_bit!(5, flags), get_bit!(6, flags))?;
 
-        let mut cursor = 2;
+        let mut cursor = fixed_header_len(&bytes)?;
         let topic = into_text!(Topic whichis consume_item!(cursor of bytes));
         let maybe_id =
             (qos > Qos::FireAndForget).if_so_then(|| {
                 cursor += 2;
-                Ok(u16(&bytes[(cursor - 2)..cursor]))
+                Ok(u16(get!((cursor - 2)..cursor, bytes)))
             });
+        let id = unpack!(maybe_id);
+        // 0 is not a valid Packet Identifier, see MQTT 3.1.1 spec 2.3.1.
+        if id == Some(0) {
+            return Err(Error::MalformedRequest);
+        }
+        let properties = properties_if(v5, &mut cursor, &bytes)?;
 
         Ok(PUBLISH {
             dup: get_bit!(4, flags),
             qos,
             retain: get_bit!(7, flags),
             topic,
-            id: unpack!(maybe_id),
-            payload: Bytes::copy_from_slice(&bytes[cursor..]),
-            raw: bytes,
+            id,
+            payload: Bytes::copy_from_slice(get!(cursor.., bytes)),
+            properties,
+            origin: None,
+            expires_at: None,
+        })
+    }
+}
+
+impl RequestFrame for PUBLISH {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized { PUBLISH::parse(bytes, false) }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized { PUBLISH::parse(bytes, true) }
+}
+
+pub_struct!(PUBACK {
+    id: u16,
+});
+
+impl RequestFrame for PUBACK {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        Ok(PUBACK {
+            id: packet_id(&bytes)?,
+        })
+    }
+}
+
+pub_struct!(PUBREC {
+    id: u16,
+    /// always SUCCESS in MQTT 3.1.1.
+    reason_code: ReasonCode,
+});
+
+impl RequestFrame for PUBREC {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        Ok(PUBREC {
+            id: packet_id(&bytes)?,
+            reason_code: ReasonCode::SUCCESS,
+        })
+    }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        // the Reason Code can be omitted if the Remaining Length is 2, and the Properties are ignored.
+        let cursor = fixed_header_len(&bytes)? + 2;
+        Ok(PUBREC {
+            id: packet_id(&bytes)?,
+            reason_code: bytes.get(cursor).map(|code| ReasonCode(*code)).unwrap_or(ReasonCode::SUCCESS),
+        })
+    }
+}
+
+pub_struct!(PUBREL {
+    id: u16,
+});
+
+impl RequestFrame for PUBREL {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0b0010);
+
+        Ok(PUBREL {
+            id: packet_id(&bytes)?,
+        })
+    }
+}
+
+pub_struct!(PUBCOMP {
+    id: u16,
+});
+
+impl RequestFrame for PUBCOMP {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        Ok(PUBCOMP {
+            id: packet_id(&bytes)?,
         })
     }
 }
@@ -293,12 +507,62 @@ impl RequestFrame for PINGREQ {
     }
 }
 
-pub_struct!(DISCONNECT {});
+pub_struct!(DISCONNECT {
+    reason_code: ReasonCode,
+    properties: Properties,
+});
 
 impl RequestFrame for DISCONNECT {
     fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
         assert_byte!(4 to 7 of get!(0, bytes), 0);
 
-        Ok(DISCONNECT {})
+        Ok(DISCONNECT {
+            reason_code: ReasonCode::NORMAL_DISCONNECTION,
+            properties: Properties::default(),
+        })
+    }
+
+    fn from_bytes_v5(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        // Reason Code and Properties can be omitted if the Remaining Length is less than 1 and 2.
+        let mut cursor = fixed_header_len(&bytes)?;
+        let reason_code = bytes.get(cursor).map(|code| ReasonCode(*code)).unwrap_or(ReasonCode::NORMAL_DISCONNECTION);
+        cursor += 1;
+        let properties = match cursor < bytes.len() {
+            true => Properties::from_bytes(&mut cursor, &bytes)?,
+            false => Properties::default(),
+        };
+
+        Ok(DISCONNECT {
+            reason_code,
+            properties,
+        })
+    }
+}
+
+pub_struct!(AUTH {
+    reason_code: ReasonCode,
+    properties: Properties,
+});
+
+impl RequestFrame for AUTH {
+    fn from_bytes(bytes: Bytes) -> Result<Self, Error> where Self: Sized {
+        assert_byte!(4 to 7 of get!(0, bytes), 0);
+
+        let mut cursor = fixed_header_len(&bytes)?;
+        if cursor == bytes.len() {
+            return Ok(AUTH {
+                reason_code: ReasonCode::SUCCESS,
+                properties: Properties::default(),
+            });
+        }
+
+        let reason_code = ReasonCode(*get!(cursor, bytes));
+        cursor += 1;
+        Ok(AUTH {
+            reason_code,
+            properties: Properties::from_bytes(&mut cursor, &bytes)?,
+        })
     }
 }
\ No newline at end of file
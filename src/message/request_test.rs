@@ -3,7 +3,8 @@
 use bytes::Bytes;
 use hex_literal::hex;
 
-use super::Qos;
+use super::{Qos, ReasonCode, RetainHandling, SubscriptionOptions};
+use super::property::{Properties, Property};
 use super::request::*;
 
 macro_rules! hex_bytes {
@@ -21,12 +22,28 @@ macro_rules! test_success {
             assert_eq!(result.$accessor, $expected);
         )*
 
-        if let Request::$type(result) = Request::from_bytes(hex_bytes!($testcase)).unwrap() {
+        if let Request::$type(_result) = Request::from_bytes(hex_bytes!($testcase)).unwrap() {
             $(
-                assert_eq!(result.$accessor, $expected);
+                assert_eq!(_result.$accessor, $expected);
             )*
             println!("    (dispatched): passed");
-        } else { assert!(false); }
+        } else { panic!("dispatched to a wrong Request variant."); }
+    };
+}
+
+macro_rules! test_success_v5 {
+    (test $type:ident with $testcase:literal assert: $(eq [$accessor:ident, $expected:expr]), *) => {
+        let result = $type::from_bytes_v5(hex_bytes!($testcase)).unwrap();
+        println!("test_success_v5! on {}: {:?}", stringify!($type), result);
+        $(
+            assert_eq!(result.$accessor, $expected);
+        )*
+
+        if let Request::$type(_result) = Request::from_bytes_v5(hex_bytes!($testcase)).unwrap() {
+            $(
+                assert_eq!(_result.$accessor, $expected);
+            )*
+        } else { panic!("dispatched to a wrong Request variant."); }
     };
 }
 
@@ -52,11 +69,27 @@ fn test_CONNECT() {
                     retain: true,
                     topic: "/testwill/will".to_string(),
                     payload: "device now go ungracefully offline.".into(),
+                    properties: Properties::default(),
                 })],
                 eq [username, None],
                 eq [password, None]);
 }
 
+#[test]
+fn test_CONNECT_v31() {
+    test_success!(
+        test CONNECT with "10 0f 00 06 4d 51 49 73 64 70 03 02 00 3c 00 01 61"
+        assert:
+            eq [protocol_name, "MQIsdp"],
+            eq [protocol_version, 3],
+            eq [clean_session, true],
+            eq [client_id, "a"]);
+
+    // neither MQTT nor MQIsdp.
+    let malformed = CONNECT::from_bytes(hex_bytes!("10 0f 00 06 4d 51 49 73 64 71 03 02 00 3c 00 01 61")).unwrap_err();
+    assert_eq!(malformed, Error::MalformedRequest);
+}
+
 #[test]
 fn test_CONNECT_malformed() {
     let malformed1 = CONNECT::from_bytes(hex_bytes!("
@@ -86,7 +119,7 @@ fn test_CONNECT_non_utf8() {
         println!("type1: {:?}", type1);
         assert_eq!(type1, TextType::ClientId);
     } else {
-        assert!(false);
+        panic!("expected Error::NonUTF8Text.");
     }
 
     // WillTopic
@@ -100,7 +133,7 @@ fn test_CONNECT_non_utf8() {
         println!("type2: {:?}", type2);
         assert_eq!(type2, TextType::WillTopic);
     } else {
-        assert!(false);
+        panic!("expected Error::NonUTF8Text.");
     }
 }
 
@@ -113,7 +146,7 @@ fn test_SUBSCRIBE() {
         "
         assert:
             eq [id, 41234],
-            eq [subscriptions, vec![("/testwill/will".to_owned(), Qos::AssuredDelivery)]]);
+            eq [subscriptions, vec![("/testwill/will".to_owned(), Qos::AssuredDelivery, SubscriptionOptions::default())]]);
 
     test_success!(
         test SUBSCRIBE with "
@@ -121,8 +154,8 @@ fn test_SUBSCRIBE() {
         "
         assert:
             eq [id, 30203],
-            eq [subscriptions, vec![("a/b".to_owned(), Qos::AcknowledgedDeliver),
-                                     ("c/d".to_owned(), Qos::AssuredDelivery)] ]);
+            eq [subscriptions, vec![("a/b".to_owned(), Qos::AcknowledgedDeliver, SubscriptionOptions::default()),
+                                     ("c/d".to_owned(), Qos::AssuredDelivery, SubscriptionOptions::default())] ]);
 }
 
 #[test]
@@ -186,4 +219,175 @@ fn test_DISCONNECT() {
     test_success!(
         test DISCONNECT with "e0 00"
         assert: );
-}
\ No newline at end of file
+}
+#[test]
+fn test_PUBACK() {
+    test_success!(
+        test PUBACK with "40 02 a1 16"
+        assert:
+            eq [id, 41238]);
+}
+
+#[test]
+fn test_PUBREC_PUBREL_PUBCOMP() {
+    test_success!(
+        test PUBREC with "50 02 a1 16"
+        assert:
+            eq [id, 41238]);
+
+    test_success!(
+        test PUBREL with "62 02 a1 16"
+        assert:
+            eq [id, 41238]);
+
+    test_success!(
+        test PUBCOMP with "70 02 a1 16"
+        assert:
+            eq [id, 41238]);
+}
+
+#[test]
+fn test_PUBREC_v5() {
+    test_success_v5!(
+        test PUBREC with "50 02 a1 16"
+        assert:
+            eq [id, 41238],
+            eq [reason_code, ReasonCode::SUCCESS]);
+
+    test_success_v5!(
+        test PUBREC with "50 04 a1 16 80 00"
+        assert:
+            eq [id, 41238],
+            eq [reason_code, ReasonCode::UNSPECIFIED_ERROR]);
+}
+
+#[test]
+fn test_PUBREL_malformed() {
+    let malformed = PUBREL::from_bytes(hex_bytes!("60 02 a1 16")).unwrap_err();
+
+    assert_eq!(malformed, Error::MalformedRequest);
+}
+
+#[test]
+fn test_PUBLISH_malformed() {
+    // Qos 1 and 2 with the Packet Identifier 0.
+    assert_eq!(PUBLISH::from_bytes(hex_bytes!("32 0a 00 05 2f 61 62 63 64 00 00 31")).unwrap_err(), Error::MalformedRequest);
+    assert_eq!(PUBLISH::from_bytes_v5(hex_bytes!("34 0b 00 05 2f 61 62 63 64 00 00 00 31")).unwrap_err(), Error::MalformedRequest);
+}
+
+#[test]
+fn test_PUBLISH_long() {
+    // Remaining Length of 207 takes two bytes.
+    let mut bytes = hex!("30 cf 01 00 05 2f 61 62 63 64").to_vec();
+    bytes.extend_from_slice(&[0x31; 200]);
+
+    let result = PUBLISH::from_bytes(bytes.into()).unwrap();
+    assert_eq!(result.topic, "/abcd");
+    assert_eq!(result.payload, Bytes::from(vec![0x31; 200]));
+}
+
+#[test]
+fn test_UNSUBSCRIBE_multiple() {
+    test_success!(
+        test UNSUBSCRIBE with "a2 0c 48 c9 00 03 61 2f 62 00 03 63 2f 64"
+        assert:
+            eq [topics, vec!["a/b", "c/d"]]);
+}
+
+#[test]
+fn test_CONNECT_v5() {
+    test_success_v5!(
+        test CONNECT with "
+            10 13 00 04 4d 51 54 54 05 02 00 3c 05 11 00 00
+            00 78 00 01 61
+        " assert:
+            eq [protocol_version, 5],
+            eq [clean_session, true],
+            eq [keep_alive, 60],
+            eq [client_id, "a"],
+            eq [will, None],
+            eq [properties, Properties(vec![Property::SessionExpiryInterval(120)])]);
+}
+
+#[test]
+fn test_SUBSCRIBE_v5() {
+    test_success_v5!(
+        test SUBSCRIBE with "82 0f 00 01 00 00 03 61 2f 62 2e 00 03 63 2f 64 11"
+        assert:
+            eq [id, 1],
+            eq [subscriptions, vec![
+                ("a/b".to_owned(), Qos::AssuredDelivery, SubscriptionOptions {
+                    no_local: true,
+                    retain_as_published: true,
+                    retain_handling: RetainHandling::Never,
+                }),
+                ("c/d".to_owned(), Qos::AcknowledgedDeliver, SubscriptionOptions {
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::IfNew,
+                }),
+            ]],
+            eq [properties, Properties::default()]);
+
+    test_success_v5!(
+        test SUBSCRIBE with "82 0b 00 01 02 0b 01 00 03 61 2f 62 00"
+        assert:
+            eq [subscriptions, vec![("a/b".to_owned(), Qos::FireAndForget, SubscriptionOptions::default())]],
+            eq [properties, Properties(vec![Property::SubscriptionIdentifier(1)])]);
+}
+
+#[test]
+fn test_SUBSCRIBE_v5_malformed() {
+    // reserved bits of Subscription Options are set.
+    let malformed1 = SUBSCRIBE::from_bytes_v5(hex_bytes!("82 09 00 01 00 00 03 61 2f 62 42")).unwrap_err();
+    // Retain Handling of 3.
+    let malformed2 = SUBSCRIBE::from_bytes_v5(hex_bytes!("82 09 00 01 00 00 03 61 2f 62 32")).unwrap_err();
+    // Subscription Options are not a part of MQTT 3.1.1.
+    let malformed3 = SUBSCRIBE::from_bytes(hex_bytes!("82 08 00 01 00 03 61 2f 62 2e")).unwrap_err();
+
+    assert_eq!(malformed1, Error::MalformedRequest);
+    assert_eq!(malformed2, Error::MalformedRequest);
+    assert_eq!(malformed3, Error::MalformedRequest);
+}
+
+#[test]
+fn test_PUBLISH_v5() {
+    test_success_v5!(
+        test PUBLISH with "32 12 00 05 2f 61 62 63 64 a1 16 05 02 00 00 00 3c 31 32 33"
+        assert:
+            eq [qos, Qos::AcknowledgedDeliver],
+            eq [id, Some(41238)],
+            eq [payload, Bytes::from("123")],
+            eq [properties, Properties(vec![Property::MessageExpiryInterval(60)])]);
+}
+
+#[test]
+fn test_DISCONNECT_v5() {
+    test_success_v5!(
+        test DISCONNECT with "e0 01 04"
+        assert:
+            eq [reason_code, ReasonCode::DISCONNECT_WITH_WILL_MESSAGE]);
+
+    test_success_v5!(
+        test DISCONNECT with "e0 00"
+        assert:
+            eq [reason_code, ReasonCode::NORMAL_DISCONNECTION]);
+
+    test_success_v5!(
+        test DISCONNECT with "e0 07 00 05 11 00 00 00 3c"
+        assert:
+            eq [reason_code, ReasonCode::NORMAL_DISCONNECTION],
+            eq [properties, Properties(vec![Property::SessionExpiryInterval(60)])]);
+}
+
+#[test]
+fn test_AUTH() {
+    test_success_v5!(
+        test AUTH with "f0 02 18 00"
+        assert:
+            eq [reason_code, ReasonCode(0x18)],
+            eq [properties, Properties::default()]);
+
+    // AUTH does not exist in MQTT 3.1.1.
+    assert_eq!(Request::from_bytes(hex_bytes!("f0 02 18 00")).unwrap_err(), Error::InvalidHeader(15));
+}
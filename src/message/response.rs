@@ -1,35 +1,36 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use thiserror::Error;
 
 use crate::pub_struct;
 
-use super::Qos;
+use super::{Qos, ReasonCode};
+use super::property::Properties;
 
 macro_rules! set_bit {
     ($pos:literal to $value:expr, $subject:expr) => {
         if $value { $subject | (0b1000_0000 >> $pos) }
-        else      { $subject & (0b0111_1111) >> $pos }
+        else      { $subject & !(0b1000_0000 >> $pos) }
     };
 }
 
 #[inline]
 // TODO: anything like pub(test)?
-pub(super) fn put_length(x: usize, dst: &mut BytesMut) {
+pub(super) fn put_length(x: usize, dst: &mut impl BufMut) {
     let mut x = x;
     loop {
         let mut encoded = x % 128;
         x /= 128;
         if x > 0 {
-            encoded = encoded | 128;
+            encoded |= 128;
         }
         dst.put_u8(encoded as u8);
 
-        if x <= 0 { break; }
+        if x == 0 { break; }
     }
 }
 
 #[inline]
-fn write_frame(header: u8, data: &[u8], dst: &mut BytesMut) {
+pub(super) fn write_frame(header: u8, data: &[u8], dst: &mut BytesMut) {
     let len = data.len();
     dst.reserve(len + 8);
 
@@ -43,13 +44,30 @@ pub enum Error {}
 
 pub trait ResponseFrame {
     fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error>;
+
+    /// encodes the frame of MQTT 5, which is the same as MQTT 3.1.1 unless overridden.
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> { self.to_bytes(dst) }
+}
+
+/// writes the acknowledgement of MQTT 5, of which the Reason Code can be omitted if it is Success
+/// and there are no Properties, see MQTT 5.0 spec 3.4.2.1.
+#[inline]
+fn write_ack_v5(header: u8, id: u16, reason_code: ReasonCode, dst: &mut BytesMut) {
+    if reason_code == ReasonCode::SUCCESS {
+        write_frame(header, &id.to_be_bytes(), dst);
+    } else {
+        let [msb, lsb] = id.to_be_bytes();
+        write_frame(header, &[msb, lsb, reason_code.0], dst);
+    }
 }
 
 pub_struct!(CONNACK {
     session_present: bool,
     return_code: CONNACKReturnCode,
+    properties: Properties,
 });
 
+#[allow(dead_code)]
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Copy, Clone)]
 pub(crate) enum CONNACKReturnCode {
     Accepted = 0,
@@ -60,21 +78,47 @@ pub(crate) enum CONNACKReturnCode {
     NotAuthorized = 5,
 }
 
+impl CONNACKReturnCode {
+    /// the Reason Code of MQTT 5 with the same meaning, see MQTT 5.0 spec 3.2.2.2.
+    fn reason_code(self) -> ReasonCode {
+        match self {
+            CONNACKReturnCode::Accepted => ReasonCode::SUCCESS,
+            CONNACKReturnCode::UnacceptableProtocol => ReasonCode::UNSUPPORTED_PROTOCOL_VERSION,
+            CONNACKReturnCode::IdentifierRejected => ReasonCode::CLIENT_IDENTIFIER_NOT_VALID,
+            CONNACKReturnCode::ServerUnavailable => ReasonCode::SERVER_UNAVAILABLE,
+            CONNACKReturnCode::BadUsernameOrPassword => ReasonCode::BAD_USER_NAME_OR_PASSWORD,
+            CONNACKReturnCode::NotAuthorized => ReasonCode::NOT_AUTHORIZED,
+        }
+    }
+}
+
 impl ResponseFrame for CONNACK {
     fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
         write_frame(32, &[set_bit!(7 to self.session_present, 0), self.return_code as u8], dst);
 
         Ok(())
     }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        let mut payload = vec![set_bit!(7 to self.session_present, 0), self.return_code.reason_code().0];
+        self.properties.to_bytes(&mut payload);
+        write_frame(32, payload.as_slice(), dst);
+
+        Ok(())
+    }
 }
 
 pub_struct!(SUBACK {
     id: u16,
-    granted_qos: Vec<Option<Qos>>,
+    /// one for each Topic Filter of SUBSCRIBE, of which a rejection is always sent as 0x80 in
+    /// MQTT 3.1.1.
+    granted_qos: Vec<Result<Qos, ReasonCode>>,
 });
 
 pub_struct!(UNSUBACK {
     id: u16,
+    /// one for each Topic Filter of UNSUBSCRIBE, which is only sent in MQTT 5.
+    reason_codes: Vec<ReasonCode>,
 });
 
 impl ResponseFrame for UNSUBACK {
@@ -83,19 +127,44 @@ impl ResponseFrame for UNSUBACK {
 
         Ok(())
     }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        let mut payload = self.id.to_be_bytes().to_vec();
+        Properties::default().to_bytes(&mut payload);
+        payload.extend(self.reason_codes.iter().map(|code| code.0));
+        write_frame(176, payload.as_slice(), dst);
+
+        Ok(())
+    }
 }
 
-impl ResponseFrame for SUBACK {
-    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+impl SUBACK {
+    fn write(&self, v5: bool, dst: &mut BytesMut) {
         let mut payload = self.id.to_be_bytes().to_vec();
+        if v5 {
+            Properties::default().to_bytes(&mut payload);
+        }
         for qos in self.granted_qos.iter().as_ref() {
             let qos_byte = match qos {
-                Some(qos) => *qos as u8,
-                None => 128
+                Ok(qos) => *qos as u8,
+                Err(reason_code) if v5 => reason_code.0,
+                Err(_) => ReasonCode::UNSPECIFIED_ERROR.0,
             };
             payload.put_u8(qos_byte);
         }
         write_frame(144, payload.as_slice(), dst);
+    }
+}
+
+impl ResponseFrame for SUBACK {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        self.write(false, dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        self.write(true, dst);
 
         Ok(())
     }
@@ -109,4 +178,141 @@ impl ResponseFrame for PINGRESP {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+pub_struct!(PUBLISH {
+    dup: bool,
+    qos: Qos,
+    retain: bool,
+    topic: String,
+    id: Option<u16>,
+    payload: Bytes,
+    properties: Properties,
+});
+
+impl PUBLISH {
+    fn write(&self, v5: bool, dst: &mut BytesMut) {
+        let header = set_bit!(4 to self.dup, set_bit!(7 to self.retain, 48 | (self.qos as u8) << 1));
+
+        let mut payload = Vec::with_capacity(self.topic.len() + self.payload.len() + 4);
+        payload.put_u16(self.topic.len() as u16);
+        payload.extend_from_slice(self.topic.as_bytes());
+        if let Some(id) = self.id {
+            payload.put_u16(id);
+        }
+        if v5 {
+            self.properties.to_bytes(&mut payload);
+        }
+        payload.extend_from_slice(&self.payload);
+        write_frame(header, payload.as_slice(), dst);
+    }
+}
+
+impl ResponseFrame for PUBLISH {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        self.write(false, dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        self.write(true, dst);
+
+        Ok(())
+    }
+}
+
+pub_struct!(PUBACK {
+    id: u16,
+    reason_code: ReasonCode,
+});
+
+impl ResponseFrame for PUBACK {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(64, &self.id.to_be_bytes(), dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_ack_v5(64, self.id, self.reason_code, dst);
+
+        Ok(())
+    }
+}
+
+pub_struct!(PUBREC {
+    id: u16,
+    reason_code: ReasonCode,
+});
+
+impl ResponseFrame for PUBREC {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(80, &self.id.to_be_bytes(), dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_ack_v5(80, self.id, self.reason_code, dst);
+
+        Ok(())
+    }
+}
+
+pub_struct!(PUBREL {
+    id: u16,
+    reason_code: ReasonCode,
+});
+
+impl ResponseFrame for PUBREL {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(98, &self.id.to_be_bytes(), dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_ack_v5(98, self.id, self.reason_code, dst);
+
+        Ok(())
+    }
+}
+
+pub_struct!(PUBCOMP {
+    id: u16,
+    reason_code: ReasonCode,
+});
+
+impl ResponseFrame for PUBCOMP {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(112, &self.id.to_be_bytes(), dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_ack_v5(112, self.id, self.reason_code, dst);
+
+        Ok(())
+    }
+}
+
+pub_struct!(DISCONNECT {
+    reason_code: ReasonCode,
+});
+
+/// DISCONNECT sent by the Server, which only exists in MQTT 5.
+impl ResponseFrame for DISCONNECT {
+    fn to_bytes(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(224, &[], dst);
+
+        Ok(())
+    }
+
+    fn to_bytes_v5(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        write_frame(224, &[self.reason_code.0], dst);
+
+        Ok(())
+    }
+}
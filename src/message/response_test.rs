@@ -3,12 +3,23 @@
 use bytes::BytesMut;
 use hex_literal::hex;
 
-use super::Qos;
+use super::{Qos, ReasonCode};
+use super::property::{Properties, Property};
 use super::response::*;
 
 fn test_success(frame: impl ResponseFrame, expected: &[u8]) {
     let mut bytes = BytesMut::new();
-    frame.to_bytes(&mut bytes);
+    frame.to_bytes(&mut bytes).unwrap();
+    assert_bytes(bytes, expected);
+}
+
+fn test_success_v5(frame: impl ResponseFrame, expected: &[u8]) {
+    let mut bytes = BytesMut::new();
+    frame.to_bytes_v5(&mut bytes).unwrap();
+    assert_bytes(bytes, expected);
+}
+
+fn assert_bytes(bytes: BytesMut, expected: &[u8]) {
     let bytes = bytes.freeze();
     println!("frame - length: {}  bytes: {:x}", bytes.len(), bytes);
 
@@ -24,11 +35,18 @@ macro_rules! test_success {
     };
 }
 
+macro_rules! test_success_v5 {
+    ($frame:expr, $expected:literal) => {
+        test_success_v5($frame, hex!($expected)[..].into());
+    };
+}
+
 #[test]
 fn test_CONNACK() {
     test_success!(CONNACK {
         session_present: true,
         return_code: CONNACKReturnCode::Accepted,
+        properties: Properties::default(),
     }, "20 02 01 00");
 }
 
@@ -36,18 +54,125 @@ fn test_CONNACK() {
 fn test_SUBACK() {
     test_success!(SUBACK {
         id: 41235,
-        granted_qos: vec![Some(Qos::FireAndForget)],
+        granted_qos: vec![Ok(Qos::FireAndForget)],
     }, "90 03 a1 13 00");
 }
 
 #[test]
 fn test_UNSUBACK() {
     test_success!(UNSUBACK {
-        id: 18633
+        id: 18633,
+        reason_codes: vec![ReasonCode::SUCCESS],
     }, "b0 02 48 c9");
 }
 
 #[test]
 fn test_PINGRESP() {
     test_success!(PINGRESP {}, "d0 00");
-}
\ No newline at end of file
+}
+#[test]
+fn test_PUBLISH() {
+    test_success!(PUBLISH {
+        dup: true,
+        qos: Qos::AcknowledgedDeliver,
+        retain: false,
+        topic: "/abcd".to_owned(),
+        id: Some(41238),
+        payload: "123".into(),
+        properties: Properties::default(),
+    }, "3a 0c 00 05 2f 61 62 63 64 a1 16 31 32 33");
+
+    test_success!(PUBLISH {
+        dup: false,
+        qos: Qos::FireAndForget,
+        retain: true,
+        topic: "/abcd".to_owned(),
+        id: None,
+        payload: "123".into(),
+        properties: Properties::default(),
+    }, "31 0a 00 05 2f 61 62 63 64 31 32 33");
+}
+
+#[test]
+fn test_PUBACK() {
+    test_success!(PUBACK {
+        id: 41238,
+        reason_code: ReasonCode::SUCCESS,
+    }, "40 02 a1 16");
+}
+
+#[test]
+fn test_PUBREC_PUBREL_PUBCOMP() {
+    test_success!(PUBREC {
+        id: 41238,
+        reason_code: ReasonCode::SUCCESS,
+    }, "50 02 a1 16");
+
+    test_success!(PUBREL {
+        id: 41238,
+        reason_code: ReasonCode::SUCCESS,
+    }, "62 02 a1 16");
+
+    test_success!(PUBCOMP {
+        id: 41238,
+        reason_code: ReasonCode::SUCCESS,
+    }, "70 02 a1 16");
+}
+
+#[test]
+fn test_CONNACK_v5() {
+    test_success_v5!(CONNACK {
+        session_present: false,
+        return_code: CONNACKReturnCode::Accepted,
+        properties: Properties(vec![Property::SharedSubscriptionAvailable(0)]),
+    }, "20 05 00 00 02 2a 00");
+
+    test_success_v5!(CONNACK {
+        session_present: false,
+        return_code: CONNACKReturnCode::UnacceptableProtocol,
+        properties: Properties::default(),
+    }, "20 03 00 84 00");
+}
+
+#[test]
+fn test_SUBACK_UNSUBACK_v5() {
+    test_success_v5!(SUBACK {
+        id: 41235,
+        granted_qos: vec![Ok(Qos::AssuredDelivery), Err(ReasonCode::TOPIC_FILTER_INVALID)],
+    }, "90 05 a1 13 00 02 8f");
+
+    test_success_v5!(UNSUBACK {
+        id: 18633,
+        reason_codes: vec![ReasonCode::SUCCESS, ReasonCode::NO_SUBSCRIPTION_EXISTED],
+    }, "b0 05 48 c9 00 00 11");
+}
+
+#[test]
+fn test_PUBLISH_v5() {
+    test_success_v5!(PUBLISH {
+        dup: false,
+        qos: Qos::AcknowledgedDeliver,
+        retain: false,
+        topic: "/abcd".to_owned(),
+        id: Some(41238),
+        payload: "123".into(),
+        properties: Properties(vec![Property::MessageExpiryInterval(60)]),
+    }, "32 12 00 05 2f 61 62 63 64 a1 16 05 02 00 00 00 3c 31 32 33");
+}
+
+#[test]
+fn test_PUBACK_DISCONNECT_v5() {
+    test_success_v5!(PUBACK {
+        id: 41238,
+        reason_code: ReasonCode::SUCCESS,
+    }, "40 02 a1 16");
+
+    test_success_v5!(PUBCOMP {
+        id: 41238,
+        reason_code: ReasonCode::PACKET_IDENTIFIER_NOT_FOUND,
+    }, "70 03 a1 16 92");
+
+    test_success_v5!(DISCONNECT {
+        reason_code: ReasonCode::KEEP_ALIVE_TIMEOUT,
+    }, "e0 01 8d");
+}
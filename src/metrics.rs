@@ -0,0 +1,161 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::context::Stats;
+use crate::context::stats::PACKET_TYPES;
+use crate::server::{SyncSessionManager, SyncWorkerManager};
+use crate::util::Shutdown;
+
+/// the largest request head accepted, as nothing but `GET /metrics` is served.
+const MAX_REQUEST: usize = 8 * 1024;
+/// how long a request head is waited for, beyond which the connection is closed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// connections served at the same time, beyond which new ones wait to be accepted.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The listener of the HTTP endpoint serving `/metrics` in Prometheus text format.
+pub struct MetricsListener {
+    pub(crate) listener: TcpListener,
+}
+
+impl MetricsListener {
+    pub async fn bind(addr: &str) -> io::Result<MetricsListener> {
+        Ok(MetricsListener {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+}
+
+/// what the metrics are read from, which is shared by every request.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) worker_manager: Arc<SyncWorkerManager>,
+    pub(crate) session_manager: Arc<SyncSessionManager>,
+    pub(crate) max_connections: Arc<Semaphore>,
+}
+
+/// serves until the Server shuts down, after which the listener is closed.
+pub(crate) async fn serve(listener: MetricsListener, metrics: Metrics, mut shutdown: Shutdown) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let accepted = tokio::select! {
+            accepted = async {
+                // the permit is taken before accepting, like the MQTT listeners do.
+                let permit = connections.clone().acquire_owned().await.expect("the semaphore is never closed.");
+                listener.listener.accept().await.map(|accepted| (accepted, permit))
+            } => accepted,
+            _ = shutdown.poll() => return,
+        };
+        match accepted {
+            Ok(((socket, addr), permit)) => {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    if let Err(err) = respond(socket, metrics).await {
+                        debug!(addr = ?addr, err = ?err, "failed to serve metrics.");
+                    }
+                });
+            }
+            Err(err) => warn!(err = ?err, "failed to accept connection of the metrics listener."),
+        }
+    }
+}
+
+/// serves a single request and closes the connection, which is all Prometheus needs.
+async fn respond(mut socket: TcpStream, metrics: Metrics) -> io::Result<()> {
+    let mut buf = Vec::new();
+    // idle connections are closed rather than left open.
+    let received = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+            if buf.len() > MAX_REQUEST {
+                write_response(&mut socket, "431 Request Header Fields Too Large", "").await?;
+                return Ok(false);
+            }
+            if socket.read_buf(&mut buf).await? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok::<_, io::Error>(true)
+    }).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the request is not received in time."))??;
+    if !received {
+        return Ok(());
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => write_response(&mut socket, "200 OK", &metrics.render().await).await,
+        (Some("GET"), _) => write_response(&mut socket, "404 Not Found", "").await,
+        _ => write_response(&mut socket, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn write_response(socket: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status, body.len(), body);
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+impl Metrics {
+    /// renders the metrics in Prometheus text format, see
+    /// https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub(crate) async fn render(&self) -> String {
+        let (connected, total) = {
+            let session_manager = self.session_manager.read().await;
+            (session_manager.connected(), session_manager.total())
+        };
+        let subscriptions = self.worker_manager.read().await.subscriptions();
+        let stats = &self.stats;
+
+        let mut out = String::new();
+        metric(&mut out, "telesteller_connections_total", "counter", "Connections accepted.", &[("", stats.connections())]);
+        metric(&mut out, "telesteller_clients_connected", "gauge", "Clients connected currently.", &[("", connected as u64)]);
+        metric(&mut out, "telesteller_clients_total", "gauge", "Clients connected, and offline ones with a persistent Session.", &[("", total as u64)]);
+        metric(&mut out, "telesteller_connection_permits_available", "gauge", "Connections that can be accepted before reaching max_connection.",
+               &[("", self.max_connections.available_permits() as u64)]);
+        metric(&mut out, "telesteller_subscriptions", "gauge", "Subscriptions of all clients.", &[("", subscriptions as u64)]);
+
+        let received = PACKET_TYPES.iter().enumerate().skip(1)
+            .map(|(i, name)| (format!("type=\"{}\"", name), stats.received_of(i)))
+            .collect::<Vec<_>>();
+        let sent = PACKET_TYPES.iter().enumerate().skip(1)
+            .map(|(i, name)| (format!("type=\"{}\"", name), stats.sent_of(i)))
+            .collect::<Vec<_>>();
+        metric(&mut out, "telesteller_packets_received_total", "counter", "Control Packets received by type.", &labelled(&received));
+        metric(&mut out, "telesteller_packets_sent_total", "counter", "Control Packets sent by type.", &labelled(&sent));
+        metric(&mut out, "telesteller_bytes_received_total", "counter", "Bytes of Control Packets received.", &[("", stats.bytes_received())]);
+        metric(&mut out, "telesteller_bytes_sent_total", "counter", "Bytes of Control Packets sent.", &[("", stats.bytes_sent())]);
+
+        metric(&mut out, "telesteller_decode_errors_total", "counter", "Errors decoding Control Packets by kind.",
+               &[("kind=\"Parsing\"", stats.parsing_errors()), ("kind=\"IO\"", stats.io_errors())]);
+        metric(&mut out, "telesteller_lagged_messages_total", "counter", "Messages dropped as the subscriber lags behind.", &[("", stats.lagged())]);
+        metric(&mut out, "telesteller_uptime_seconds", "gauge", "Seconds since the server started.", &[("", stats.uptime().as_secs())]);
+
+        out
+    }
+}
+
+fn labelled(samples: &[(String, u64)]) -> Vec<(&str, u64)> {
+    samples.iter().map(|(labels, value)| (&labels[..], *value)).collect()
+}
+
+/// writes a metric family, of which each sample is the labels without braces and the value.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = match *labels {
+            "" => writeln!(out, "{} {}", name, value),
+            labels => writeln!(out, "{}{{{}}} {}", name, labels, value),
+        };
+    }
+}
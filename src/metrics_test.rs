@@ -0,0 +1,112 @@
+#![allow(non_snake_case)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Semaphore};
+
+use crate::{Opt, Server};
+use crate::listener::Listener;
+use crate::metrics::MetricsListener;
+
+/// starts a Server, returning the addresses of the MQTT and the metrics listener.
+async fn serve() -> (SocketAddr, SocketAddr) {
+    let metrics_listener = MetricsListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.listener.local_addr().unwrap();
+
+    let opt = Opt::from_iter(&["telesteller"]);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener)], Some(metrics_listener), shutdown_tx, Arc::new(Semaphore::new(8))).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    (addr, metrics_addr)
+}
+
+async fn get(addr: SocketAddr, path: &str) -> String {
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let (addr, metrics_addr) = serve().await;
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&[0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01, b'a']).await.unwrap();
+    let mut connack = [0; 4];
+    socket.read_exact(&mut connack).await.unwrap();
+    // the reserved Control Packet type is malformed, which closes the connection.
+    socket.write_all(&[0x00, 0x00]).await.unwrap();
+    assert_eq!(socket.read(&mut [0; 4]).await.unwrap(), 0);
+
+    let response = get(metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    for line in [
+        "telesteller_connections_total 1",
+        "telesteller_clients_connected 0",
+        // a permit is always taken in advance by the accept loop.
+        "telesteller_connection_permits_available 7",
+        "telesteller_packets_received_total{type=\"CONNECT\"} 1",
+        "telesteller_packets_received_total{type=\"PINGREQ\"} 0",
+        "telesteller_packets_sent_total{type=\"CONNACK\"} 1",
+        "telesteller_decode_errors_total{kind=\"Parsing\"} 1",
+        "telesteller_decode_errors_total{kind=\"IO\"} 0",
+        "telesteller_lagged_messages_total 0",
+        "# TYPE telesteller_lagged_messages_total counter",
+    ] {
+        assert!(response.lines().any(|l| l == line), "{} not found in:\n{}", line, response);
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_not_found() {
+    let (_, metrics_addr) = serve().await;
+    assert!(get(metrics_addr, "/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[tokio::test]
+async fn test_metrics_shutdown() {
+    let metrics_listener = MetricsListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.listener.local_addr().unwrap();
+    let mut server = Server::builder().metrics_listener(metrics_listener).build().unwrap();
+    let handle = server.handle();
+    let serving = tokio::spawn(async move { server.serve().await });
+    assert!(get(metrics_addr, "/metrics").await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // the port is released once the Server is shut down.
+    handle.shutdown();
+    serving.await.unwrap().unwrap();
+    assert!(TcpStream::connect(metrics_addr).await.is_err());
+    TcpListener::bind(metrics_addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_metrics_idle_connections() {
+    let (_, metrics_addr) = serve().await;
+    let mut idle = Vec::new();
+    for _ in 0..16 {
+        idle.push(TcpStream::connect(metrics_addr).await.unwrap());
+    }
+
+    // no more connections are served while the limit is reached by idle ones.
+    let request = tokio::spawn(get(metrics_addr, "/metrics"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!request.is_finished());
+    drop(idle.pop());
+    assert!(request.await.unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // idle connections are closed once the request is not received in time.
+    let start = tokio::time::Instant::now();
+    assert_eq!(idle[0].read(&mut [0; 1]).await.unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(6));
+}
@@ -1,8 +1,20 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
 use structopt::StructOpt;
 
+use crate::config::{self, BridgeConfig, ListenerConfig};
+
 #[derive(StructOpt, Debug)]
 #[structopt()]
 pub struct Opt {
+    /// TOML file of options and listeners, which are overridden by command line arguments and
+    /// `TELESTELLER_*` environment variables.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// validates the options and the files they refer to, then exits.
+    #[structopt(long)]
+    pub check_config: bool,
     #[structopt(short, long, default_value = "127.0.0.1:18990")]
     pub addr: String,
     #[structopt(long, default_value = "40960")]
@@ -11,4 +23,114 @@ pub struct Opt {
     pub max_session: Option<usize>,
     #[structopt(long, default_value = "info")]
     pub log_filter: String,
-}
\ No newline at end of file
+    /// the TLS listener is only started if both --tls-cert and --tls-key are given.
+    #[structopt(long, default_value = "127.0.0.1:8883")]
+    pub tls_addr: String,
+    /// PEM file of the certificate chain.
+    #[structopt(long, parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file of the private key.
+    #[structopt(long, parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+    /// PEM file of the CA, which requires clients to authenticate with certificates signed by it.
+    #[structopt(long, parse(from_os_str))]
+    pub tls_ca: Option<PathBuf>,
+    /// the WebSocket listener is only started if this is given.
+    #[structopt(long)]
+    pub ws_addr: Option<String>,
+    #[structopt(long, default_value = "/mqtt")]
+    pub ws_path: String,
+    /// file of `username:hash` lines in the format of mosquitto_passwd, which authenticates clients
+    /// with a username. Usernames are not verified if this is not given.
+    #[structopt(long, parse(from_os_str))]
+    pub password_file: Option<PathBuf>,
+    /// rejects clients without a username.
+    #[structopt(long)]
+    pub deny_anonymous: bool,
+    /// file of topic rules in the format of mosquitto's acl_file. Every topic is accessible to every
+    /// client if this is not given.
+    #[structopt(long, parse(from_os_str))]
+    pub acl_file: Option<PathBuf>,
+    /// file to which persistent Sessions are saved, so that they survive restarts. Sessions are
+    /// kept in memory only if this is not given.
+    #[structopt(long, parse(from_os_str))]
+    pub session_file: Option<PathBuf>,
+    /// Qos 1+ messages queued for each offline client with a persistent Session, beyond which
+    /// messages are dropped. 0 turns queueing off.
+    #[structopt(long, default_value = "1000")]
+    pub max_queued_messages: usize,
+    /// seconds between publishing statistics to `$SYS/broker/...` topics. 0 turns it off.
+    #[structopt(long, default_value = "10")]
+    pub sys_interval: u64,
+    /// the HTTP listener serving `/metrics` in Prometheus text format is only started if this is given.
+    #[structopt(long)]
+    pub metrics_addr: Option<String>,
+    /// seconds to wait for CONNECT once a connection is established, beyond which it's closed,
+    /// which bounds the TLS and WebSocket handshakes as well.
+    #[structopt(long, default_value = "10")]
+    pub connect_timeout: u64,
+    /// seconds to wait for connections to be closed on shutdown.
+    #[structopt(long, default_value = "10")]
+    pub drain_timeout: u64,
+    /// how messages of a Shared Subscription `$share/{ShareName}/{filter}` are distributed among
+    /// its members, either `round-robin` or `random`.
+    #[structopt(long, default_value = "round-robin")]
+    pub share_strategy: ShareStrategy,
+    /// declared in the configuration file, which replace the ones of `--addr`, `--tls-*` and `--ws-*`.
+    #[structopt(skip)]
+    pub listeners: Vec<ListenerConfig>,
+    /// declared in the configuration file only.
+    #[structopt(skip)]
+    pub bridges: Vec<BridgeConfig>,
+}
+
+impl Opt {
+    /// loads from command line arguments, environment variables and the configuration file.
+    pub fn load() -> Result<Opt, config::Error> {
+        let env = std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+        config::load_from(std::env::args_os(), env)
+    }
+
+    /// the listeners to be started, which are given by `--addr`, `--tls-*` and `--ws-*` unless
+    /// declared in the configuration file.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        let mut listeners = vec![ListenerConfig::Tcp { addr: self.addr.clone(), max_connections: None }];
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            listeners.push(ListenerConfig::Tls {
+                addr: self.tls_addr.clone(),
+                cert: cert.clone(),
+                key: key.clone(),
+                ca: self.tls_ca.clone(),
+                max_connections: None,
+            });
+        }
+        if let Some(addr) = &self.ws_addr {
+            listeners.push(ListenerConfig::Ws { addr: addr.clone(), path: self.ws_path.clone(), max_connections: None });
+        }
+
+        listeners
+    }
+}
+/// Chooses the member of a Shared Subscription to which a message is sent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShareStrategy {
+    RoundRobin,
+    Random,
+}
+
+impl FromStr for ShareStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(ShareStrategy::RoundRobin),
+            "random" => Ok(ShareStrategy::Random),
+            _ => Err(format!("unknown strategy `{}`, expected `round-robin` or `random`", s)),
+        }
+    }
+}
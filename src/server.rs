@@ -1,53 +1,288 @@
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use structopt::StructOpt;
 use thiserror::Error;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, RwLock, Semaphore};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
-use tracing::{debug, info};
+use tracing::{debug, info, warn, Instrument};
 
-use crate::context::{PublisherManager, SessionManager};
-use crate::handler::Handler;
-use crate::message::codec::MQTT311;
-use crate::Opt;
+use crate::bridge;
+use crate::config::BridgeConfig;
+use crate::context::{self, AuthManager, PublisherManager, SessionManager, Stats};
+use crate::context::stats;
+use crate::handler::{Handler, MessageStream, offline};
+use crate::listener::{Listener, Transport};
+use crate::message::codec::{Codec, Socket};
+use crate::metrics::{self, Metrics, MetricsListener};
+use crate::handle::Handle;
+use crate::opt::{Opt, ShareStrategy};
+use crate::util::Shutdown;
+use crate::ws;
 
 pub(crate) type SyncWorkerManager = RwLock<PublisherManager>;
 pub(crate) type SyncSessionManager = RwLock<SessionManager>;
+pub(crate) type SyncAuthManager = RwLock<AuthManager>;
 
 pub struct Server {
     opt: Opt,
-    listener: TcpListener,
-    shutdown_rx: broadcast::Sender<()>,
+    listeners: Vec<Listener>,
+    metrics_listener: Option<MetricsListener>,
+    /// fired once the Server should shut down, which every connection listens to as well.
+    shutdown_tx: broadcast::Sender<()>,
     max_connections: Arc<Semaphore>,
     worker_manager: Arc<SyncWorkerManager>,
     session_manager: Arc<SyncSessionManager>,
+    auth_manager: Arc<SyncAuthManager>,
+    stats: Arc<Stats>,
+    /// permits of `max_connections` in total, including the ones held by connections.
+    capacity: Arc<Mutex<usize>>,
+    /// of `opt`, or those given to the builder which may be less than a second.
+    sys_interval: Duration,
+    connect_timeout: Duration,
+    drain_timeout: Duration,
+}
+
+/// Swaps in the options that can be changed while the Server is running, which are the
+/// authentication and ACL data, and `max_connection`.
+#[derive(Clone)]
+pub struct Reloader {
+    auth_manager: Arc<SyncAuthManager>,
+    max_connections: Arc<Semaphore>,
+    capacity: Arc<Mutex<usize>>,
+}
+
+impl Reloader {
+    /// reads the files again and swaps in the options, see `prepare`.
+    pub async fn reload(&self, opt: &Opt) -> Result<(), Error> {
+        self.prepare(opt)?.apply().await;
+
+        Ok(())
+    }
+
+    /// reads the files again without changing anything, so that nothing changes if any of them is
+    /// invalid, or if anything else to be reloaded together with them fails.
+    pub fn prepare(&self, opt: &Opt) -> Result<Reload, Error> {
+        let auth_manager = AuthManager::new(opt.password_file.as_deref(), !opt.deny_anonymous, opt.acl_file.as_deref())?;
+
+        Ok(Reload { reloader: self.clone(), auth_manager, max_connection: opt.max_connection })
+    }
+}
+
+/// the options validated by `Reloader::prepare`, which are swapped in only by `apply`.
+pub struct Reload {
+    reloader: Reloader,
+    auth_manager: AuthManager,
+    max_connection: usize,
+}
+
+impl Reload {
+    /// Existing connections are kept even if there're more of them than the new `max_connection`,
+    /// in which case new ones are not accepted until enough of them are closed.
+    pub async fn apply(self) {
+        let Reload { reloader, auth_manager, max_connection } = self;
+        *reloader.auth_manager.write().await = auth_manager;
+
+        let mut capacity = reloader.capacity.lock().unwrap();
+        match max_connection.cmp(&capacity) {
+            Ordering::Greater => reloader.max_connections.add_permits(max_connection - *capacity),
+            Ordering::Less => {
+                // permits are taken away once they are released by connections.
+                let surplus = (*capacity - max_connection) as u32;
+                let max_connections = reloader.max_connections.clone();
+                tokio::spawn(async move {
+                    if let Ok(permits) = max_connections.acquire_many_owned(surplus).await {
+                        permits.forget();
+                    }
+                });
+            }
+            Ordering::Equal => {}
+        }
+        *capacity = max_connection;
+    }
 }
 
 impl Server {
-    pub async fn serve(&mut self) -> Result<TcpStream, Error> {
-        info!(addr = &self.opt.addr[..], "Telesteller server starts successfully.");
+    /// serves until the shutdown signal is sent, after which the connections are closed and their
+    /// Sessions saved, waiting for at most `drain_timeout`.
+    pub async fn serve(&mut self) -> Result<(), Error> {
+        info!("Telesteller server starts successfully.");
+        for listener in self.listeners.iter() {
+            info!(addr = ?listener.local_addr()?, kind = listener.kind(), "listener starts successfully.");
+        }
+        // both stop on shutdown, and are awaited before returning so that nothing is left running.
+        let mut tasks = Vec::new();
+        if let Some(metrics_listener) = self.metrics_listener.take() {
+            info!(addr = ?metrics_listener.listener.local_addr()?, "metrics listener starts successfully.");
+            tasks.push(tokio::spawn(metrics::serve(metrics_listener, Metrics {
+                stats: self.stats.clone(),
+                worker_manager: self.worker_manager.clone(),
+                session_manager: self.session_manager.clone(),
+                max_connections: self.max_connections.clone(),
+            }, Shutdown::new(self.shutdown_tx.subscribe()))));
+        }
+
+        if !self.sys_interval.is_zero() {
+            tasks.push(tokio::spawn(stats::publish_sys(self.sys_interval,
+                                                       self.stats.clone(),
+                                                       self.worker_manager.clone(),
+                                                       self.session_manager.clone(),
+                                                       Shutdown::new(self.shutdown_tx.subscribe()))));
+        }
+
+        // clients of the Sessions recovered are all offline.
+        let client_ids = self.session_manager.read().await.client_ids();
+        for client_id in client_ids {
+            offline::park(&client_id, None, MessageStream::default(), &self.worker_manager, &self.session_manager, &self.stats).await;
+        }
+
+        let mut shutdown = Shutdown::new(self.shutdown_tx.subscribe());
+        // every connection holds a sender, so that the receiver completes once all of them are closed.
+        let (closed_tx, mut closed_rx) = mpsc::channel::<()>(1);
+
+        for config in self.opt.bridges.iter().cloned() {
+            info!(name = &config.name[..], addr = &config.addr[..], "bridge starts.");
+            let span = tracing::info_span!("bridge", name = &config.name[..]);
+            let (worker_manager, stats) = (self.worker_manager.clone(), self.stats.clone());
+            let shutdown = Shutdown::new(self.shutdown_tx.subscribe());
+            let closed_tx = closed_tx.clone();
+            tokio::spawn(async move {
+                let _closed_tx = closed_tx;
+                bridge::run(config, worker_manager, stats, shutdown).await;
+            }.instrument(span));
+        }
+        let result = tokio::select! {
+            result = self.run(&closed_tx) => result,
+            _ = shutdown.poll() => {
+                info!("Telesteller server is shutting down, no more connections will be accepted.");
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            tasks.iter().for_each(JoinHandle::abort);
+            return Err(err);
+        }
+
+        drop(closed_tx);
+        match tokio::time::timeout(self.drain_timeout, closed_rx.recv()).await {
+            Ok(_) => info!("all connections are closed, Telesteller server shuts down successfully."),
+            Err(_) => warn!(drain_timeout = ?self.drain_timeout,
+                            "connections are not all closed within the drain timeout, which are abandoned."),
+        }
+        let synced = self.session_manager.read().await.synced();
+        synced.await;
+        for task in tasks {
+            let _ = task.await;
+        }
+
+        Ok(())
+    }
 
+    /// accepts connections from every listener until an error occurs, or runs until shutdown if
+    /// there's none, e.g. embedded only for the [`Handle`].
+    async fn run(&self, closed_tx: &mpsc::Sender<()>) -> Result<(), Error> {
+        if self.listeners.is_empty() {
+            futures::future::pending::<()>().await;
+        }
+        futures::future::try_join_all(self.listeners.iter().map(|listener| self.listen(listener, closed_tx))).await?;
+        Ok(())
+    }
+
+    async fn listen(&self, listener: &Listener, closed_tx: &mpsc::Sender<()>) -> Result<(), Error> {
         loop {
+            // the limit of the listener is checked first, so that a listener at its limit doesn't
+            // take the permits of others.
+            let permit = match &listener.max_connections {
+                Some(max_connections) => Some(max_connections.clone().acquire_owned().await?),
+                None => None,
+            };
             self.max_connections.acquire().await?.forget();
 
-            let (socket, addr) = self.accept().await?;
-            let transport = Framed::new(socket, MQTT311);
-            let worker_manager = self.worker_manager.clone();
-            let session_manager = self.session_manager.clone();
-            let max_connections = self.max_connections.clone();
-            tokio::spawn(async move {
-                Handler::new(transport, addr, worker_manager, session_manager, max_connections).serve().await;
-            });
+            match &listener.transport {
+                Transport::Tcp(tcp_listener) => {
+                    let (socket, addr) = Server::accept(tcp_listener).await?;
+                    self.spawn(addr, permit, closed_tx.clone(), async move { Some(Box::new(socket) as Box<dyn Socket>) });
+                }
+                Transport::Tls(tls_listener) => {
+                    let (socket, addr) = Server::accept(&tls_listener.listener).await?;
+                    let acceptor = tls_listener.acceptor.clone();
+                    let timeout = self.connect_timeout;
+                    self.spawn(addr, permit, closed_tx.clone(), async move {
+                        match tokio::time::timeout(timeout, acceptor.accept(socket)).await {
+                            Ok(Ok(socket)) => Some(Box::new(socket) as Box<dyn Socket>),
+                            Ok(Err(err)) => {
+                                warn!(addr = ?addr, err = ?err, "TLS handshake failed.");
+                                None
+                            }
+                            Err(_) => {
+                                warn!(addr = ?addr, "TLS handshake timed out.");
+                                None
+                            }
+                        }
+                    });
+                }
+                Transport::Ws(ws_listener) => {
+                    let (socket, addr) = Server::accept(&ws_listener.listener).await?;
+                    let path = ws_listener.path.clone();
+                    let timeout = self.connect_timeout;
+                    self.spawn(addr, permit, closed_tx.clone(), async move {
+                        match tokio::time::timeout(timeout, ws::accept(socket, path)).await {
+                            Ok(Ok(socket)) => Some(Box::new(socket) as Box<dyn Socket>),
+                            Ok(Err(err)) => {
+                                warn!(addr = ?addr, err = ?err, "WebSocket handshake failed.");
+                                None
+                            }
+                            Err(_) => {
+                                warn!(addr = ?addr, "WebSocket handshake timed out.");
+                                None
+                            }
+                        }
+                    });
+                }
+            }
         }
     }
 
-    async fn accept(&self) -> Result<(TcpStream, SocketAddr), Error> {
+    /// handles the connection once `socket` is ready, which may fail in case of a TLS or WebSocket
+    /// handshake. `permit` of the listener is held until the connection is closed.
+    fn spawn(&self,
+             addr: SocketAddr,
+             permit: Option<OwnedSemaphorePermit>,
+             closed_tx: mpsc::Sender<()>,
+             socket: impl Future<Output=Option<Box<dyn Socket>>> + Send + 'static) {
+        let worker_manager = self.worker_manager.clone();
+        let session_manager = self.session_manager.clone();
+        let auth_manager = self.auth_manager.clone();
+        let max_connections = self.max_connections.clone();
+        let stats = self.stats.clone();
+        let connect_timeout = self.connect_timeout;
+        // subscribed before the connection is handled, so that the signal is never missed.
+        let shutdown = Shutdown::new(self.shutdown_tx.subscribe());
+        tokio::spawn(async move {
+            let _closed_tx = closed_tx;
+            let _permit = permit;
+            match socket.await {
+                Some(socket) => {
+                    stats.connect();
+                    let transport = Framed::new(socket, Codec::new(stats.clone()));
+                    Handler::new(transport, addr, worker_manager, session_manager, auth_manager, stats, shutdown, max_connections, connect_timeout).serve().await;
+                }
+                None => max_connections.add_permits(1),
+            }
+        });
+    }
+
+    async fn accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr), Error> {
         let mut backoff = 1;
 
         loop {
-            match self.listener.accept().await {
+            match listener.accept().await {
                 Ok(result) => {
                     debug!(addr = ?&result.1, "TCP connection established.");
                     return Ok(result);
@@ -64,16 +299,155 @@ impl Server {
         }
     }
 
-    pub fn new(opt: Opt, listener: TcpListener, shutdown_rx: broadcast::Sender<()>, max_connections: Arc<Semaphore>) -> Server {
+    /// publishes and subscribes in process, which works once the Server is serving.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            worker_manager: self.worker_manager.clone(),
+            stats: self.stats.clone(),
+            shutdown_tx: self.shutdown_tx.clone(),
+        }
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            opt: Opt::from_iter(&["telesteller"]),
+            listeners: Vec::new(),
+            metrics_listener: None,
+            sys_interval: None,
+            connect_timeout: None,
+            drain_timeout: None,
+        }
+    }
+
+    pub fn reloader(&self) -> Reloader {
+        Reloader {
+            auth_manager: self.auth_manager.clone(),
+            max_connections: self.max_connections.clone(),
+            capacity: self.capacity.clone(),
+        }
+    }
+
+    pub fn new(opt: Opt,
+               listeners: Vec<Listener>,
+               metrics_listener: Option<MetricsListener>,
+               shutdown_tx: broadcast::Sender<()>,
+               max_connections: Arc<Semaphore>) -> Result<Server, Error> {
         let max_session = opt.max_session.unwrap_or(opt.max_connection);
-        Server {
-            opt,
-            listener,
-            shutdown_rx,
+        let auth_manager = AuthManager::new(opt.password_file.as_deref(), !opt.deny_anonymous, opt.acl_file.as_deref())?;
+        let session_manager = SessionManager::new(max_session, opt.session_file.as_deref(), opt.max_queued_messages)?;
+        let worker_manager = PublisherManager::new(opt.share_strategy);
+        let capacity = max_connections.available_permits();
+        Ok(Server {
+            listeners,
+            metrics_listener,
+            shutdown_tx,
             max_connections,
-            worker_manager: Arc::new(RwLock::new(PublisherManager::new())),
-            session_manager: Arc::new(RwLock::new(SessionManager::new(max_session))),
-        }
+            worker_manager: Arc::new(RwLock::new(worker_manager)),
+            session_manager: Arc::new(RwLock::new(session_manager)),
+            auth_manager: Arc::new(RwLock::new(auth_manager)),
+            stats: Arc::new(Stats::new()),
+            capacity: Arc::new(Mutex::new(capacity)),
+            sys_interval: Duration::from_secs(opt.sys_interval),
+            connect_timeout: Duration::from_secs(opt.connect_timeout),
+            drain_timeout: Duration::from_secs(opt.drain_timeout),
+            opt,
+        })
+    }
+}
+
+/// Builds a Server for embedding, whose options default to those of the command line. The Server
+/// serves the [`Handle`] only if no listener is added, and is shut down by [`Handle::shutdown`].
+pub struct ServerBuilder {
+    opt: Opt,
+    listeners: Vec<Listener>,
+    metrics_listener: Option<MetricsListener>,
+    /// kept apart from `opt`, which only holds whole seconds.
+    sys_interval: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+}
+
+impl ServerBuilder {
+    pub fn listener(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn metrics_listener(mut self, metrics_listener: MetricsListener) -> Self {
+        self.metrics_listener = Some(metrics_listener);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.opt.max_connection = max_connections;
+        self
+    }
+
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.opt.max_session = Some(max_sessions);
+        self
+    }
+
+    pub fn password_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opt.password_file = Some(path.into());
+        self
+    }
+
+    pub fn acl_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opt.acl_file = Some(path.into());
+        self
+    }
+
+    pub fn deny_anonymous(mut self, deny_anonymous: bool) -> Self {
+        self.opt.deny_anonymous = deny_anonymous;
+        self
+    }
+
+    pub fn session_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opt.session_file = Some(path.into());
+        self
+    }
+
+    pub fn max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.opt.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    /// 0 turns `$SYS` topics off.
+    pub fn sys_interval(mut self, sys_interval: Duration) -> Self {
+        self.sys_interval = Some(sys_interval);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+
+    pub fn share_strategy(mut self, share_strategy: ShareStrategy) -> Self {
+        self.opt.share_strategy = share_strategy;
+        self
+    }
+
+    pub fn bridge(mut self, bridge: BridgeConfig) -> Self {
+        self.opt.bridges.push(bridge);
+        self
+    }
+
+    pub fn build(self) -> Result<Server, Error> {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let max_connections = Arc::new(Semaphore::new(self.opt.max_connection));
+        let mut server = Server::new(self.opt, self.listeners, self.metrics_listener, shutdown_tx, max_connections)?;
+        server.sys_interval = self.sys_interval.unwrap_or(server.sys_interval);
+        server.connect_timeout = self.connect_timeout.unwrap_or(server.connect_timeout);
+        server.drain_timeout = self.drain_timeout.unwrap_or(server.drain_timeout);
+
+        Ok(server)
     }
 }
 
@@ -85,4 +459,8 @@ pub enum Error {
     AcceptError(std::io::Error),
     #[error("cannot read the TcpStream: {0:?}")]
     ReadError(#[from] std::io::Error),
+    #[error("cannot load authentication data: {0}")]
+    AuthError(#[from] context::auth::Error),
+    #[error("cannot recover Sessions: {0}")]
+    SessionError(#[from] context::session::Error),
 }
\ No newline at end of file
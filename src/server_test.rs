@@ -0,0 +1,828 @@
+#![allow(non_snake_case)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::{Opt, Server};
+use crate::listener::Listener;
+use crate::server::Error;
+
+async fn serve(args: &[&str]) -> SocketAddr { start(args).await.0 }
+
+/// starts a Server which could be killed by aborting the returned handle, or shut down by the
+/// returned sender.
+async fn start(args: &[&str]) -> (SocketAddr, JoinHandle<Result<(), Error>>, broadcast::Sender<()>) {
+    let opt = Opt::from_iter(["telesteller"].iter().chain(args));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener)], None, shutdown_tx.clone(), Arc::new(Semaphore::new(8))).unwrap();
+    let handle = tokio::spawn(async move { server.serve().await });
+
+    (addr, handle, shutdown_tx)
+}
+
+pub(crate) fn text(s: &str) -> Vec<u8> {
+    let mut bytes = (s.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(s.as_bytes());
+    bytes
+}
+
+pub(crate) fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![header, body.len() as u8];
+    bytes.extend(body);
+    bytes
+}
+
+/// connects by MQTT 3.1.1 with a Keep Alive of 60 seconds, returning the CONNACK.
+pub(crate) async fn connect(addr: SocketAddr, client_id: &str, clean_session: bool, will: Option<(&str, &str)>) -> (TcpStream, Vec<u8>) {
+    let mut flags = if clean_session { 0x02 } else { 0x00 };
+    let mut payload = text(client_id);
+    if let Some((topic, message)) = will {
+        flags |= 0x04;
+        payload.extend(text(topic));
+        payload.extend(text(message));
+    }
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x04, flags, 0x00, 0x3c]);
+    body.extend(payload);
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&packet(0x10, body)).await.unwrap();
+    let connack = read(&mut socket).await.unwrap();
+    (socket, connack)
+}
+
+/// connects by MQTT 5 with a Keep Alive of 60 seconds, returning the CONNACK.
+async fn connect_v5(addr: SocketAddr, client_id: &str, clean_start: bool, session_expiry_interval: u32) -> (TcpStream, Vec<u8>) {
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x05, if clean_start { 0x02 } else { 0x00 }, 0x00, 0x3c]);
+    match session_expiry_interval {
+        0 => body.push(0x00),
+        interval => body.extend([vec![0x05, 0x11], interval.to_be_bytes().to_vec()].concat()),
+    }
+    body.extend(text(client_id));
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&packet(0x10, body)).await.unwrap();
+    let connack = read(&mut socket).await.unwrap();
+    assert_eq!(connack[3], 0x00);
+    (socket, connack)
+}
+
+/// subscribes by MQTT 5 with the Subscription Options byte, returning the Reason Code of SUBACK.
+async fn subscribe_v5(socket: &mut TcpStream, filter: &str, options: u8) -> u8 {
+    let mut body = vec![0x00, 0x01, 0x00];
+    body.extend(text(filter));
+    body.push(options);
+    socket.write_all(&packet(0x82, body)).await.unwrap();
+    let suback = read(socket).await.unwrap();
+    assert_eq!(suback[..5], [0x90, 0x04, 0x00, 0x01, 0x00]);
+    suback[5]
+}
+
+pub(crate) async fn subscribe(socket: &mut TcpStream, filter: &str, qos: u8) {
+    let mut body = vec![0x00, 0x01];
+    body.extend(text(filter));
+    body.push(qos);
+    socket.write_all(&packet(0x82, body)).await.unwrap();
+    assert_eq!(read(socket).await.unwrap(), [0x90, 0x03, 0x00, 0x01, qos]);
+}
+
+/// publishes a Qos 0 message.
+pub(crate) async fn publish(socket: &mut TcpStream, topic: &str, message: &str) {
+    let mut body = text(topic);
+    body.extend_from_slice(message.as_bytes());
+    socket.write_all(&packet(0x30, body)).await.unwrap();
+}
+
+/// publishes a Qos 1 message, and waits for the PUBACK.
+pub(crate) async fn publish_qos1(socket: &mut TcpStream, topic: &str, message: &str, id: u16) {
+    let mut body = text(topic);
+    body.extend_from_slice(&id.to_be_bytes());
+    body.extend_from_slice(message.as_bytes());
+    socket.write_all(&packet(0x32, body)).await.unwrap();
+    assert_eq!(read(socket).await.unwrap(), packet(0x40, id.to_be_bytes().to_vec()));
+}
+
+/// waits until every packet sent before is handled by the Server.
+async fn ping(socket: &mut TcpStream) {
+    socket.write_all(&[0xc0, 0x00]).await.unwrap();
+    assert_eq!(read(socket).await.unwrap(), [0xd0, 0x00]);
+}
+
+/// reads a packet whose Remaining Length is less than 128, or None if the connection is closed.
+pub(crate) async fn read(socket: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut header = [0; 2];
+    match tokio::time::timeout(Duration::from_secs(3), socket.read_exact(&mut header)).await.unwrap() {
+        Ok(_) => {}
+        Err(_) => return None,
+    }
+    let mut body = vec![0; header[1] as usize];
+    socket.read_exact(&mut body).await.unwrap();
+
+    Some([header.to_vec(), body].concat())
+}
+
+#[tokio::test]
+async fn test_takeover() {
+    let addr = serve(&[]).await;
+    let (mut old, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+    subscribe(&mut old, "a/b", 0).await;
+
+    // the Session is taken over by the new connection, while the old one is closed.
+    let (mut new, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    assert_eq!(read(&mut old).await, None);
+    ping(&mut new).await;
+
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish(&mut publisher, "a/b", "m").await;
+    assert_eq!(read(&mut new).await.unwrap(), packet(0x30, [text("a/b"), b"m".to_vec()].concat()));
+}
+
+#[tokio::test]
+async fn test_takeover_will() {
+    let addr = serve(&[]).await;
+    let (mut watcher, _) = connect(addr, "w", true, None).await;
+    subscribe(&mut watcher, "will", 0).await;
+
+    let (mut old, _) = connect(addr, "c", true, Some(("will", "bye"))).await;
+    let (_new, connack) = connect(addr, "c", true, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+    assert_eq!(read(&mut old).await, None);
+    assert_eq!(read(&mut watcher).await.unwrap(), packet(0x30, [text("will"), b"bye".to_vec()].concat()));
+}
+
+/// the Session is saved however the connection ends, e.g. being closed by the client or by a
+/// malformed packet.
+#[tokio::test]
+async fn test_session_saved() {
+    let addr = serve(&[]).await;
+    for (client_id, last_packet) in [("eof", None), ("malformed", Some(vec![0x00, 0x00]))] {
+        let (mut socket, _) = connect(addr, client_id, false, None).await;
+        subscribe(&mut socket, "a/b", 0).await;
+        match last_packet {
+            Some(packet) => {
+                socket.write_all(&packet).await.unwrap();
+                assert_eq!(read(&mut socket).await, None);
+            }
+            None => drop(socket),
+        }
+
+        let (mut socket, connack) = connect(addr, client_id, false, None).await;
+        assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+        ping(&mut socket).await;
+
+        let (mut publisher, _) = connect(addr, "p", true, None).await;
+        publish(&mut publisher, "a/b", "m").await;
+        assert_eq!(read(&mut socket).await.unwrap(), packet(0x30, [text("a/b"), b"m".to_vec()].concat()));
+    }
+}
+
+/// retained messages are not sent again to subscriptions restored from the Session.
+#[tokio::test]
+async fn test_session_retained() {
+    let addr = serve(&[]).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publisher.write_all(&packet(0x31, [text("a/b"), b"m".to_vec()].concat())).await.unwrap();
+    ping(&mut publisher).await;
+
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/b", 0).await;
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x31, [text("a/b"), b"m".to_vec()].concat()));
+    socket.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+
+    let (mut socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    ping(&mut socket).await;
+}
+
+#[tokio::test]
+async fn test_session_file_recovery() {
+    let path = std::env::temp_dir().join(format!("telesteller-recovery-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = ["--session-file", path.to_str().unwrap()];
+
+    let (addr, server, _) = start(&args).await;
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/b", 1).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish_qos1(&mut publisher, "a/b", "m", 1).await;
+    // the message is left unacknowledged.
+    let message = [text("a/b"), vec![0x00, 0x01], b"m".to_vec()].concat();
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x32, message.clone()));
+    drop(socket);
+
+    // the Session is saved once the connection is closed, which the new connection waits for,
+    // then the Server crashes.
+    let (_alive, _) = connect(addr, "c", false, None).await;
+    server.abort();
+    let _ = server.await;
+    let (addr, _server, _) = start(&args).await;
+
+    let (mut socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    // the unacknowledged message is sent again with DUP flag.
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x3a, message));
+    socket.write_all(&[0x40, 0x02, 0x00, 0x01]).await.unwrap();
+    ping(&mut socket).await;
+
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish_qos1(&mut publisher, "a/b", "n", 1).await;
+    let message = [text("a/b"), vec![0x00, 0x02], b"n".to_vec()].concat();
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x32, message));
+}
+
+#[tokio::test]
+async fn test_offline_queue() {
+    let addr = serve(&["--max-queued-messages", "2"]).await;
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/+", 1).await;
+    socket.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish(&mut publisher, "a/0", "not queued").await;
+    publish_qos1(&mut publisher, "a/1", "1", 1).await;
+    publish_qos1(&mut publisher, "a/2", "2", 2).await;
+    // the queue is full.
+    publish_qos1(&mut publisher, "a/3", "3", 3).await;
+
+    let (mut socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    for (id, topic, message) in [(1u16, "a/1", "1"), (2, "a/2", "2")] {
+        let message = [text(topic), id.to_be_bytes().to_vec(), message.as_bytes().to_vec()].concat();
+        assert_eq!(read(&mut socket).await.unwrap(), packet(0x32, message));
+    }
+    ping(&mut socket).await;
+}
+
+/// no Qos 1 message is lost while the client goes offline and connects again, however quickly.
+#[tokio::test]
+async fn test_offline_reconnect() {
+    let addr = serve(&[]).await;
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/b", 1).await;
+
+    // messages are published without waiting for PUBACK, so that some are always in flight.
+    let publisher = tokio::spawn(async move {
+        let (mut publisher, _) = connect(addr, "p", true, None).await;
+        for i in 0..300u16 {
+            let mut body = text("a/b");
+            body.extend_from_slice(&(i + 1).to_be_bytes());
+            body.extend_from_slice(i.to_string().as_bytes());
+            publisher.write_all(&packet(0x32, body)).await.unwrap();
+            if i % 10 == 9 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        for i in 0..300u16 {
+            assert_eq!(read(&mut publisher).await.unwrap(), packet(0x40, (i + 1).to_be_bytes().to_vec()));
+        }
+    });
+
+    // every message is acknowledged, but might be received again if the PUBACK is lost.
+    let mut received = std::collections::HashSet::new();
+    let mut receive = |packet: Vec<u8>| {
+        let topic_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        received.insert(String::from_utf8(packet[6 + topic_len..].to_vec()).unwrap());
+        [0x40, 0x02, packet[4 + topic_len], packet[5 + topic_len]]
+    };
+    for round in 0..20 {
+        while let Ok(Some(packet)) = tokio::time::timeout(Duration::from_millis(5), read(&mut socket)).await {
+            socket.write_all(&receive(packet)).await.unwrap();
+        }
+        // goes offline either gracefully or not.
+        if round % 2 == 0 {
+            socket.write_all(&[0xe0, 0x00]).await.unwrap();
+        }
+        drop(socket);
+        socket = connect(addr, "c", false, None).await.0;
+    }
+    publisher.await.unwrap();
+    while let Ok(Some(packet)) = tokio::time::timeout(Duration::from_millis(200), read(&mut socket)).await {
+        socket.write_all(&receive(packet)).await.unwrap();
+    }
+    assert_eq!(received.len(), 300);
+}
+
+#[tokio::test]
+async fn test_sys() {
+    let addr = serve(&["--sys-interval", "1"]).await;
+    let (mut socket, _) = connect(addr, "c", true, None).await;
+    let mut body = vec![0x00, 0x01];
+    for filter in ["$SYS/broker/version", "$SYS/broker/clients/connected"] {
+        body.extend(text(filter));
+        body.push(0);
+    }
+    socket.write_all(&packet(0x82, body)).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0x90, 0x04, 0x00, 0x01, 0x00, 0x00]);
+
+    let version = format!("telesteller {}", env!("CARGO_PKG_VERSION"));
+    let (mut version_received, mut connected_received) = (false, false);
+    while !(version_received && connected_received) {
+        let packet = read(&mut socket).await.unwrap();
+        let topic_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let (topic, payload) = packet[4..].split_at(topic_len);
+        match (topic, payload) {
+            (b"$SYS/broker/version", payload) => version_received = payload == version.as_bytes(),
+            (b"$SYS/broker/clients/connected", b"1") => connected_received = true,
+            _ => {}
+        }
+    }
+}
+
+/// subscriptions are persisted once subscribed, rather than once the connection is closed.
+#[tokio::test]
+async fn test_session_file_subscribed() {
+    let path = std::env::temp_dir().join(format!("telesteller-subscribed-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = ["--session-file", path.to_str().unwrap()];
+
+    let (addr, server, _) = start(&args).await;
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/b", 1).await;
+    // the Server crashes while the client is still connected.
+    server.abort();
+    let _ = server.await;
+    let (addr, _server, _) = start(&args).await;
+
+    let (mut socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish_qos1(&mut publisher, "a/b", "m", 1).await;
+    let message = [text("a/b"), vec![0x00, 0x01], b"m".to_vec()].concat();
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x32, message));
+}
+
+/// so is the removal of subscriptions once unsubscribed.
+#[tokio::test]
+async fn test_session_file_unsubscribed() {
+    let path = std::env::temp_dir().join(format!("telesteller-unsubscribed-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = ["--session-file", path.to_str().unwrap()];
+
+    let (addr, server, _) = start(&args).await;
+    let (mut socket, _) = connect(addr, "c", false, None).await;
+    subscribe(&mut socket, "a/b", 1).await;
+    subscribe(&mut socket, "a/c", 1).await;
+    socket.write_all(&packet(0xa2, [vec![0x00, 0x02], text("a/b")].concat())).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0xb0, 0x02, 0x00, 0x02]);
+    server.abort();
+    let _ = server.await;
+    let (addr, _server, _) = start(&args).await;
+
+    let (mut socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish_qos1(&mut publisher, "a/b", "b", 1).await;
+    publish_qos1(&mut publisher, "a/c", "c", 2).await;
+    assert_eq!(read(&mut socket).await.unwrap()[2..7], text("a/c"));
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let path = std::env::temp_dir().join(format!("telesteller-shutdown-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = ["--session-file", path.to_str().unwrap()];
+
+    let opt = Opt::from_iter(["telesteller"].iter().chain(&args));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener)], None, shutdown_tx.clone(), Arc::new(Semaphore::new(8))).unwrap();
+    // subscribed in process, which outlives the connections closed by the shutdown.
+    let mut wills = server.handle().subscribe("will").await.unwrap();
+    let server = tokio::spawn(async move { server.serve().await });
+
+    let (mut socket, _) = connect(addr, "c", false, Some(("will", "bye"))).await;
+    subscribe(&mut socket, "a/b", 1).await;
+    // MQTT 5 clients are told why the connection is closed.
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x05, 0x02, 0x00, 0x3c, 0x00]);
+    body.extend(text("d"));
+    let mut socket_v5 = TcpStream::connect(addr).await.unwrap();
+    socket_v5.write_all(&packet(0x10, body)).await.unwrap();
+    assert_eq!(read(&mut socket_v5).await.unwrap()[2..4], [0x00, 0x00]);
+
+    shutdown_tx.send(()).unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    assert_eq!(read(&mut socket_v5).await.unwrap(), [0xe0, 0x01, 0x8b]);
+    assert_eq!(read(&mut socket_v5).await, None);
+    tokio::time::timeout(Duration::from_secs(3), server).await.unwrap().unwrap().unwrap();
+    // no more connections are accepted.
+    assert!(TcpStream::connect(addr).await.is_err());
+    // closing connections for shutdown is not the fault of clients, so no Will Message is published.
+    assert!(tokio::time::timeout(Duration::from_millis(100), wills.next()).await.is_err());
+
+    // the Session is saved before the Server exits.
+    let (addr, _server, _) = start(&args).await;
+    let (_socket, connack) = connect(addr, "c", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+}
+
+/// waits until every connection is closed, and its permit is returned.
+async fn wait_closed(max_connections: &Semaphore) {
+    tokio::time::timeout(Duration::from_secs(1), async {
+        // a permit is taken by the accept loop.
+        while max_connections.available_permits() != 7 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_keep_alive() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let max_connections = Arc::new(Semaphore::new(8));
+    let opt = Opt::from_iter(&["telesteller", "--connect-timeout", "1"]);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener)], None, shutdown_tx, max_connections.clone()).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    // the connection is closed if CONNECT is not received in time.
+    let start = tokio::time::Instant::now();
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    wait_closed(&max_connections).await;
+
+    // a Keep Alive of 1 second is exceeded after 1.5 seconds of silence.
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x04, 0x02, 0x00, 0x01]);
+    body.extend(text("c"));
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&packet(0x10, body)).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0x20, 0x02, 0x00, 0x00]);
+    let start = tokio::time::Instant::now();
+    assert_eq!(read(&mut socket).await, None);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1400) && elapsed < Duration::from_millis(2500), "closed after {:?}", elapsed);
+    wait_closed(&max_connections).await;
+}
+
+#[tokio::test]
+async fn test_listener_max_connections() {
+    let opt = Opt::from_iter(&["telesteller"]);
+    let limited = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let limited_addr = limited.local_addr().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let listeners = vec![Listener::tcp(limited).max_connections(1), Listener::tcp(listener)];
+    let mut server = Server::new(opt, listeners, None, shutdown_tx, Arc::new(Semaphore::new(8))).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    let (first, _) = connect(limited_addr, "a", true, None).await;
+    // the connection is not accepted until the first one is closed, while other listeners are
+    // not affected.
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x04, 0x02, 0x00, 0x3c]);
+    body.extend(text("b"));
+    let mut second = TcpStream::connect(limited_addr).await.unwrap();
+    second.write_all(&packet(0x10, body)).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(500), second.read(&mut [0; 4])).await.is_err());
+    connect(addr, "c", true, None).await;
+
+    drop(first);
+    assert_eq!(read(&mut second).await.unwrap(), [0x20, 0x02, 0x00, 0x00]);
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let acl = std::env::temp_dir().join(format!("telesteller-reload-{}.acl", std::process::id()));
+    std::fs::write(&acl, "topic readwrite a/#").unwrap();
+    let args = ["telesteller", "--acl-file", acl.to_str().unwrap()];
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let max_connections = Arc::new(Semaphore::new(8));
+    let mut server = Server::new(Opt::from_iter(&args), vec![Listener::tcp(listener)], None, shutdown_tx, max_connections.clone()).unwrap();
+    let reloader = server.reloader();
+    tokio::spawn(async move { server.serve().await });
+
+    let (mut socket, _) = connect(addr, "c", true, None).await;
+    let mut body = vec![0x00, 0x01];
+    body.extend(text("b"));
+    body.push(0);
+    socket.write_all(&packet(0x82, body.clone())).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0x90, 0x03, 0x00, 0x01, 0x80]);
+
+    // the new ACL applies to existing connections as well.
+    std::fs::write(&acl, "topic readwrite #").unwrap();
+    reloader.reload(&Opt::from_iter(args.iter().chain(&["--max-connection", "10"]))).await.unwrap();
+    subscribe(&mut socket, "b", 0).await;
+    // a permit is taken by the accept loop, and another by the connection.
+    assert_eq!(max_connections.available_permits(), 8);
+
+    reloader.reload(&Opt::from_iter(args.iter().chain(&["--max-connection", "4"]))).await.unwrap();
+    tokio::task::yield_now().await;
+    assert_eq!(max_connections.available_permits(), 2);
+
+    // nothing changes until the options prepared are applied.
+    drop(reloader.prepare(&Opt::from_iter(args.iter().chain(&["--max-connection", "10"]))).unwrap());
+    tokio::task::yield_now().await;
+    assert_eq!(max_connections.available_permits(), 2);
+
+    // nothing changes if the files are invalid.
+    std::fs::write(&acl, "topic").unwrap();
+    assert!(reloader.reload(&Opt::from_iter(&args)).await.is_err());
+    ping(&mut socket).await;
+    subscribe(&mut socket, "c", 0).await;
+}
+
+#[tokio::test]
+async fn test_shared_subscription() {
+    let addr = serve(&[]).await;
+    let (mut worker1, _) = connect(addr, "w1", true, None).await;
+    subscribe(&mut worker1, "$share/group/a/+", 0).await;
+    let (mut worker2, _) = connect(addr, "w2", true, None).await;
+    subscribe(&mut worker2, "$share/group/a/+", 0).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+
+    for message in ["1", "2", "3", "4"] {
+        publish(&mut publisher, "a/b", message).await;
+    }
+    // members take turns, in the order they subscribed.
+    for (worker, messages) in [(&mut worker1, ["1", "3"]), (&mut worker2, ["2", "4"])] {
+        for message in messages {
+            assert_eq!(read(worker).await.unwrap(), packet(0x30, [text("a/b"), message.as_bytes().to_vec()].concat()));
+        }
+    }
+
+    // messages go to the worker left once the other one disconnects.
+    worker1.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut worker1).await, None);
+    for message in ["5", "6"] {
+        publish(&mut publisher, "a/b", message).await;
+        assert_eq!(read(&mut worker2).await.unwrap(), packet(0x30, [text("a/b"), message.as_bytes().to_vec()].concat()));
+    }
+}
+
+#[tokio::test]
+async fn test_subscription_options() {
+    let addr = serve(&[]).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publisher.write_all(&packet(0x31, [text("a/b"), b"r".to_vec()].concat())).await.unwrap();
+    ping(&mut publisher).await;
+
+    // No Local, Retain As Published, and Retain Handling of 1.
+    let (mut socket, _) = connect_v5(addr, "c", true, 0).await;
+    assert_eq!(subscribe_v5(&mut socket, "a/b", 0x1c).await, 0x00);
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x31, [text("a/b"), vec![0x00], b"r".to_vec()].concat()));
+
+    // messages of the client itself are skipped, while RETAIN is kept for others.
+    socket.write_all(&packet(0x30, [text("a/b"), vec![0x00], b"own".to_vec()].concat())).await.unwrap();
+    ping(&mut socket).await;
+    publisher.write_all(&packet(0x31, [text("a/b"), b"m".to_vec()].concat())).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), packet(0x31, [text("a/b"), vec![0x00], b"m".to_vec()].concat()));
+
+    // retained messages are not sent again for an existing subscription, nor with Retain Handling of 2.
+    assert_eq!(subscribe_v5(&mut socket, "a/b", 0x1c).await, 0x00);
+    assert_eq!(subscribe_v5(&mut socket, "a/+", 0x20).await, 0x00);
+    ping(&mut socket).await;
+
+    assert_eq!(subscribe_v5(&mut socket, "a/#/b", 0x00).await, 0x8f);
+    // No Local is a Protocol Error on a Shared Subscription.
+    let mut body = vec![0x00, 0x01, 0x00];
+    body.extend(text("$share/g/a/b"));
+    body.push(0x04);
+    socket.write_all(&packet(0x82, body)).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0xe0, 0x01, 0x82]);
+    assert_eq!(read(&mut socket).await, None);
+}
+
+#[tokio::test]
+async fn test_session_expiry() {
+    let addr = serve(&[]).await;
+    let (mut socket, _) = connect_v5(addr, "c", false, 1).await;
+    assert_eq!(subscribe_v5(&mut socket, "a/b", 0x01).await, 0x01);
+    socket.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+
+    let (mut socket, connack) = connect_v5(addr, "c", false, 1).await;
+    assert_eq!(connack[2], 0x01);
+    socket.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    // the Session is gone once the interval passes.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let (mut socket, connack) = connect_v5(addr, "c", false, 1).await;
+    assert_eq!(connack[2], 0x00);
+
+    // DISCONNECT ends the Session at once by a Session Expiry Interval of 0.
+    assert_eq!(subscribe_v5(&mut socket, "a/b", 0x01).await, 0x01);
+    socket.write_all(&[0xe0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    let (mut socket, connack) = connect_v5(addr, "c", false, 0).await;
+    assert_eq!(connack[2], 0x00);
+
+    // which could not be set by DISCONNECT if it was 0.
+    socket.write_all(&[0xe0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x3c]).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0xe0, 0x01, 0x82]);
+    assert_eq!(read(&mut socket).await, None);
+    let (_socket, connack) = connect_v5(addr, "c", false, 0).await;
+    assert_eq!(connack[2], 0x00);
+}
+
+#[tokio::test]
+async fn test_publish_properties() {
+    let addr = serve(&[]).await;
+    let (mut subscriber, _) = connect_v5(addr, "s", true, 0).await;
+    assert_eq!(subscribe_v5(&mut subscriber, "a/b", 0x00).await, 0x00);
+
+    // Message Expiry Interval is forwarded with the time left.
+    let (mut publisher, _) = connect_v5(addr, "p", true, 0).await;
+    let message = [text("a/b"), vec![0x05, 0x02, 0x00, 0x00, 0x00, 0x3c], b"m".to_vec()].concat();
+    publisher.write_all(&packet(0x30, message.clone())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), packet(0x30, message));
+
+    // and the message is dropped once it expires, including retained ones.
+    let message = [text("a/c"), vec![0x05, 0x02, 0x00, 0x00, 0x00, 0x01], b"m".to_vec()].concat();
+    publisher.write_all(&packet(0x31, message)).await.unwrap();
+    ping(&mut publisher).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(subscribe_v5(&mut subscriber, "a/c", 0x00).await, 0x00);
+    ping(&mut subscriber).await;
+
+    // Subscription Identifiers are never sent by clients in PUBLISH.
+    publisher.write_all(&packet(0x30, [text("a/b"), vec![0x02, 0x0b, 0x01], b"m".to_vec()].concat())).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0xe0, 0x01, 0x82]);
+    assert_eq!(read(&mut publisher).await, None);
+    // nor in SUBSCRIBE, which are not available.
+    subscriber.write_all(&packet(0x82, [vec![0x00, 0x02, 0x02, 0x0b, 0x01], text("a/d"), vec![0x00]].concat())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), [0xe0, 0x01, 0xa1]);
+    assert_eq!(read(&mut subscriber).await, None);
+}
+
+#[tokio::test]
+async fn test_puback() {
+    let addr = serve(&[]).await;
+    let (mut subscriber, _) = connect(addr, "s", false, None).await;
+    subscribe(&mut subscriber, "a/b", 1).await;
+
+    // PUBACK is replied to the publisher with the Packet Identifier of the PUBLISH.
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish_qos1(&mut publisher, "a/b", "1", 7).await;
+    let publish = read(&mut subscriber).await.unwrap();
+    assert_eq!(publish, packet(0x32, [text("a/b"), publish[7..9].to_vec(), b"1".to_vec()].concat()));
+    subscriber.write_all(&packet(0x40, publish[7..9].to_vec())).await.unwrap();
+    publish_qos1(&mut publisher, "a/b", "2", 8).await;
+    let unacknowledged = read(&mut subscriber).await.unwrap();
+    assert_eq!(unacknowledged[9..], *b"2");
+
+    // only the message not acknowledged by the subscriber is sent again, with DUP set.
+    subscriber.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut subscriber).await, None);
+    let (mut subscriber, connack) = connect(addr, "s", false, None).await;
+    assert_eq!(connack, [0x20, 0x02, 0x01, 0x00]);
+    assert_eq!(read(&mut subscriber).await.unwrap(), [&[0x3a], &unacknowledged[1..]].concat());
+    subscriber.write_all(&packet(0x40, unacknowledged[7..9].to_vec())).await.unwrap();
+    ping(&mut subscriber).await;
+}
+
+#[tokio::test]
+async fn test_qos2() {
+    let addr = serve(&[]).await;
+    let (mut subscriber, _) = connect(addr, "s", true, None).await;
+    subscribe(&mut subscriber, "a/b", 2).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    let qos2 = |message: &str| packet(0x34, [text("a/b"), vec![0x00, 0x05], message.as_bytes().to_vec()].concat());
+
+    publisher.write_all(&qos2("1")).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x50, 0x02, 0x00, 0x05]);
+    // a resend with DUP flag set is acknowledged again, but not dispatched again, which the Qos 0
+    // message published after it shows.
+    publisher.write_all(&[&[0x3c], &qos2("1")[1..]].concat()).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x50, 0x02, 0x00, 0x05]);
+    publish(&mut publisher, "a/b", "0").await;
+
+    let publish = read(&mut subscriber).await.unwrap();
+    assert_eq!(publish, packet(0x34, [text("a/b"), publish[7..9].to_vec(), b"1".to_vec()].concat()));
+    assert_eq!(read(&mut subscriber).await.unwrap(), packet(0x30, [text("a/b"), b"0".to_vec()].concat()));
+    subscriber.write_all(&packet(0x50, publish[7..9].to_vec())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), packet(0x62, publish[7..9].to_vec()));
+    subscriber.write_all(&packet(0x70, publish[7..9].to_vec())).await.unwrap();
+    ping(&mut subscriber).await;
+
+    // once released, the Packet Identifier is available to a new message.
+    publisher.write_all(&packet(0x62, vec![0x00, 0x05])).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x70, 0x02, 0x00, 0x05]);
+    publisher.write_all(&qos2("2")).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x50, 0x02, 0x00, 0x05]);
+    assert_eq!(read(&mut subscriber).await.unwrap()[9..], *b"2");
+}
+
+#[tokio::test]
+async fn test_will() {
+    let addr = serve(&[]).await;
+    let (mut watcher, _) = connect(addr, "w", true, None).await;
+    subscribe(&mut watcher, "will", 0).await;
+    let will = packet(0x30, [text("will"), b"bye".to_vec()].concat());
+
+    // not published on a DISCONNECT, which the Qos 0 message published after it shows.
+    let (mut socket, _) = connect(addr, "c", true, Some(("will", "bye"))).await;
+    socket.write_all(&[0xe0, 0x00]).await.unwrap();
+    assert_eq!(read(&mut socket).await, None);
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish(&mut publisher, "will", "-").await;
+    assert_eq!(read(&mut watcher).await.unwrap(), packet(0x30, [text("will"), b"-".to_vec()].concat()));
+
+    // published when the socket is dropped without DISCONNECT.
+    let (socket, _) = connect(addr, "c", true, Some(("will", "bye"))).await;
+    drop(socket);
+    assert_eq!(read(&mut watcher).await.unwrap(), will);
+
+    // published when the Keep Alive of 1 second is exceeded.
+    let mut body = text("MQTT");
+    body.extend_from_slice(&[0x04, 0x06, 0x00, 0x01]);
+    body.extend([text("c"), text("will"), text("bye")].concat());
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&packet(0x10, body)).await.unwrap();
+    assert_eq!(read(&mut socket).await.unwrap(), [0x20, 0x02, 0x00, 0x00]);
+    assert_eq!(read(&mut watcher).await.unwrap(), will);
+    assert_eq!(read(&mut socket).await, None);
+}
+
+#[tokio::test]
+async fn test_unsubscribe() {
+    let addr = serve(&[]).await;
+    let (mut subscriber, _) = connect(addr, "s", true, None).await;
+    subscribe(&mut subscriber, "a/b", 0).await;
+    subscribe(&mut subscriber, "a/c", 0).await;
+    subscriber.write_all(&packet(0xa2, [vec![0x00, 0x02], text("a/b")].concat())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), [0xb0, 0x02, 0x00, 0x02]);
+
+    // messages to the filter unsubscribed are no longer delivered, unlike the one still subscribed.
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish(&mut publisher, "a/b", "1").await;
+    publish(&mut publisher, "a/c", "2").await;
+    assert_eq!(read(&mut subscriber).await.unwrap(), packet(0x30, [text("a/c"), b"2".to_vec()].concat()));
+    ping(&mut subscriber).await;
+}
+
+#[tokio::test]
+async fn test_acl_deny_wildcard() {
+    let acl = std::env::temp_dir().join(format!("telesteller-deny-{}.acl", std::process::id()));
+    std::fs::write(&acl, "topic readwrite alice/#\ntopic deny alice/secret").unwrap();
+    let addr = serve(&["--acl-file", acl.to_str().unwrap()]).await;
+
+    // a wildcard subscription which would receive messages of the denied topic is rejected.
+    let (mut subscriber, _) = connect(addr, "s", true, None).await;
+    let mut body = vec![0x00, 0x01];
+    body.extend(text("alice/#"));
+    body.push(0);
+    subscriber.write_all(&packet(0x82, body)).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), [0x90, 0x03, 0x00, 0x01, 0x80]);
+    subscribe(&mut subscriber, "alice/public", 0).await;
+
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+    publish(&mut publisher, "alice/secret", "secret").await;
+    publish(&mut publisher, "alice/public", "public").await;
+    assert_eq!(read(&mut subscriber).await.unwrap(), packet(0x30, [text("alice/public"), b"public".to_vec()].concat()));
+    std::fs::remove_file(&acl).unwrap();
+}
+
+#[tokio::test]
+async fn test_ack_mismatched() {
+    let addr = serve(&[]).await;
+    let (mut publisher, _) = connect(addr, "p", true, None).await;
+
+    // a Qos 1 message can't be completed by PUBCOMP.
+    let (mut subscriber, _) = connect(addr, "s", true, None).await;
+    subscribe(&mut subscriber, "a/b", 1).await;
+    publish_qos1(&mut publisher, "a/b", "1", 1).await;
+    let publish = read(&mut subscriber).await.unwrap();
+    subscriber.write_all(&packet(0x70, publish[7..9].to_vec())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await, None);
+
+    // a Qos 2 message is dropped by a PUBREC of failure, which is not followed by PUBREL.
+    let (mut subscriber, _) = connect_v5(addr, "s5", true, 0).await;
+    assert_eq!(subscribe_v5(&mut subscriber, "a/c", 2).await, 2);
+    publisher.write_all(&packet(0x34, [text("a/c"), vec![0x00, 0x02], b"2".to_vec()].concat())).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x50, 0x02, 0x00, 0x02]);
+    let publish = read(&mut subscriber).await.unwrap();
+    subscriber.write_all(&packet(0x50, [publish[7..9].to_vec(), vec![0x80, 0x00]].concat())).await.unwrap();
+    ping(&mut subscriber).await;
+
+    // nor can it be completed by PUBACK.
+    publisher.write_all(&packet(0x34, [text("a/c"), vec![0x00, 0x03], b"3".to_vec()].concat())).await.unwrap();
+    assert_eq!(read(&mut publisher).await.unwrap(), [0x50, 0x02, 0x00, 0x03]);
+    let publish = read(&mut subscriber).await.unwrap();
+    assert_eq!(publish[10..], *b"3");
+    subscriber.write_all(&packet(0x40, publish[7..9].to_vec())).await.unwrap();
+    assert_eq!(read(&mut subscriber).await.unwrap(), [0xe0, 0x01, 0x82]);
+    assert_eq!(read(&mut subscriber).await, None);
+}
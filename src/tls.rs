@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::TlsAcceptor;
+
+/// The listener of TLS connections, which are decrypted before being handled as plain ones.
+pub struct TlsListener {
+    pub(crate) listener: TcpListener,
+    pub(crate) acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: &str, acceptor: TlsAcceptor) -> Result<TlsListener, Error> {
+        Ok(TlsListener {
+            listener: TcpListener::bind(addr).await?,
+            acceptor,
+        })
+    }
+}
+
+/// builds the acceptor of the TLS listener from PEM files. Clients are required to present a
+/// certificate signed by the CA if `ca` is given.
+pub fn acceptor(cert: &Path, key: &Path, ca: Option<&Path>) -> Result<TlsAcceptor, Error> {
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let config = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            let certs = read_certs(ca)?.into_iter().map(|cert| cert.0).collect::<Vec<_>>();
+            if let (0, _) = roots.add_parsable_certificates(&certs) {
+                return Err(Error::NotFound("valid CA certificate", ca.display().to_string()));
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    }.with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(Error::NotFound("certificate", path.display().to_string()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(Error::NotFound("private key", path.display().to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot read the PEM file or bind the listener: {0:?}")]
+    ReadError(#[from] std::io::Error),
+    #[error("no {0} found in {1}")]
+    NotFound(&'static str, String),
+    #[error("invalid certificate or key: {0:?}")]
+    InvalidConfig(#[from] tokio_rustls::rustls::Error),
+}
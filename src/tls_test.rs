@@ -0,0 +1,92 @@
+#![allow(non_snake_case)]
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Semaphore};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::{Opt, Server};
+use crate::listener::Listener;
+use crate::tls::{self, Error, TlsListener};
+
+/// writes a self-signed certificate of localhost and its key, returning their paths and the DER of
+/// the certificate.
+fn self_signed(name: &str) -> (PathBuf, PathBuf, Vec<u8>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("telesteller-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    (cert_path, key_path, cert.serialize_der().unwrap())
+}
+
+#[tokio::test]
+async fn test_tls_CONNECT() {
+    let (cert, key, der) = self_signed("connect");
+    let tls_listener = TlsListener::bind("127.0.0.1:0", tls::acceptor(&cert, &key, None).unwrap()).await.unwrap();
+    let addr = tls_listener.listener.local_addr().unwrap();
+
+    let opt = Opt::from_iter(&["telesteller"]);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener), Listener::tls(tls_listener)], None, shutdown_tx, Arc::new(Semaphore::new(8))).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(der)).unwrap();
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let mut socket = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket).await.unwrap();
+
+    socket.write_all(&[0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01, b'a']).await.unwrap();
+    let mut connack = [0; 4];
+    socket.read_exact(&mut connack).await.unwrap();
+    assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+}
+
+/// a client stalling the handshake is disconnected after the connect timeout.
+#[tokio::test]
+async fn test_tls_handshake_timeout() {
+    let (cert, key, _) = self_signed("timeout");
+    let tls_listener = TlsListener::bind("127.0.0.1:0", tls::acceptor(&cert, &key, None).unwrap()).await.unwrap();
+    let addr = tls_listener.listener.local_addr().unwrap();
+
+    let opt = Opt::from_iter(&["telesteller", "--connect-timeout", "1"]);
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let max_connections = Arc::new(Semaphore::new(8));
+    let mut server = Server::new(opt, vec![Listener::tls(tls_listener)], None, shutdown_tx, max_connections.clone()).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(3), socket.read(&mut [0; 1])).await.unwrap();
+    assert_eq!(closed.unwrap(), 0);
+    tokio::task::yield_now().await;
+    // a permit is taken by the accept loop.
+    assert_eq!(max_connections.available_permits(), 7);
+}
+
+#[test]
+fn test_acceptor_invalid() {
+    let (cert, _, _) = self_signed("invalid");
+
+    // a certificate is not a private key.
+    match tls::acceptor(&cert, &cert, None) {
+        Err(Error::NotFound(..)) => {}
+        result => panic!("expected Error::NotFound, got {:?}.", result.map(|_| ())),
+    }
+
+    match tls::acceptor(&cert.with_file_name("missing.pem"), &cert, None) {
+        Err(Error::ReadError(..)) => {}
+        result => panic!("expected Error::ReadError, got {:?}.", result.map(|_| ())),
+    }
+}
@@ -1,4 +1,5 @@
 pub(crate) trait BoolExt {
+    #[allow(dead_code)]
     fn if_so<V>(self, v: V) -> Option<V>;
     fn if_so_then<F, R>(self, f: F) -> Option<R> where F: FnMut() -> R;
 }
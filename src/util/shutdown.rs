@@ -1,5 +1,7 @@
 use tokio::sync::broadcast;
 
+/// Listens for the shutdown signal of the Server, which is sent once through a broadcast channel.
+#[derive(Debug)]
 pub(crate) struct Shutdown {
     shutdown: bool,
     signal: broadcast::Receiver<()>,
@@ -13,8 +15,7 @@ impl Shutdown {
         }
     }
 
-    pub(crate) fn is_shutdown(&self) -> bool { self.shutdown }
-
+    /// waits until the signal is sent, which completes immediately once it has been received.
     pub(crate) async fn poll(&mut self) {
         if self.shutdown {
             return;
@@ -0,0 +1,115 @@
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// the WebSocket Sub Protocol of MQTT, see MQTT 3.1.1 spec 6.0.
+const SUBPROTOCOL: &str = "mqtt";
+
+/// The listener of MQTT over WebSocket, which serves the handshake on `path` only.
+pub struct WsListener {
+    pub(crate) listener: TcpListener,
+    pub(crate) path: String,
+}
+
+impl WsListener {
+    pub async fn bind(addr: &str, path: &str) -> io::Result<WsListener> {
+        Ok(WsListener {
+            listener: TcpListener::bind(addr).await?,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// performs the WebSocket handshake, which requires the `mqtt` Sub Protocol to be offered.
+pub(crate) async fn accept(socket: TcpStream, path: String) -> Result<WsStream, tokio_tungstenite::tungstenite::Error> {
+    // the signature of the callback is required by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = move |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        if request.uri().path() != path {
+            return Err(error_response(StatusCode::NOT_FOUND));
+        }
+
+        let offered = request.headers().get_all("Sec-WebSocket-Protocol").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == SUBPROTOCOL);
+        if !offered {
+            return Err(error_response(StatusCode::BAD_REQUEST));
+        }
+
+        response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+        Ok(response)
+    };
+
+    let inner = tokio_tungstenite::accept_hdr_async(socket, callback).await?;
+    Ok(WsStream {
+        inner,
+        read: Bytes::new(),
+    })
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(None);
+    *response.status_mut() = status;
+    response
+}
+
+fn into_io_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+/// A WebSocket connection read and written as a byte stream, so that MQTT packets can be carried
+/// in binary messages regardless of how they are split, see MQTT 3.1.1 spec 6.0.
+pub(crate) struct WsStream {
+    inner: WebSocketStream<TcpStream>,
+    /// the rest of the last binary message that is not read yet.
+    read: Bytes,
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read.is_empty() {
+                let len = cmp::min(buf.remaining(), self.read.len());
+                buf.put_slice(&self.read.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read = Bytes::from(data),
+                // Ping and Pong are replied by tungstenite itself.
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                                     "MQTT packets must be sent in binary messages."))),
+                Some(Err(err)) => return Poll::Ready(Err(into_io_error(err))),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.inner).start_send(Message::Binary(buf.to_vec())).map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(into_io_error)
+    }
+}
@@ -0,0 +1,71 @@
+#![allow(non_snake_case)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use structopt::StructOpt;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Semaphore};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{Opt, Server};
+use crate::listener::Listener;
+use crate::ws::WsListener;
+
+async fn serve(args: &[&str]) -> SocketAddr {
+    let ws_listener = WsListener::bind("127.0.0.1:0", "/mqtt").await.unwrap();
+    let addr = ws_listener.listener.local_addr().unwrap();
+
+    let opt = Opt::from_iter(["telesteller"].iter().chain(args));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut server = Server::new(opt, vec![Listener::tcp(listener), Listener::ws(ws_listener)], None, shutdown_tx, Arc::new(Semaphore::new(8))).unwrap();
+    tokio::spawn(async move { server.serve().await });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_ws_CONNECT() {
+    let addr = serve(&[]).await;
+    let mut request = format!("ws://{}/mqtt", addr).into_client_request().unwrap();
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(response.headers().get("Sec-WebSocket-Protocol").unwrap(), "mqtt");
+
+    // a packet may be split into multiple messages.
+    socket.send(Message::Binary(vec![0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T'])).await.unwrap();
+    socket.send(Message::Binary(vec![b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01, b'a'])).await.unwrap();
+    assert_eq!(socket.next().await.unwrap().unwrap(), Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+
+    socket.send(Message::Binary(vec![0xc0, 0x00])).await.unwrap();
+    assert_eq!(socket.next().await.unwrap().unwrap(), Message::Binary(vec![0xd0, 0x00]));
+}
+
+#[tokio::test]
+async fn test_ws_rejected() {
+    let addr = serve(&[]).await;
+
+    // the mqtt Sub Protocol is not offered.
+    let request = format!("ws://{}/mqtt", addr).into_client_request().unwrap();
+    assert!(tokio_tungstenite::connect_async(request).await.is_err());
+
+    let mut request = format!("ws://{}/other", addr).into_client_request().unwrap();
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    assert!(tokio_tungstenite::connect_async(request).await.is_err());
+}
+
+/// a client stalling the handshake is disconnected after the connect timeout.
+#[tokio::test]
+async fn test_ws_handshake_timeout() {
+    let addr = serve(&["--connect-timeout", "1"]).await;
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(3), socket.read(&mut [0; 1])).await.unwrap();
+    assert_eq!(closed.unwrap(), 0);
+}